@@ -0,0 +1,160 @@
+// Evaluation benchmarks, timed with a small harness of our own
+// so that they run on stable. `cargo bench -- FILTER` runs the
+// benchmarks whose name contains FILTER. Under `cargo test` each
+// runs once, as a smoke test.
+
+use std::{
+    env,
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+use symreg_rs::{
+    compile_expr,
+    vm::{
+        jit::JitProgram,
+        register::{compile_registers, RegisterProgram},
+    },
+    Expr, Program, Vec2d,
+};
+
+struct Bencher {
+    filter: Option<String>,
+    timed: bool,
+}
+
+impl Bencher {
+    // Runs `f` for about a second, after warming up,
+    // and prints the mean time per iteration.
+    fn bench(&self, name: &str, mut f: impl FnMut()) {
+        if let Some(filter) = &self.filter {
+            if !name.contains(filter.as_str()) {
+                return;
+            }
+        }
+        if !self.timed {
+            f();
+            println!("bench {name} ... ok");
+            return;
+        }
+
+        let start = Instant::now();
+        f();
+        let once = start.elapsed().max(Duration::from_nanos(1));
+        let iterations = (Duration::from_secs(1).as_nanos() / once.as_nanos()).clamp(1, 1_000_000);
+
+        let start = Instant::now();
+        for _ in 0..iterations {
+            f();
+        }
+        let per_iteration = start.elapsed().as_nanos() / iterations;
+        println!("bench {name:<24} {per_iteration:>14} ns/iter");
+    }
+}
+
+fn random_exprs(n: usize, n_inputs: usize, max_depth: usize) -> Vec<Expr> {
+    (0..n)
+        .map(|_| {
+            let mut expr = Expr::new(n_inputs);
+            expr.random_tree(max_depth);
+            expr
+        })
+        .collect()
+}
+
+// Derivatives share a lot of subexpressions
+fn derivatives() -> Vec<Expr> {
+    random_exprs(1_000, 1, 6)
+        .iter()
+        .map(|expr| expr.derivative(0))
+        .collect()
+}
+
+fn dataset(rows: usize, cols: usize) -> Vec2d<f64> {
+    let mut x = Vec2d::new(cols);
+    for _ in 0..rows * cols {
+        x.push(rand::random::<f64>());
+    }
+    x
+}
+
+fn main() {
+    let mut bencher = Bencher {
+        filter: None,
+        timed: false,
+    };
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--bench" => bencher.timed = true,
+            _ if arg.starts_with('-') => (),
+            _ => bencher.filter = Some(arg),
+        }
+    }
+
+    let exprs = random_exprs(10_000, 0, 10);
+    bencher.bench("exprs", || {
+        for expr in &exprs {
+            black_box(expr.evaluate(&[]));
+        }
+    });
+
+    let programs: Vec<Program> = exprs.iter().map(compile_expr).collect();
+    bencher.bench("compiled_exprs", || {
+        for program in &programs {
+            black_box(program.evaluate(&[]).unwrap());
+        }
+    });
+
+    let jit: Vec<JitProgram> = programs.iter().cloned().map(JitProgram::new).collect();
+    bencher.bench("jit_exprs", || {
+        for program in &jit {
+            black_box(program.evaluate(&[]).unwrap());
+        }
+    });
+
+    let registers: Vec<RegisterProgram> = exprs.iter().map(compile_registers).collect();
+    bencher.bench("register_exprs", || {
+        for program in &registers {
+            black_box(program.evaluate(&[]));
+        }
+    });
+
+    let derivatives = derivatives();
+    let programs: Vec<Program> = derivatives.iter().map(compile_expr).collect();
+    bencher.bench("compiled_derivatives", || {
+        for program in &programs {
+            black_box(program.evaluate(&[0.5]).unwrap());
+        }
+    });
+
+    let registers: Vec<RegisterProgram> = derivatives.iter().map(compile_registers).collect();
+    bencher.bench("register_derivatives", || {
+        for program in &registers {
+            black_box(program.evaluate(&[0.5]));
+        }
+    });
+
+    let x = dataset(1_000, 4);
+    let (rows, _cols) = x.shape();
+    let programs: Vec<Program> = random_exprs(100, 4, 10).iter().map(compile_expr).collect();
+    bencher.bench("compiled_exprs_rows", || {
+        for program in &programs {
+            for i_row in 0..rows {
+                black_box(program.evaluate(x.get_row(i_row).unwrap()).unwrap());
+            }
+        }
+    });
+
+    bencher.bench("compiled_exprs_batch", || {
+        for program in &programs {
+            black_box(program.evaluate_batch(&x).unwrap());
+        }
+    });
+
+    let jit: Vec<JitProgram> = programs.iter().cloned().map(JitProgram::new).collect();
+    bencher.bench("jit_exprs_batch", || {
+        for program in &jit {
+            black_box(program.evaluate_batch(&x).unwrap());
+        }
+    });
+}
@@ -2,6 +2,8 @@ use std::{collections::HashMap, fs};
 
 use crate::vec2d::Vec2d;
 
+/// A CSV file read into memory. Fields are separated by commas,
+/// without quoting, and lines end in LF or CRLF.
 #[derive(Debug, Clone)]
 pub struct DataLoader {
     // For larger datasets, data
@@ -16,6 +18,7 @@ pub struct DataLoader {
 }
 
 impl DataLoader {
+    /// Reads the whole file at `path`.
     pub fn new(path: &str) -> std::io::Result<DataLoader> {
         let data = fs::read_to_string(path)?;
 
@@ -56,30 +59,28 @@ impl DataLoader {
         Some((res, i))
     }
 
+    /// Number of columns, counted on the first line.
     pub fn columns(&self) -> usize {
         let mut i = 0;
         let mut n_cols = 1;
 
-        loop {
-            if let Some(c) = self.data.as_bytes().get(i) {
-                match c {
-                    b'\n' => {
-                        break;
-                    }
-                    b',' => {
-                        n_cols += 1;
-                    }
-                    _ => (),
+        while let Some(c) = self.data.as_bytes().get(i) {
+            match c {
+                b'\n' => {
+                    break;
                 }
-
-                i += 1;
-            } else {
-                break;
+                b',' => {
+                    n_cols += 1;
+                }
+                _ => (),
             }
+
+            i += 1;
         }
         n_cols
     }
 
+    /// Every line, the header included, split into fields.
     pub fn vec2d(&self) -> Vec2d<&str> {
         let cols = self.columns();
         let mut res = Vec2d::new(cols);
@@ -93,6 +94,8 @@ impl DataLoader {
         res
     }
 
+    /// Same as [`categorize_cols`](crate::vec2d::categorize_cols)
+    /// on [`DataLoader::vec2d`].
     pub fn categorize_cols(&self) -> Vec2d<f64> {
         let vec2d_str = self.vec2d();
         let (rows, cols) = vec2d_str.shape();
@@ -0,0 +1,280 @@
+use std::f64::consts::{LN_10, LN_2};
+
+use crate::expr::{BinOp, BinaryOp, Expr, IfPositive, Node, UnOp, UnaryOp};
+
+impl Expr {
+    /// Symbolic partial derivative with respect to the variable
+    /// `var`, simplified. The expression is simplified first, and
+    /// its subtrees are shared with the result instead of copied.
+    pub fn derivative(&self, var: usize) -> Expr {
+        let expr = self.simplify();
+        let root = expr.root;
+        let mut differentiator = Differentiator { expr, var };
+        let root = differentiator.derivative(root);
+        differentiator.expr.root = root;
+        differentiator.expr.simplify()
+    }
+}
+
+struct Differentiator {
+    expr: Expr,
+    var: usize,
+}
+
+impl Differentiator {
+    fn push(&mut self, node: Node) -> usize {
+        self.expr.nodes.push(node);
+        self.expr.nodes.len() - 1
+    }
+
+    fn number(&mut self, x: f64) -> usize {
+        self.push(Node::Number(x))
+    }
+
+    fn unop(&mut self, op: UnaryOp, a: usize) -> usize {
+        self.push(Node::UnOp(UnOp { op, a }))
+    }
+
+    fn binop(&mut self, op: BinaryOp, a: usize, b: usize) -> usize {
+        self.push(Node::BinOp(BinOp { op, a, b }))
+    }
+
+    // The derivatives of constants and other variables are zeros that
+    // hold everywhere, so products with them are dropped here. The
+    // simplifier keeps `0 * x` for any `x` that might not be finite.
+    fn is_zero(&self, node: usize) -> bool {
+        matches!(self.expr.nodes[node], Node::Number(x) if x == 0.0)
+    }
+
+    // `x * d` for a derivative `d`.
+    fn scale(&mut self, x: usize, d: usize) -> usize {
+        if self.is_zero(d) {
+            return d;
+        }
+        self.binop(BinaryOp::Mul, x, d)
+    }
+
+    // `d / x` for a derivative `d`.
+    fn shrink(&mut self, d: usize, x: usize) -> usize {
+        if self.is_zero(d) {
+            return d;
+        }
+        self.binop(BinaryOp::Div, d, x)
+    }
+
+    fn add(&mut self, a: usize, b: usize) -> usize {
+        if self.is_zero(a) {
+            return b;
+        }
+        if self.is_zero(b) {
+            return a;
+        }
+        self.binop(BinaryOp::Add, a, b)
+    }
+
+    fn sub(&mut self, a: usize, b: usize) -> usize {
+        if self.is_zero(b) {
+            return a;
+        }
+        self.binop(BinaryOp::Sub, a, b)
+    }
+
+    fn if_positive(&mut self, cond: usize, then: usize, otherwise: usize) -> usize {
+        self.push(Node::IfPositive(IfPositive {
+            cond,
+            then,
+            otherwise,
+        }))
+    }
+
+    fn derivative(&mut self, node: usize) -> usize {
+        match self.expr.nodes[node].clone() {
+            Node::Number(_) => self.number(0.0),
+            Node::Variable(ptr) => self.number(if ptr == self.var { 1.0 } else { 0.0 }),
+            Node::UnOp(op) => {
+                let a = op.a;
+                let da = self.derivative(a);
+
+                match op.op {
+                    UnaryOp::Neg if self.is_zero(da) => da,
+                    UnaryOp::Neg => self.unop(UnaryOp::Neg, da),
+                    UnaryOp::Abs => {
+                        // da * a / |a|
+                        let abs = self.unop(UnaryOp::Abs, a);
+                        let sign = self.binop(BinaryOp::Div, a, abs);
+                        self.scale(sign, da)
+                    }
+                    UnaryOp::Loge => self.shrink(da, a),
+                    UnaryOp::Log2 | UnaryOp::Log10 => {
+                        // da / (a * ln(base))
+                        let ln_base = if op.op == UnaryOp::Log2 { LN_2 } else { LN_10 };
+                        let ln_base = self.number(ln_base);
+                        let denominator = self.binop(BinaryOp::Mul, a, ln_base);
+                        self.shrink(da, denominator)
+                    }
+                    UnaryOp::Sin => {
+                        let cos = self.unop(UnaryOp::Cos, a);
+                        self.scale(cos, da)
+                    }
+                    UnaryOp::Cos => {
+                        let sin = self.unop(UnaryOp::Sin, a);
+                        let neg_sin = self.unop(UnaryOp::Neg, sin);
+                        self.scale(neg_sin, da)
+                    }
+                    UnaryOp::Tan => {
+                        // da / cos(a)^2
+                        let cos = self.unop(UnaryOp::Cos, a);
+                        let two = self.number(2.0);
+                        let cos2 = self.binop(BinaryOp::Pow, cos, two);
+                        self.shrink(da, cos2)
+                    }
+                }
+            }
+            Node::BinOp(op) => {
+                let (a, b) = (op.a, op.b);
+                let da = self.derivative(a);
+                let db = self.derivative(b);
+
+                match op.op {
+                    BinaryOp::Add => self.add(da, db),
+                    BinaryOp::Sub => self.sub(da, db),
+                    BinaryOp::Mul => {
+                        // da * b + a * db
+                        let lhs = self.scale(b, da);
+                        let rhs = self.scale(a, db);
+                        self.add(lhs, rhs)
+                    }
+                    BinaryOp::Div => {
+                        // (da * b - a * db) / b^2
+                        let lhs = self.scale(b, da);
+                        let rhs = self.scale(a, db);
+                        let numerator = self.sub(lhs, rhs);
+                        let two = self.number(2.0);
+                        let denominator = self.binop(BinaryOp::Pow, b, two);
+                        self.shrink(numerator, denominator)
+                    }
+                    BinaryOp::Pow => {
+                        if let Node::Number(c) = self.expr.nodes[b] {
+                            // c * a^(c - 1) * da
+                            let c_minus_one = self.number(c - 1.0);
+                            let pow = self.binop(BinaryOp::Pow, a, c_minus_one);
+                            let c = self.number(c);
+                            let scaled = self.binop(BinaryOp::Mul, c, pow);
+                            self.scale(scaled, da)
+                        } else {
+                            // a^b * (db * ln(a) + b * da / a)
+                            let ln = self.unop(UnaryOp::Loge, a);
+                            let lhs = self.scale(ln, db);
+                            let b_da = self.scale(b, da);
+                            let rhs = self.shrink(b_da, a);
+                            let sum = self.add(lhs, rhs);
+                            let pow = self.binop(BinaryOp::Pow, a, b);
+                            self.scale(pow, sum)
+                        }
+                    }
+
+                    // Piecewise constant, and the derivative of the
+                    // chosen branch, ignoring the jumps in between.
+                    BinaryOp::Lt | BinaryOp::Gt => self.number(0.0),
+                    BinaryOp::Min => {
+                        let cond = self.binop(BinaryOp::Sub, b, a);
+                        self.if_positive(cond, da, db)
+                    }
+                    BinaryOp::Max => {
+                        let cond = self.binop(BinaryOp::Sub, a, b);
+                        self.if_positive(cond, da, db)
+                    }
+                }
+            }
+            Node::IfPositive(op) => {
+                let then = self.derivative(op.then);
+                let otherwise = self.derivative(op.otherwise);
+                self.if_positive(op.cond, then, otherwise)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rules() {
+        let d = |src: &str, var: usize| {
+            let expr = Expr::from_infix(src, 2, &["x", "y"]).unwrap();
+            expr.derivative(var).infix(&["x", "y"]).to_string()
+        };
+
+        assert_eq!(d("x^3", 0), "3 * x^2");
+        assert_eq!(d("x^3", 1), "0");
+        assert_eq!(d("x * y + y", 0), "y");
+        assert_eq!(d("sin(x)", 0), "cos(x)");
+        assert_eq!(d("-cos(2 * x)", 0), "2 * sin(2 * x)");
+        assert_eq!(d("ln(x)", 0), "1 / x");
+        assert_eq!(d("(x < y) * 3", 0), "0");
+        assert_eq!(d("max(x^2, y)", 0), "if_positive(x^2 - y, 2 * x, 0)");
+        assert_eq!(
+            d("if_positive(y, sin(x), 2 * x)", 0),
+            "if_positive(y, cos(x), 2)"
+        );
+    }
+
+    #[test]
+    fn finite_differences() {
+        let n_vars = 3;
+        let close = |a: f64, b: f64| (a - b).abs() <= 1e-3 * a.abs().max(1.0);
+
+        for _ in 0..10_000 {
+            let vars: Vec<f64> = (0..n_vars).map(|_| rand::random::<f64>() + 0.5).collect();
+            let mut expr = Expr::new(n_vars);
+            expr.random_tree(4);
+            let var = rand::random::<usize>() % n_vars;
+
+            let central_difference = |h: f64| {
+                let mut lo = vars.clone();
+                let mut hi = vars.clone();
+                lo[var] -= h;
+                hi[var] += h;
+                (expr.evaluate(&hi) - expr.evaluate(&lo)) / (2.0 * h)
+            };
+
+            // The rules only hold where every subexpression is finite,
+            // and `a^b` needs `a > 0` unless `b` is a constant, and
+            // `a != 0` even then, as comparisons do give an exact 0.
+            // Huge intermediate values also ruin the finite differences.
+            let well_behaved = expr.subtree(expr.root).into_iter().all(|i| {
+                let value = |node: usize| {
+                    let mut subexpr = expr.clone();
+                    subexpr.root = node;
+                    subexpr.evaluate(&vars)
+                };
+                let domain = match &expr.nodes[i] {
+                    Node::BinOp(op) if op.op == BinaryOp::Pow => {
+                        let constant = matches!(expr.nodes[op.b], Node::Number(_));
+                        value(op.a) > 0.0 || (constant && value(op.a) != 0.0)
+                    }
+                    Node::UnOp(op) if op.op == UnaryOp::Abs => value(op.a) != 0.0,
+                    _ => true,
+                };
+                domain && value(i).abs() < 1e6
+            });
+            if !well_behaved {
+                continue;
+            }
+
+            // Skip kinks, poles and the like where
+            // the step size changes the estimate.
+            let estimate = central_difference(1e-6);
+            if !estimate.is_finite() || !close(estimate, central_difference(1e-5)) {
+                continue;
+            }
+
+            let exact = expr.derivative(var).evaluate(&vars);
+            assert!(
+                close(estimate, exact),
+                "d/dx{var} {expr} = {exact}, estimated {estimate}"
+            );
+        }
+    }
+}
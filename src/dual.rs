@@ -0,0 +1,335 @@
+use std::{
+    f64::consts::{LN_10, LN_2},
+    ops::{Add, Div, Mul, Neg, Sub},
+};
+
+/// Number type the evaluators can be generic over. Implemented
+/// for plain `f64` and for `Dual`, which carries a gradient along.
+pub trait Scalar:
+    Clone
+    + Add<Output = Self>
+    + Sub<Output = Self>
+    + Mul<Output = Self>
+    + Div<Output = Self>
+    + Neg<Output = Self>
+{
+    /// A constant, which has no gradient.
+    fn constant(x: f64) -> Self;
+    /// The plain value.
+    fn value(&self) -> f64;
+
+    /// Absolute value.
+    fn abs(self) -> Self;
+    /// Natural logarithm.
+    fn ln(self) -> Self;
+    /// Base 2 logarithm.
+    fn log2(self) -> Self;
+    /// Base 10 logarithm.
+    fn log10(self) -> Self;
+    /// Sine.
+    fn sin(self) -> Self;
+    /// Cosine.
+    fn cos(self) -> Self;
+    /// Tangent.
+    fn tan(self) -> Self;
+    /// Raised to the power `exponent`.
+    fn powf(self, exponent: Self) -> Self;
+
+    /// `then` if the value is positive, `otherwise` if not or NaN.
+    fn if_positive(self, then: Self, otherwise: Self) -> Self {
+        if self.value() > 0.0 {
+            then
+        } else {
+            otherwise
+        }
+    }
+
+    // The comparisons, `min` and `max` branch on a difference, which
+    // keeps the gradient of the branch taken. `b - a > 0` is exactly
+    // `a < b`, as distinct floats never subtract to zero. They are
+    // named apart from `PartialOrd::lt` and `f64::min`, which treat
+    // NaN differently and would win method resolution on `f64`.
+
+    /// 1 if less than `rhs`, and 0 otherwise.
+    fn less(self, rhs: Self) -> Self {
+        (rhs - self).if_positive(Self::constant(1.0), Self::constant(0.0))
+    }
+
+    /// 1 if greater than `rhs`, and 0 otherwise.
+    fn greater(self, rhs: Self) -> Self {
+        (self - rhs).if_positive(Self::constant(1.0), Self::constant(0.0))
+    }
+
+    /// The lesser of the two, `rhs` if either is NaN.
+    fn select_min(self, rhs: Self) -> Self {
+        (rhs.clone() - self.clone()).if_positive(self, rhs)
+    }
+
+    /// The greater of the two, `rhs` if either is NaN.
+    fn select_max(self, rhs: Self) -> Self {
+        (self.clone() - rhs.clone()).if_positive(self, rhs)
+    }
+}
+
+impl Scalar for f64 {
+    fn constant(x: f64) -> f64 {
+        x
+    }
+
+    fn value(&self) -> f64 {
+        *self
+    }
+
+    fn abs(self) -> f64 {
+        f64::abs(self)
+    }
+
+    fn ln(self) -> f64 {
+        f64::ln(self)
+    }
+
+    fn log2(self) -> f64 {
+        f64::log2(self)
+    }
+
+    fn log10(self) -> f64 {
+        f64::log10(self)
+    }
+
+    fn sin(self) -> f64 {
+        f64::sin(self)
+    }
+
+    fn cos(self) -> f64 {
+        f64::cos(self)
+    }
+
+    fn tan(self) -> f64 {
+        f64::tan(self)
+    }
+
+    fn powf(self, exponent: f64) -> f64 {
+        f64::powf(self, exponent)
+    }
+}
+
+/// Dual number for forward-mode automatic differentiation.
+/// `grad` holds the partial derivatives with respect to each
+/// seeded parameter. Constants have an empty gradient, which
+/// is treated as all zeros, to save on allocations.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dual {
+    /// The plain value.
+    pub value: f64,
+    /// Partial derivatives, one per parameter.
+    pub grad: Vec<f64>,
+}
+
+impl Dual {
+    /// The `i`th of `n` parameters, with a unit gradient.
+    pub fn variable(value: f64, i: usize, n: usize) -> Dual {
+        let mut grad = vec![0.0; n];
+        grad[i] = 1.0;
+        Dual { value, grad }
+    }
+
+    // Applies the chain rule for a function with derivative `d`.
+    fn chain(self, value: f64, d: f64) -> Dual {
+        Dual {
+            value,
+            grad: self.grad.iter().map(|g| g * d).collect(),
+        }
+    }
+}
+
+// `a * da + b * db` elementwise, with missing
+// entries in the shorter gradient being zero.
+fn combine(da: &[f64], a: f64, db: &[f64], b: f64) -> Vec<f64> {
+    let n = da.len().max(db.len());
+    (0..n)
+        .map(|i| {
+            let x = da.get(i).map_or(0.0, |g| g * a);
+            let y = db.get(i).map_or(0.0, |g| g * b);
+            x + y
+        })
+        .collect()
+}
+
+impl Add for Dual {
+    type Output = Dual;
+
+    fn add(self, rhs: Dual) -> Dual {
+        Dual {
+            value: self.value + rhs.value,
+            grad: combine(&self.grad, 1.0, &rhs.grad, 1.0),
+        }
+    }
+}
+
+impl Sub for Dual {
+    type Output = Dual;
+
+    fn sub(self, rhs: Dual) -> Dual {
+        Dual {
+            value: self.value - rhs.value,
+            grad: combine(&self.grad, 1.0, &rhs.grad, -1.0),
+        }
+    }
+}
+
+impl Mul for Dual {
+    type Output = Dual;
+
+    fn mul(self, rhs: Dual) -> Dual {
+        Dual {
+            value: self.value * rhs.value,
+            grad: combine(&self.grad, rhs.value, &rhs.grad, self.value),
+        }
+    }
+}
+
+impl Div for Dual {
+    type Output = Dual;
+
+    fn div(self, rhs: Dual) -> Dual {
+        let b2 = rhs.value * rhs.value;
+        Dual {
+            value: self.value / rhs.value,
+            grad: combine(&self.grad, 1.0 / rhs.value, &rhs.grad, -self.value / b2),
+        }
+    }
+}
+
+impl Neg for Dual {
+    type Output = Dual;
+
+    fn neg(self) -> Dual {
+        let value = -self.value;
+        self.chain(value, -1.0)
+    }
+}
+
+impl Scalar for Dual {
+    fn constant(x: f64) -> Dual {
+        Dual {
+            value: x,
+            grad: Vec::new(),
+        }
+    }
+
+    fn value(&self) -> f64 {
+        self.value
+    }
+
+    fn abs(self) -> Dual {
+        let sign = if self.value > 0.0 {
+            1.0
+        } else if self.value < 0.0 {
+            -1.0
+        } else {
+            0.0
+        };
+        let value = self.value.abs();
+        self.chain(value, sign)
+    }
+
+    fn ln(self) -> Dual {
+        let x = self.value;
+        self.chain(x.ln(), 1.0 / x)
+    }
+
+    fn log2(self) -> Dual {
+        let x = self.value;
+        self.chain(x.log2(), 1.0 / (x * LN_2))
+    }
+
+    fn log10(self) -> Dual {
+        let x = self.value;
+        self.chain(x.log10(), 1.0 / (x * LN_10))
+    }
+
+    fn sin(self) -> Dual {
+        let x = self.value;
+        self.chain(x.sin(), x.cos())
+    }
+
+    fn cos(self) -> Dual {
+        let x = self.value;
+        self.chain(x.cos(), -x.sin())
+    }
+
+    fn tan(self) -> Dual {
+        let x = self.value;
+        let cos = x.cos();
+        self.chain(x.tan(), 1.0 / (cos * cos))
+    }
+
+    fn powf(self, exponent: Dual) -> Dual {
+        let (a, b) = (self.value, exponent.value);
+        let value = a.powf(b);
+        let da = b * a.powf(b - 1.0);
+
+        // Skip the `ln(a)` term for constant exponents,
+        // so negative bases don't turn the gradient NaN.
+        let db = if exponent.grad.iter().all(|g| *g == 0.0) {
+            0.0
+        } else {
+            value * a.ln()
+        };
+
+        Dual {
+            value,
+            grad: combine(&self.grad, da, &exponent.grad, db),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::expr::Expr;
+
+    #[test]
+    fn gradient() {
+        let n_vars = 3;
+        for _ in 0..1_000 {
+            let vars: Vec<f64> = (0..n_vars).map(|_| rand::random::<f64>() + 0.5).collect();
+            let mut expr = Expr::new(n_vars);
+            expr.random_tree(4);
+
+            let inputs: Vec<Dual> = vars
+                .iter()
+                .enumerate()
+                .map(|(i, x)| Dual::variable(*x, i, n_vars))
+                .collect();
+            let dual = expr.evaluate_scalar(&inputs, &|_, x| Dual::constant(x));
+
+            let value = expr.evaluate(&vars);
+            assert!(dual.value == value || (dual.value.is_nan() && value.is_nan()));
+
+            // `derivative` simplifies first, which only holds where
+            // every subexpression is finite. Comparisons can hide
+            // the ones that aren't from the value.
+            let finite = expr.subtree(expr.root).into_iter().all(|i| {
+                let mut subexpr = expr.clone();
+                subexpr.root = i;
+                subexpr.evaluate(&vars).is_finite()
+            });
+            if !finite {
+                continue;
+            }
+
+            for var in 0..n_vars {
+                let exact = expr.derivative(var).evaluate(&vars);
+                let grad = dual.grad.get(var).copied().unwrap_or(0.0);
+                if !exact.is_finite() || !grad.is_finite() {
+                    continue;
+                }
+                assert!(
+                    (exact - grad).abs() <= 1e-6 * exact.abs().max(1.0),
+                    "d/dx{var} {expr} = {exact}, dual {grad}"
+                );
+            }
+        }
+    }
+}
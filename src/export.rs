@@ -0,0 +1,936 @@
+use crate::{
+    expr::{BinaryOp, Expr, Node, UnaryOp},
+    format::child_needs_parens,
+};
+
+impl Expr {
+    /// LaTeX math mode source, for example
+    /// `\frac{\sin\left(x_{0}\right)}{2}`.
+    pub fn to_latex(&self, names: &[&str]) -> String {
+        let vars: Vec<String> = (0..self.n_inputs)
+            .map(|i| match names.get(i) {
+                Some(name) => format!("\\mathrm{{{}}}", latex_escape(name)),
+                None => format!("x_{{{i}}}"),
+            })
+            .collect();
+
+        let mut out = String::new();
+        self.write_latex(&mut out, self.root, &vars);
+        out
+    }
+
+    /// A Python snippet that declares the variables as
+    /// SymPy symbols and binds the expression to `expr`.
+    pub fn to_sympy(&self, names: &[&str]) -> String {
+        let vars = identifiers(self.n_inputs, names, python_identifier, "_");
+
+        let mut body = String::new();
+        self.write_infix(&mut body, self.root, &vars, &SYMPY);
+
+        let mut out = String::from("import sympy as sp\n\n");
+        if !vars.is_empty() {
+            // `seq` returns a tuple even for a single
+            // name, which the trailing comma unpacks.
+            out += &format!(
+                "{}, = sp.symbols(\"{}\", seq=True)\n",
+                vars.join(", "),
+                vars.join(" ")
+            );
+        }
+        out += &format!("expr = {body}\n");
+        out
+    }
+
+    /// Mathematica (Wolfram Language) expression.
+    pub fn to_mathematica(&self, names: &[&str]) -> String {
+        let vars = identifiers(self.n_inputs, names, mathematica_identifier, "");
+
+        let mut out = String::new();
+        self.write_infix(&mut out, self.root, &vars, &MATHEMATICA);
+        out
+    }
+
+    /// Rust source with `pub fn predict(x: &[f64]) -> f64` and
+    /// `pub fn predict_f32(x: &[f32]) -> f32`, for committing into
+    /// other crates. The inputs are bound to locals named after the
+    /// columns. Comparisons are written out so that NaN takes the
+    /// same branch as in `evaluate`, which `f64::min` wouldn't do.
+    pub fn to_rust(&self, names: &[&str]) -> String {
+        let vars = identifiers(self.n_inputs, names, rust_identifier, "_");
+
+        let mut out = String::new();
+        let mut helpers = Vec::new();
+        for ty in ["f64", "f32"] {
+            let mut writer = RustWriter {
+                expr: self,
+                vars: &vars,
+                ty,
+                used: vec![false; self.n_inputs],
+                helpers: Vec::new(),
+            };
+            let (body, _) = writer.write(self.root);
+
+            if !vars.is_empty() {
+                out += &format!(
+                    "/// Predicts from the inputs in the order {}.\n",
+                    vars.join(", ")
+                );
+            }
+            let name = if ty == "f64" {
+                "predict"
+            } else {
+                "predict_f32"
+            };
+            let param = if writer.used.contains(&true) {
+                "x"
+            } else {
+                "_x"
+            };
+            out += &format!("pub fn {name}({param}: &[{ty}]) -> {ty} {{\n");
+            for (i, var) in vars.iter().enumerate() {
+                if writer.used[i] {
+                    out += &format!("    let {var} = x[{i}];\n");
+                }
+            }
+            out += &format!("    {body}\n}}\n\n");
+
+            for helper in writer.helpers {
+                if !helpers.contains(&helper) {
+                    helpers.push(helper);
+                }
+            }
+        }
+
+        // Generic, so both functions can share them
+        helpers.sort();
+        for helper in helpers {
+            let cmp = if helper == "min" { "<" } else { ">" };
+            out += &format!(
+                "fn {helper}<T: PartialOrd>(a: T, b: T) -> T {{\n    \
+                 if a {cmp} b {{\n        a\n    }} else {{\n        b\n    }}\n}}\n\n"
+            );
+        }
+
+        out.pop();
+        out
+    }
+
+    fn write_latex(&self, out: &mut String, node: usize, vars: &[String]) {
+        match &self.nodes[node] {
+            Node::Number(x) => *out += &latex_number(*x),
+            Node::Variable(ptr) => *out += &vars[*ptr],
+            Node::UnOp(op) => {
+                let (open, close) = match op.op {
+                    UnaryOp::Neg => {
+                        *out += "-";
+                        self.write_latex_operand(out, op.a, vars, self.latex_parens(node, op.a));
+                        return;
+                    }
+                    UnaryOp::Abs => ("\\left|", "\\right|"),
+                    UnaryOp::Loge => ("\\ln\\left(", "\\right)"),
+                    UnaryOp::Log2 => ("\\log_{2}\\left(", "\\right)"),
+                    UnaryOp::Log10 => ("\\log_{10}\\left(", "\\right)"),
+                    UnaryOp::Sin => ("\\sin\\left(", "\\right)"),
+                    UnaryOp::Cos => ("\\cos\\left(", "\\right)"),
+                    UnaryOp::Tan => ("\\tan\\left(", "\\right)"),
+                };
+                *out += open;
+                self.write_latex(out, op.a, vars);
+                *out += close;
+            }
+            Node::BinOp(op) => match op.op {
+                // Iverson bracket, 1 when true and 0 otherwise
+                BinaryOp::Lt | BinaryOp::Gt => {
+                    *out += "\\left[";
+                    self.write_latex(out, op.a, vars);
+                    *out += if op.op == BinaryOp::Lt { " < " } else { " > " };
+                    self.write_latex(out, op.b, vars);
+                    *out += "\\right]";
+                }
+                BinaryOp::Min | BinaryOp::Max => {
+                    *out += if op.op == BinaryOp::Min {
+                        "\\min"
+                    } else {
+                        "\\max"
+                    };
+                    *out += "\\left(";
+                    self.write_latex(out, op.a, vars);
+                    *out += ", ";
+                    self.write_latex(out, op.b, vars);
+                    *out += "\\right)";
+                }
+                BinaryOp::Div => {
+                    *out += "\\frac{";
+                    self.write_latex(out, op.a, vars);
+                    *out += "}{";
+                    self.write_latex(out, op.b, vars);
+                    *out += "}";
+                }
+                BinaryOp::Pow => {
+                    *out += "{";
+                    self.write_latex_operand(out, op.a, vars, self.latex_parens(node, op.a));
+                    *out += "}^{";
+                    self.write_latex(out, op.b, vars);
+                    *out += "}";
+                }
+                _ => {
+                    self.write_latex_operand(out, op.a, vars, self.latex_parens(node, op.a));
+                    *out += match op.op {
+                        BinaryOp::Add => " + ",
+                        BinaryOp::Sub => " - ",
+                        _ => " \\cdot ",
+                    };
+                    self.write_latex_operand(out, op.b, vars, self.latex_parens(node, op.b));
+                }
+            },
+            Node::IfPositive(op) => {
+                *out += "\\begin{cases} ";
+                self.write_latex(out, op.then, vars);
+                *out += " & \\text{if } ";
+                self.write_latex(out, op.cond, vars);
+                *out += " > 0 \\\\ ";
+                self.write_latex(out, op.otherwise, vars);
+                *out += " & \\text{otherwise} \\end{cases}";
+            }
+        }
+    }
+
+    fn write_latex_operand(&self, out: &mut String, node: usize, vars: &[String], parens: bool) {
+        if parens {
+            *out += "\\left(";
+            self.write_latex(out, node, vars);
+            *out += "\\right)";
+        } else {
+            self.write_latex(out, node, vars);
+        }
+    }
+
+    // Same rules as the plain infix notation, except that
+    // fractions group their operands on their own and the
+    // base of a power has to be a single symbol.
+    fn latex_parens(&self, parent: usize, child: usize) -> bool {
+        let is_fraction = matches!(
+            &self.nodes[child],
+            Node::BinOp(op) if op.op == BinaryOp::Div
+        );
+
+        match &self.nodes[parent] {
+            Node::BinOp(op) if op.op == BinaryOp::Pow && op.a == child => {
+                match &self.nodes[child] {
+                    Node::Number(x) => x.is_sign_negative(),
+                    Node::Variable(_) => false,
+                    _ => true,
+                }
+            }
+            _ if is_fraction => false,
+            _ => self.operand_parens(parent, child),
+        }
+    }
+
+    // Like the plain infix notation, except that comparisons
+    // are written as calls or brackets, which group on their own.
+    fn operand_parens(&self, parent: usize, child: usize) -> bool {
+        let is_comparison = matches!(
+            &self.nodes[child],
+            Node::BinOp(op) if matches!(op.op, BinaryOp::Lt | BinaryOp::Gt)
+        );
+        !is_comparison && child_needs_parens(self, parent, child)
+    }
+
+    fn write_infix(&self, out: &mut String, node: usize, vars: &[String], dialect: &Dialect) {
+        match &self.nodes[node] {
+            Node::Number(x) => *out += &(dialect.number)(*x),
+            Node::Variable(ptr) => *out += &vars[*ptr],
+            Node::UnOp(op) => match op.op {
+                UnaryOp::Neg => {
+                    *out += "-";
+                    let parens = self.operand_parens(node, op.a);
+                    self.write_infix_operand(out, op.a, vars, dialect, parens);
+                }
+                _ => {
+                    let mut arg = String::new();
+                    self.write_infix(&mut arg, op.a, vars, dialect);
+                    *out += &(dialect.call)(&op.op, &arg);
+                }
+            },
+            Node::BinOp(op) if matches!(op.op, BinaryOp::Lt | BinaryOp::Gt) => {
+                let mut a = String::new();
+                let mut b = String::new();
+                self.write_infix(&mut a, op.a, vars, dialect);
+                self.write_infix(&mut b, op.b, vars, dialect);
+                let symbol = if op.op == BinaryOp::Lt { "<" } else { ">" };
+                *out += &(dialect.boole)(&format!("{a} {symbol} {b}"));
+            }
+            Node::BinOp(op) if matches!(op.op, BinaryOp::Min | BinaryOp::Max) => {
+                let mut a = String::new();
+                let mut b = String::new();
+                self.write_infix(&mut a, op.a, vars, dialect);
+                self.write_infix(&mut b, op.b, vars, dialect);
+                let name = if op.op == BinaryOp::Min { "Min" } else { "Max" };
+                *out += &(dialect.extremum)(name, &a, &b);
+            }
+            Node::BinOp(op) => {
+                let parens = self.operand_parens(node, op.a);
+                self.write_infix_operand(out, op.a, vars, dialect, parens);
+                *out += match op.op {
+                    BinaryOp::Add => " + ",
+                    BinaryOp::Sub => " - ",
+                    BinaryOp::Mul => "*",
+                    BinaryOp::Div => "/",
+                    BinaryOp::Pow => dialect.pow,
+                    _ => unreachable!(),
+                };
+                let parens = self.operand_parens(node, op.b);
+                self.write_infix_operand(out, op.b, vars, dialect, parens);
+            }
+            Node::IfPositive(op) => {
+                let mut cond = String::new();
+                let mut then = String::new();
+                let mut otherwise = String::new();
+                self.write_infix(&mut cond, op.cond, vars, dialect);
+                self.write_infix(&mut then, op.then, vars, dialect);
+                self.write_infix(&mut otherwise, op.otherwise, vars, dialect);
+                *out += &(dialect.piecewise)(&format!("{cond} > 0"), &then, &otherwise);
+            }
+        }
+    }
+
+    fn write_infix_operand(
+        &self,
+        out: &mut String,
+        node: usize,
+        vars: &[String],
+        dialect: &Dialect,
+        parens: bool,
+    ) {
+        if parens {
+            *out += "(";
+            self.write_infix(out, node, vars, dialect);
+            *out += ")";
+        } else {
+            self.write_infix(out, node, vars, dialect);
+        }
+    }
+}
+
+// Precedence of Rust expressions, lowest first. Operands of a
+// lower precedence than the operator get parentheses.
+const RUST_IF: u8 = 0;
+const RUST_SUM: u8 = 1;
+const RUST_PRODUCT: u8 = 2;
+const RUST_UNARY: u8 = 3;
+const RUST_ATOM: u8 = 4;
+
+struct RustWriter<'a> {
+    expr: &'a Expr,
+    vars: &'a [String],
+
+    // `f64` or `f32`, literals carry it as a suffix
+    // so that method calls on them type check
+    ty: &'static str,
+
+    // Inputs the expression reads
+    used: Vec<bool>,
+
+    // Names of the helper functions called
+    helpers: Vec<&'static str>,
+}
+
+impl RustWriter<'_> {
+    // The source for `node` and its precedence.
+    fn write(&mut self, node: usize) -> (String, u8) {
+        let ty = self.ty;
+        match &self.expr.nodes[node] {
+            Node::Number(x) => {
+                // Rounded first, `1e300_f32` doesn't compile, and printed
+                // as `f32` so that `0.1` doesn't gain digits it can't hold
+                let (x, digits) = if ty == "f32" {
+                    (*x as f32 as f64, format!("{:?}", *x as f32))
+                } else {
+                    (*x, format!("{x:?}"))
+                };
+                if x.is_nan() {
+                    (format!("{ty}::NAN"), RUST_ATOM)
+                } else if x.is_infinite() {
+                    let name = if x > 0.0 { "INFINITY" } else { "NEG_INFINITY" };
+                    (format!("{ty}::{name}"), RUST_ATOM)
+                } else if x.is_sign_negative() {
+                    (format!("{digits}_{ty}"), RUST_UNARY)
+                } else {
+                    (format!("{digits}_{ty}"), RUST_ATOM)
+                }
+            }
+            Node::Variable(ptr) => {
+                self.used[*ptr] = true;
+                (self.vars[*ptr].clone(), RUST_ATOM)
+            }
+            Node::UnOp(op) => {
+                // Receivers have to be atoms, and so does the operand
+                // of `-`, to write `-(-x)` rather than `--x`
+                let a = self.operand(op.a, RUST_ATOM);
+                let method = match op.op {
+                    UnaryOp::Neg => return (format!("-{a}"), RUST_UNARY),
+                    UnaryOp::Abs => "abs",
+                    UnaryOp::Loge => "ln",
+                    UnaryOp::Log2 => "log2",
+                    UnaryOp::Log10 => "log10",
+                    UnaryOp::Sin => "sin",
+                    UnaryOp::Cos => "cos",
+                    UnaryOp::Tan => "tan",
+                };
+                (format!("{a}.{method}()"), RUST_ATOM)
+            }
+            Node::BinOp(op) => match op.op {
+                BinaryOp::Lt | BinaryOp::Gt => {
+                    let symbol = if op.op == BinaryOp::Lt { "<" } else { ">" };
+                    let a = self.operand(op.a, RUST_SUM);
+                    let b = self.operand(op.b, RUST_SUM);
+                    let source = format!("if {a} {symbol} {b} {{ 1.0_{ty} }} else {{ 0.0_{ty} }}");
+                    (source, RUST_IF)
+                }
+                BinaryOp::Min | BinaryOp::Max => {
+                    let name = if op.op == BinaryOp::Min { "min" } else { "max" };
+                    self.helpers.push(name);
+                    let (a, _) = self.write(op.a);
+                    let (b, _) = self.write(op.b);
+                    (format!("{name}({a}, {b})"), RUST_ATOM)
+                }
+                BinaryOp::Pow => {
+                    let a = self.operand(op.a, RUST_ATOM);
+                    let (b, _) = self.write(op.b);
+                    (format!("{a}.powf({b})"), RUST_ATOM)
+                }
+                _ => {
+                    let (symbol, precedence) = match op.op {
+                        BinaryOp::Add => ("+", RUST_SUM),
+                        BinaryOp::Sub => ("-", RUST_SUM),
+                        BinaryOp::Mul => ("*", RUST_PRODUCT),
+                        _ => ("/", RUST_PRODUCT),
+                    };
+                    // Left associative, and regrouping would round
+                    // differently, so the right side keeps its parens
+                    let a = self.operand(op.a, precedence);
+                    let b = self.operand(op.b, precedence + 1);
+                    (format!("{a} {symbol} {b}"), precedence)
+                }
+            },
+            Node::IfPositive(op) => {
+                let cond = self.operand(op.cond, RUST_SUM);
+                let (then, _) = self.write(op.then);
+                let (otherwise, _) = self.write(op.otherwise);
+                let source = format!("if {cond} > 0.0 {{ {then} }} else {{ {otherwise} }}");
+                (source, RUST_IF)
+            }
+        }
+    }
+
+    // `node`, in parentheses unless it has at least `precedence`.
+    fn operand(&mut self, node: usize, precedence: u8) -> String {
+        match self.write(node) {
+            (source, p) if p < precedence => format!("({source})"),
+            (source, _) => source,
+        }
+    }
+}
+
+// Both SymPy and Mathematica share the operator precedence and
+// associativity of the plain infix notation, including `-x^2`
+// meaning `-(x^2)`, so they only differ in the spelling.
+struct Dialect {
+    pow: &'static str,
+    number: fn(f64) -> String,
+    call: fn(&UnaryOp, &str) -> String,
+
+    // 1 if the condition holds, 0 otherwise
+    boole: fn(&str) -> String,
+
+    // `Min` or `Max` of two arguments
+    extremum: fn(&str, &str, &str) -> String,
+
+    // The second argument if the condition holds, else the third
+    piecewise: fn(&str, &str, &str) -> String,
+}
+
+const SYMPY: Dialect = Dialect {
+    pow: "**",
+    number: |x| {
+        if x.is_nan() {
+            "sp.nan".to_string()
+        } else if x.is_infinite() {
+            if x > 0.0 { "sp.oo" } else { "-sp.oo" }.to_string()
+        } else {
+            format!("{x:?}")
+        }
+    },
+    call: |op, arg| match op {
+        UnaryOp::Neg => format!("-({arg})"),
+        UnaryOp::Abs => format!("sp.Abs({arg})"),
+        UnaryOp::Loge => format!("sp.log({arg})"),
+        UnaryOp::Log2 => format!("sp.log({arg}, 2)"),
+        UnaryOp::Log10 => format!("sp.log({arg}, 10)"),
+        UnaryOp::Sin => format!("sp.sin({arg})"),
+        UnaryOp::Cos => format!("sp.cos({arg})"),
+        UnaryOp::Tan => format!("sp.tan({arg})"),
+    },
+    boole: |cond| format!("sp.Piecewise((1, {cond}), (0, True))"),
+    extremum: |name, a, b| format!("sp.{name}({a}, {b})"),
+    piecewise: |cond, then, otherwise| {
+        format!("sp.Piecewise(({then}, {cond}), ({otherwise}, True))")
+    },
+};
+
+const MATHEMATICA: Dialect = Dialect {
+    pow: "^",
+    number: |x| {
+        if x.is_nan() {
+            "Indeterminate".to_string()
+        } else if x.is_infinite() {
+            if x > 0.0 { "Infinity" } else { "-Infinity" }.to_string()
+        } else {
+            // Not `{x:?}`, `1e300` means `1 * e * 300` in Mathematica
+            format!("{x}")
+        }
+    },
+    call: |op, arg| match op {
+        UnaryOp::Neg => format!("-({arg})"),
+        UnaryOp::Abs => format!("Abs[{arg}]"),
+        UnaryOp::Loge => format!("Log[{arg}]"),
+        UnaryOp::Log2 => format!("Log[2, {arg}]"),
+        UnaryOp::Log10 => format!("Log10[{arg}]"),
+        UnaryOp::Sin => format!("Sin[{arg}]"),
+        UnaryOp::Cos => format!("Cos[{arg}]"),
+        UnaryOp::Tan => format!("Tan[{arg}]"),
+    },
+    boole: |cond| format!("Boole[{cond}]"),
+    extremum: |name, a, b| format!("{name}[{a}, {b}]"),
+    piecewise: |cond, then, otherwise| format!("Piecewise[{{{{{then}, {cond}}}}}, {otherwise}]"),
+};
+
+fn latex_number(x: f64) -> String {
+    if x.is_nan() {
+        "\\mathrm{NaN}".to_string()
+    } else if x.is_infinite() {
+        if x > 0.0 { "\\infty" } else { "-\\infty" }.to_string()
+    } else {
+        format!("{x}")
+    }
+}
+
+fn latex_escape(name: &str) -> String {
+    let mut res = String::new();
+    for c in name.chars() {
+        match c {
+            '_' | '%' | '&' | '#' | '$' | '{' | '}' => {
+                res.push('\\');
+                res.push(c);
+            }
+            '\\' => res += "\\backslash{}",
+            _ => res.push(c),
+        }
+    }
+    res
+}
+
+const PYTHON_KEYWORDS: [&str; 35] = [
+    "False", "None", "True", "and", "as", "assert", "async", "await", "break", "class", "continue",
+    "def", "del", "elif", "else", "except", "finally", "for", "from", "global", "if", "import",
+    "in", "is", "lambda", "nonlocal", "not", "or", "pass", "raise", "return", "try", "while",
+    "with", "yield",
+];
+
+// Distinct identifiers for the inputs, `x{i}` for those without a name.
+// Names that sanitize to the same identifier get the index of the input
+// appended, after `separator`.
+fn identifiers(
+    n_inputs: usize,
+    names: &[&str],
+    identifier: impl Fn(&str) -> String,
+    separator: &str,
+) -> Vec<String> {
+    let mut vars: Vec<String> = Vec::new();
+    for i in 0..n_inputs {
+        let mut ident = match names.get(i) {
+            Some(name) => identifier(name),
+            None => format!("x{i}"),
+        };
+        while vars.contains(&ident) {
+            ident += &format!("{separator}{i}");
+        }
+        vars.push(ident);
+    }
+    vars
+}
+
+fn python_identifier(name: &str) -> String {
+    let mut res: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+
+    if res.is_empty() || res.starts_with(|c: char| c.is_ascii_digit()) {
+        res.insert(0, '_');
+    }
+    // `sp` is the name of the module
+    if PYTHON_KEYWORDS.contains(&res.as_str()) || res == "sp" {
+        res.push('_');
+    }
+
+    res
+}
+
+// Mathematica identifiers can only contain letters and digits, so
+// `sepal_length` becomes `sepalLength`. The first letter is always
+// lowercase to keep clear of the built-in symbols like `E` and `N`.
+fn mathematica_identifier(name: &str) -> String {
+    let mut res = String::new();
+    let mut upper = false;
+
+    for c in name.chars() {
+        if !c.is_ascii_alphanumeric() {
+            upper = !res.is_empty();
+        } else if res.is_empty() {
+            if c.is_ascii_digit() {
+                res.push('x');
+                res.push(c);
+            } else {
+                res.push(c.to_ascii_lowercase());
+            }
+        } else if upper {
+            res.push(c.to_ascii_uppercase());
+            upper = false;
+        } else {
+            res.push(c);
+        }
+    }
+
+    if res.is_empty() {
+        res.push('x');
+    }
+
+    res
+}
+
+const RUST_KEYWORDS: [&str; 51] = [
+    "Self", "abstract", "as", "async", "await", "become", "box", "break", "const", "continue",
+    "crate", "do", "dyn", "else", "enum", "extern", "false", "final", "fn", "for", "gen", "if",
+    "impl", "in", "let", "loop", "macro", "match", "mod", "move", "mut", "override", "priv", "pub",
+    "ref", "return", "self", "static", "struct", "super", "trait", "true", "try", "type", "typeof",
+    "union", "unsafe", "unsized", "use", "virtual", "where",
+];
+
+// Snake case, so the output passes the default lints. `x`
+// and the helpers are taken by the generated code.
+fn rust_identifier(name: &str) -> String {
+    let mut res: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+
+    if res.is_empty() || res.starts_with(|c: char| c.is_ascii_digit()) {
+        res.insert(0, '_');
+    }
+    if RUST_KEYWORDS.contains(&res.as_str()) || ["_", "x", "min", "max"].contains(&res.as_str()) {
+        res.push('_');
+    }
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use super::*;
+
+    const NAMES: [&str; 3] = ["sepal_length", "class", "2nd"];
+
+    fn sample() -> Expr {
+        // Covers every operator and function
+        let src = "-x0^2 / abs(x1 - 1.5) + ln(x2) * log2(x2) - log10(sin(x0)) \
+                   * cos(x1)^-tan(-0.5 * x2)";
+        Expr::from_infix(src, 3, &[]).unwrap()
+    }
+
+    #[test]
+    fn latex() {
+        assert_eq!(
+            sample().to_latex(&NAMES),
+            "\\frac{-{\\mathrm{sepal\\_length}}^{2}}{\\left|\\mathrm{class} - 1.5\\right|} \
+             + \\ln\\left(\\mathrm{2nd}\\right) \\cdot \\log_{2}\\left(\\mathrm{2nd}\\right) \
+             - \\log_{10}\\left(\\sin\\left(\\mathrm{sepal\\_length}\\right)\\right) \
+             \\cdot {\\left(\\cos\\left(\\mathrm{class}\\right)\\right)}^{-\\tan\\left(-0.5 \
+             \\cdot \\mathrm{2nd}\\right)}"
+        );
+    }
+
+    #[test]
+    fn mathematica() {
+        assert_eq!(
+            sample().to_mathematica(&NAMES),
+            "-sepalLength^2/Abs[class - 1.5] + Log[x2nd]*Log[2, x2nd] \
+             - Log10[Sin[sepalLength]]*Cos[class]^(-Tan[-0.5*x2nd])"
+        );
+
+        let expr = Expr::from_infix("x0 + x1 + x2 + x3", 4, &[]).unwrap();
+        assert_eq!(
+            expr.to_mathematica(&["sepal length", "sepalLength", "", "x"]),
+            "sepalLength + sepalLength1 + x + x3"
+        );
+    }
+
+    #[test]
+    fn piecewise() {
+        let src = "if_positive(x0 - 1, min(x0, x1), 2) * -(x0 < x1)";
+        let expr = Expr::from_infix(src, 2, &[]).unwrap();
+        assert_eq!(
+            expr.to_latex(&[]),
+            "\\begin{cases} \\min\\left(x_{0}, x_{1}\\right) & \\text{if } x_{0} - 1 > 0 \\\\ \
+             2 & \\text{otherwise} \\end{cases} \\cdot -\\left[x_{0} < x_{1}\\right]"
+        );
+        assert_eq!(
+            expr.to_mathematica(&[]),
+            "Piecewise[{{Min[x0, x1], x0 - 1 > 0}}, 2]*-Boole[x0 < x1]"
+        );
+        assert_eq!(
+            expr.to_sympy(&[]),
+            "import sympy as sp\n\n\
+             x0, x1, = sp.symbols(\"x0 x1\", seq=True)\n\
+             expr = sp.Piecewise((sp.Min(x0, x1), x0 - 1.0 > 0), (2.0, True))\
+             *-sp.Piecewise((1, x0 < x1), (0, True))\n"
+        );
+    }
+
+    #[test]
+    fn rust() {
+        assert_eq!(
+            sample().to_rust(&NAMES),
+            "/// Predicts from the inputs in the order sepal_length, class, _2nd.\n\
+             pub fn predict(x: &[f64]) -> f64 {\n    \
+             let sepal_length = x[0];\n    \
+             let class = x[1];\n    \
+             let _2nd = x[2];\n    \
+             -sepal_length.powf(2.0_f64) / (class - 1.5_f64).abs() + _2nd.ln() * _2nd.log2() \
+             - sepal_length.sin().log10() * class.cos().powf(-(-0.5_f64 * _2nd).tan())\n\
+             }\n\
+             \n\
+             /// Predicts from the inputs in the order sepal_length, class, _2nd.\n\
+             pub fn predict_f32(x: &[f32]) -> f32 {\n    \
+             let sepal_length = x[0];\n    \
+             let class = x[1];\n    \
+             let _2nd = x[2];\n    \
+             -sepal_length.powf(2.0_f32) / (class - 1.5_f32).abs() + _2nd.ln() * _2nd.log2() \
+             - sepal_length.sin().log10() * class.cos().powf(-(-0.5_f32 * _2nd).tan())\n\
+             }\n"
+        );
+
+        // 0.1 isn't exact in either type, and each gets its own shortest digits
+        let expr = Expr::from_infix("0.1 * x0", 1, &[]).unwrap();
+        assert_eq!(
+            expr.to_rust(&["a"]),
+            "/// Predicts from the inputs in the order a.\n\
+             pub fn predict(x: &[f64]) -> f64 {\n    \
+             let a = x[0];\n    \
+             0.1_f64 * a\n\
+             }\n\
+             \n\
+             /// Predicts from the inputs in the order a.\n\
+             pub fn predict_f32(x: &[f32]) -> f32 {\n    \
+             let a = x[0];\n    \
+             0.1_f32 * a\n\
+             }\n"
+        );
+
+        let src = "if_positive(x0 - 1, min(x0, x1), 2) * -(x0 < x1)";
+        let expr = Expr::from_infix(src, 3, &[]).unwrap();
+        assert_eq!(
+            expr.to_rust(&["x", "Type", "x"]),
+            "/// Predicts from the inputs in the order x_, type_, x__2.\n\
+             pub fn predict(x: &[f64]) -> f64 {\n    \
+             let x_ = x[0];\n    \
+             let type_ = x[1];\n    \
+             (if x_ - 1.0_f64 > 0.0 { min(x_, type_) } else { 2.0_f64 }) \
+             * -(if x_ < type_ { 1.0_f64 } else { 0.0_f64 })\n\
+             }\n\
+             \n\
+             /// Predicts from the inputs in the order x_, type_, x__2.\n\
+             pub fn predict_f32(x: &[f32]) -> f32 {\n    \
+             let x_ = x[0];\n    \
+             let type_ = x[1];\n    \
+             (if x_ - 1.0_f32 > 0.0 { min(x_, type_) } else { 2.0_f32 }) \
+             * -(if x_ < type_ { 1.0_f32 } else { 0.0_f32 })\n\
+             }\n\
+             \n\
+             fn min<T: PartialOrd>(a: T, b: T) -> T {\n    \
+             if a < b {\n        \
+             a\n    \
+             } else {\n        \
+             b\n    \
+             }\n\
+             }\n"
+        );
+    }
+
+    #[test]
+    fn rust_compiles() {
+        let version = Command::new("rustc").arg("--version").output();
+        if !version.is_ok_and(|output| output.status.success()) {
+            eprintln!("skipping rust_compiles, there is no `rustc`");
+            return;
+        }
+
+        let n_rows = 20;
+        let inputs: Vec<f64> = (0..3 * n_rows)
+            .map(|_| rand::random::<f64>() * 4.0 - 2.0)
+            .collect();
+
+        // Every model in a module of its own, and a
+        // `main` printing their results for every row
+        let mut exprs = vec![sample()];
+        for _ in 0..100 {
+            let mut expr = Expr::new(3);
+            expr.random_tree(8);
+            exprs.push(expr);
+        }
+
+        let values: Vec<String> = inputs.iter().map(|x| format!("{x:?}")).collect();
+        let mut source = format!(
+            "const INPUTS: [f64; {}] = [{}];\n\n",
+            inputs.len(),
+            values.join(", ")
+        );
+        let mut main = String::from(
+            "fn main() {\n    \
+             let inputs_f32: Vec<f32> = INPUTS.iter().map(|x| *x as f32).collect();\n    \
+             for (row, row_f32) in INPUTS.chunks(3).zip(inputs_f32.chunks(3)) {\n",
+        );
+        for (i, expr) in exprs.iter().enumerate() {
+            source += &format!("mod m{i} {{\n{}}}\n\n", expr.to_rust(&NAMES));
+            main += &format!(
+                "        println!(\"{{:?}} {{:?}}\", m{i}::predict(row), m{i}::predict_f32(row_f32));\n"
+            );
+        }
+        source += &main;
+        source += "    }\n}\n";
+
+        let dir = std::env::temp_dir().join(format!("symreg-rust-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let rs_path = dir.join("models.rs");
+        let exe_path = dir.join("models");
+        std::fs::write(&rs_path, &source).unwrap();
+        let output = Command::new("rustc")
+            .args(["--edition", "2021", "-O", "-D", "warnings", "-o"])
+            .arg(&exe_path)
+            .arg(&rs_path)
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+
+        let output = Command::new(&exe_path).output().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let mut lines = stdout.lines();
+        for row in inputs.chunks(3) {
+            for (i, expr) in exprs.iter().enumerate() {
+                let line = lines.next().unwrap();
+                let (result, result_f32) = line.split_once(' ').unwrap();
+                let result: f64 = result.parse().unwrap();
+                let expected = expr.evaluate(row);
+                // The same operations in the same order, so the same bits
+                assert!(
+                    result == expected || (result.is_nan() && expected.is_nan()),
+                    "{result} != {expected} at {row:?} for {expr}"
+                );
+
+                // Single precision has its own rounding,
+                // so only check the sample loosely
+                if i == 0 {
+                    let result_f32: f64 = result_f32.parse().unwrap();
+                    if expected.is_nan() {
+                        assert!(result_f32.is_nan());
+                    } else {
+                        assert!((result_f32 - expected).abs() < 1e-2 * expected.abs().max(1.0));
+                    }
+                }
+            }
+        }
+        assert_eq!(lines.next(), None);
+    }
+
+    #[test]
+    fn sympy() {
+        let expr = sample();
+        let src = expr.to_sympy(&NAMES);
+        assert_eq!(
+            src,
+            "import sympy as sp\n\n\
+             sepal_length, class_, _2nd, = sp.symbols(\"sepal_length class_ _2nd\", seq=True)\n\
+             expr = -sepal_length**2.0/sp.Abs(class_ - 1.5) + sp.log(_2nd)*sp.log(_2nd, 2) \
+             - sp.log(sp.sin(sepal_length), 10)*sp.cos(class_)**(-sp.tan(-0.5*_2nd))\n"
+        );
+
+        let single = Expr::from_infix("sin(x0) * x0", 1, &[]).unwrap();
+        let single_src = single.to_sympy(&[]);
+        assert_eq!(
+            single_src,
+            "import sympy as sp\n\n\
+             x0, = sp.symbols(\"x0\", seq=True)\n\
+             expr = sp.sin(x0)*x0\n"
+        );
+
+        let pair = Expr::from_infix("x0 - x1", 2, &[]).unwrap();
+        assert!(pair
+            .to_sympy(&["petal width", "petal_width"])
+            .contains("petal_width, petal_width_1, = "));
+
+        // Check the numbers too when SymPy is around.
+        let sympy = Command::new("python3")
+            .arg("-c")
+            .arg("import sympy")
+            .output();
+        if !sympy.is_ok_and(|output| output.status.success()) {
+            return;
+        }
+        let inputs = [0.3, 0.7, 1.9];
+        let script = format!(
+            "{src}print(float(expr.subs({{sepal_length: {}, class_: {}, _2nd: {}}})))",
+            inputs[0], inputs[1], inputs[2]
+        );
+        assert_close(python(&script), expr.evaluate(&inputs));
+        let script = format!("{single_src}print(float(expr.subs(x0, {})))", inputs[0]);
+        assert_close(python(&script), single.evaluate(&inputs[..1]));
+    }
+
+    fn python(script: &str) -> f64 {
+        let output = Command::new("python3")
+            .arg("-c")
+            .arg(script)
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        String::from_utf8_lossy(&output.stdout)
+            .trim()
+            .parse()
+            .unwrap()
+    }
+
+    fn assert_close(result: f64, expected: f64) {
+        assert!(
+            (result - expected).abs() < 1e-9 * expected.abs().max(1.0),
+            "{result} != {expected}"
+        );
+    }
+}
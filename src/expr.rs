@@ -1,68 +1,152 @@
+use std::{error::Error, fmt, ops::Range};
+
 use rand::{self, rngs::ThreadRng, Rng};
 
-#[derive(Debug, Clone)]
+use crate::dual::Scalar;
+
+/// Operators taking two operands.
+#[derive(Debug, Clone, PartialEq)]
 pub enum BinaryOp {
+    /// `a + b`
     Add,
+    /// `a - b`
     Sub,
+    /// `a * b`
     Mul,
+    /// `a / b`
     Div,
+    /// `a` to the power `b`
     Pow,
+
+    /// `a < b`, 1 when true and 0 otherwise
+    Lt,
+    /// `a > b`, 1 when true and 0 otherwise
+    Gt,
+
+    /// The lesser operand, `b` if either is NaN
+    Min,
+    /// The greater operand, `b` if either is NaN
+    Max,
 }
 
-#[derive(Debug, Clone)]
+/// Negation and the built-in functions.
+#[derive(Debug, Clone, PartialEq)]
 pub enum UnaryOp {
+    /// `-a`
     Neg,
 
-    // Built-in functioins
+    /// Absolute value
     Abs,
+    /// Natural logarithm
     Loge,
+    /// Base 2 logarithm
     Log2,
+    /// Base 10 logarithm
     Log10,
+    /// Sine
     Sin,
+    /// Cosine
     Cos,
+    /// Tangent
     Tan,
 }
 
+impl BinaryOp {
+    /// Applies the operator to `a` and `b`.
+    pub fn apply<T: Scalar>(&self, a: T, b: T) -> T {
+        match self {
+            BinaryOp::Add => a + b,
+            BinaryOp::Sub => a - b,
+            BinaryOp::Mul => a * b,
+            BinaryOp::Div => a / b,
+            BinaryOp::Pow => a.powf(b),
+            BinaryOp::Lt => a.less(b),
+            BinaryOp::Gt => a.greater(b),
+            BinaryOp::Min => a.select_min(b),
+            BinaryOp::Max => a.select_max(b),
+        }
+    }
+}
+
+impl UnaryOp {
+    /// Applies the operator to `x`.
+    pub fn apply<T: Scalar>(&self, x: T) -> T {
+        match self {
+            UnaryOp::Neg => -x,
+            UnaryOp::Abs => x.abs(),
+            UnaryOp::Loge => x.ln(),
+            UnaryOp::Log2 => x.log2(),
+            UnaryOp::Log10 => x.log10(),
+            UnaryOp::Sin => x.sin(),
+            UnaryOp::Cos => x.cos(),
+            UnaryOp::Tan => x.tan(),
+        }
+    }
+}
+
+/// `op` applied to the nodes `a` and `b`.
 #[derive(Debug, Clone)]
 pub struct BinOp {
+    /// The operator.
     pub op: BinaryOp,
 
-    // pointres (via Vec index)
-    // to child nodes
+    /// Index of the first operand in [`Expr::nodes`].
     pub a: usize,
+    /// Index of the second operand in [`Expr::nodes`].
     pub b: usize,
 }
 
+/// `op` applied to the node `a`.
 #[derive(Debug, Clone)]
 pub struct UnOp {
+    /// The operator.
     pub op: UnaryOp,
-    // pointer (via Vec index)
-    // to child node.
+    /// Index of the operand in [`Expr::nodes`].
     pub a: usize,
 }
 
+/// `then` when `cond` is positive, `otherwise` if not or NaN.
+#[derive(Debug, Clone)]
+pub struct IfPositive {
+    /// Index of the condition node.
+    pub cond: usize,
+    /// Index of the node taken when the condition is positive.
+    pub then: usize,
+    /// Index of the node taken otherwise.
+    pub otherwise: usize,
+}
+
+/// A node of the expression tree.
 #[derive(Debug, Clone)]
 pub enum Node {
+    /// A constant.
     Number(f64),
 
-    //Index to the variables Vec
+    /// The input with this index.
     Variable(usize),
 
+    /// A unary operator.
     UnOp(UnOp),
+    /// A binary operator.
     BinOp(BinOp),
+    /// A conditional.
+    IfPositive(IfPositive),
 }
 
+/// Expression tree over `n_inputs` variables. Nodes that are
+/// no longer reachable from `root` are left in the arena.
 #[derive(Debug, Clone)]
 pub struct Expr {
-    // Using vec as an arena style
-    // allocator and indecies as
-    // easy to work with pointers.
+    /// Arena of the nodes, which refer to each other by index.
     pub nodes: Vec<Node>,
+    /// Index of the root node.
     pub root: usize,
+    /// Number of input variables.
     pub n_inputs: usize,
 }
 
 impl Expr {
+    /// An empty expression, to be filled by [`Expr::random_tree`].
     pub fn new(n_inputs: usize) -> Expr {
         Expr {
             nodes: Vec::new(),
@@ -71,6 +155,7 @@ impl Expr {
         }
     }
 
+    /// The value for one row of inputs.
     pub fn evaluate(&self, inputs: &[f64]) -> f64 {
         self.eval(self.root, inputs)
     }
@@ -81,33 +166,58 @@ impl Expr {
             Node::Variable(ptr) => inputs[*ptr],
             Node::UnOp(op) => {
                 let x = self.eval(op.a, inputs);
-
-                match op.op {
-                    UnaryOp::Neg => -x,
-                    UnaryOp::Abs => x.abs(),
-                    UnaryOp::Loge => x.ln(),
-                    UnaryOp::Log2 => x.log2(),
-                    UnaryOp::Log10 => x.log10(),
-                    UnaryOp::Sin => x.sin(),
-                    UnaryOp::Cos => x.cos(),
-                    UnaryOp::Tan => x.tan(),
-                }
+                op.op.apply(x)
             }
             Node::BinOp(op) => {
                 let a = self.eval(op.a, inputs);
                 let b = self.eval(op.b, inputs);
-
-                match op.op {
-                    BinaryOp::Add => a + b,
-                    BinaryOp::Sub => a - b,
-                    BinaryOp::Mul => a * b,
-                    BinaryOp::Div => a / b,
-                    BinaryOp::Pow => a.powf(b),
+                op.op.apply(a, b)
+            }
+            Node::IfPositive(op) => {
+                if self.eval(op.cond, inputs) > 0.0 {
+                    self.eval(op.then, inputs)
+                } else {
+                    self.eval(op.otherwise, inputs)
                 }
             }
         }
     }
 
+    /// Evaluates over any `Scalar`. Constants are mapped through
+    /// `number`, which also gets their node index, so they can
+    /// be seeded as parameters for automatic differentiation.
+    pub fn evaluate_scalar<T: Scalar>(&self, inputs: &[T], number: &impl Fn(usize, f64) -> T) -> T {
+        self.eval_scalar(self.root, inputs, number)
+    }
+
+    pub(crate) fn eval_scalar<T: Scalar>(
+        &self,
+        node: usize,
+        inputs: &[T],
+        number: &impl Fn(usize, f64) -> T,
+    ) -> T {
+        match &self.nodes[node] {
+            Node::Number(x) => number(node, *x),
+            Node::Variable(ptr) => inputs[*ptr].clone(),
+            Node::UnOp(op) => {
+                let x = self.eval_scalar(op.a, inputs, number);
+                op.op.apply(x)
+            }
+            Node::BinOp(op) => {
+                let a = self.eval_scalar(op.a, inputs, number);
+                let b = self.eval_scalar(op.b, inputs, number);
+                op.op.apply(a, b)
+            }
+            Node::IfPositive(op) => {
+                let cond = self.eval_scalar(op.cond, inputs, number);
+                let then = self.eval_scalar(op.then, inputs, number);
+                let otherwise = self.eval_scalar(op.otherwise, inputs, number);
+                cond.if_positive(then, otherwise)
+            }
+        }
+    }
+
+    /// Grows a random tree at most `max_depth` deep and makes it the root.
     pub fn random_tree(&mut self, max_depth: usize) {
         self.root = self.generate_tree(max_depth);
     }
@@ -121,7 +231,20 @@ impl Expr {
                 Node::Number(x)
             }
         } else {
-            if rand::random::<bool>() {
+            let kind = rand::random::<f64>();
+            if kind < 0.1 {
+                // generate conditional
+
+                let cond = self.generate_tree(max_depth - 1);
+                let then = self.generate_tree(max_depth - 1);
+                let otherwise = self.generate_tree(max_depth - 1);
+
+                Node::IfPositive(IfPositive {
+                    cond,
+                    then,
+                    otherwise,
+                })
+            } else if kind < 0.55 {
                 // generate binop
 
                 let a = self.generate_tree(max_depth - 1);
@@ -148,6 +271,7 @@ impl Expr {
         self.nodes.len() - 1
     }
 
+    /// Reverse Polish notation, which [`Expr::from_rpn`] reads back.
     pub fn rpn(&self) -> String {
         self.generate_rpn(self.root)
     }
@@ -182,13 +306,25 @@ impl Expr {
                     BinaryOp::Mul => "*".to_string(),
                     BinaryOp::Div => "/".to_string(),
                     BinaryOp::Pow => "^".to_string(),
+                    BinaryOp::Lt => "<".to_string(),
+                    BinaryOp::Gt => ">".to_string(),
+                    BinaryOp::Min => "min".to_string(),
+                    BinaryOp::Max => "max".to_string(),
                 };
 
                 format!("{lhs} {rhs} {op}")
             }
+            Node::IfPositive(op) => {
+                let cond = self.generate_rpn(op.cond);
+                let then = self.generate_rpn(op.then);
+                let otherwise = self.generate_rpn(op.otherwise);
+                format!("{cond} {then} {otherwise} if_positive")
+            }
         }
     }
 
+    /// A copy with each node replaced by a random
+    /// one with probability `rate`.
     pub fn mutate(&self, rate: f64) -> Expr {
         let mut rng = rand::thread_rng();
         let mut expr = self.clone();
@@ -196,7 +332,7 @@ impl Expr {
         for i in 0..expr.nodes.len() {
             let node = &expr.nodes[i];
             if sometimes(&mut rng, rate) {
-                let node_type = rng.gen_range(0..=3);
+                let node_type = rng.gen_range(0..=4);
                 match node_type {
                     0 => expr.nodes[i] = Node::Number(rng.gen::<f64>() * 10.0 - 5.0),
                     1 => expr.nodes[i] = Node::Variable(rng.gen_range(0..expr.n_inputs)),
@@ -224,6 +360,21 @@ impl Expr {
 
                         expr.nodes[i] = Node::UnOp(UnOp { op, a });
                     }
+                    4 => {
+                        let mut new_nodes = Vec::new();
+                        let offset = expr.nodes.len();
+                        let mut subtree =
+                            || generate_subtree(&mut new_nodes, 3, expr.n_inputs, offset, &mut rng);
+                        let (cond, then, otherwise) = (subtree(), subtree(), subtree());
+
+                        expr.nodes.append(&mut new_nodes);
+
+                        expr.nodes[i] = Node::IfPositive(IfPositive {
+                            cond,
+                            then,
+                            otherwise,
+                        });
+                    }
                     _ => unreachable!(),
                 }
             } else {
@@ -234,8 +385,226 @@ impl Expr {
             }
         }
 
+        expr.compact();
+        expr
+    }
+
+    /// Subtree crossover. Replaces a random node of a copy
+    /// of `self` with a random subtree of `donor`.
+    pub fn crossover(&self, donor: &Expr) -> Expr {
+        let mut rng = rand::thread_rng();
+        let mut expr = self.clone();
+
+        // Only pick nodes that are reachable from the root,
+        // the arenas may contain orphaned nodes.
+        let targets = self.subtree(self.root);
+        let sources = donor.subtree(donor.root);
+        let target = targets[rng.gen_range(0..targets.len())];
+        let source = sources[rng.gen_range(0..sources.len())];
+
+        expr.nodes[target] = expr.copy_node(donor, source);
+
+        expr.compact();
         expr
     }
+
+    /// Rebuilds the arena with only the nodes reachable from the
+    /// root, children before their parents. Mutation leaves the
+    /// replaced subtrees behind as garbage in the arena.
+    pub fn compact(&mut self) {
+        if self.nodes.is_empty() {
+            return;
+        }
+
+        let mut nodes = Vec::new();
+        let mut remap = vec![usize::MAX; self.nodes.len()];
+        self.root = self.compact_node(self.root, &mut nodes, &mut remap);
+        self.nodes = nodes;
+    }
+
+    fn compact_node(&self, node: usize, nodes: &mut Vec<Node>, remap: &mut [usize]) -> usize {
+        // Shared nodes stay shared
+        if remap[node] != usize::MAX {
+            return remap[node];
+        }
+
+        let new_node = match &self.nodes[node] {
+            Node::Number(x) => Node::Number(*x),
+            Node::Variable(ptr) => Node::Variable(*ptr),
+            Node::UnOp(op) => Node::UnOp(UnOp {
+                op: op.op.clone(),
+                a: self.compact_node(op.a, nodes, remap),
+            }),
+            Node::BinOp(op) => {
+                let a = self.compact_node(op.a, nodes, remap);
+                let b = self.compact_node(op.b, nodes, remap);
+                Node::BinOp(BinOp {
+                    op: op.op.clone(),
+                    a,
+                    b,
+                })
+            }
+            Node::IfPositive(op) => {
+                let cond = self.compact_node(op.cond, nodes, remap);
+                let then = self.compact_node(op.then, nodes, remap);
+                let otherwise = self.compact_node(op.otherwise, nodes, remap);
+                Node::IfPositive(IfPositive {
+                    cond,
+                    then,
+                    otherwise,
+                })
+            }
+        };
+
+        nodes.push(new_node);
+        remap[node] = nodes.len() - 1;
+        remap[node]
+    }
+
+    /// Number of distinct nodes reachable from the root.
+    pub fn reachable_size(&self) -> usize {
+        if self.nodes.is_empty() {
+            return 0;
+        }
+
+        let mut seen = vec![false; self.nodes.len()];
+        let mut stack = vec![self.root];
+        let mut size = 0;
+
+        while let Some(i) = stack.pop() {
+            if seen[i] {
+                continue;
+            }
+            seen[i] = true;
+            size += 1;
+
+            match &self.nodes[i] {
+                Node::Number(_) | Node::Variable(_) => (),
+                Node::UnOp(op) => stack.push(op.a),
+                Node::BinOp(op) => {
+                    stack.push(op.a);
+                    stack.push(op.b);
+                }
+                Node::IfPositive(op) => stack.extend([op.cond, op.then, op.otherwise]),
+            }
+        }
+
+        size
+    }
+
+    /// Is there a conditional reachable from the root? Only
+    /// those compile to jumps, unlike comparisons, `min` and `max`.
+    pub fn has_conditional(&self) -> bool {
+        self.subtree(self.root)
+            .into_iter()
+            .any(|i| matches!(self.nodes[i], Node::IfPositive(_)))
+    }
+
+    /// Length of the longest path from the root to a leaf,
+    /// a single leaf has depth 0 like in `random_tree`.
+    pub fn depth(&self) -> usize {
+        self.node_depth(self.root)
+    }
+
+    fn node_depth(&self, node: usize) -> usize {
+        match &self.nodes[node] {
+            Node::Number(_) | Node::Variable(_) => 0,
+            Node::UnOp(op) => 1 + self.node_depth(op.a),
+            Node::BinOp(op) => 1 + self.node_depth(op.a).max(self.node_depth(op.b)),
+            Node::IfPositive(op) => {
+                let depth = self.node_depth(op.cond).max(self.node_depth(op.then));
+                1 + depth.max(self.node_depth(op.otherwise))
+            }
+        }
+    }
+
+    // Indecies of all the nodes in the subtree rooted at `node`.
+    pub(crate) fn subtree(&self, node: usize) -> Vec<usize> {
+        let mut res = Vec::new();
+        let mut stack = vec![node];
+
+        while let Some(i) = stack.pop() {
+            res.push(i);
+            match &self.nodes[i] {
+                Node::Number(_) | Node::Variable(_) => (),
+                Node::UnOp(op) => stack.push(op.a),
+                Node::BinOp(op) => {
+                    stack.push(op.b);
+                    stack.push(op.a);
+                }
+                Node::IfPositive(op) => stack.extend([op.otherwise, op.then, op.cond]),
+            }
+        }
+
+        res
+    }
+
+    // Copies the children of `node` from the `donor` arena into
+    // this one and returns `node` with its pointers remapped.
+    fn copy_node(&mut self, donor: &Expr, node: usize) -> Node {
+        match &donor.nodes[node] {
+            Node::Number(x) => Node::Number(*x),
+            Node::Variable(ptr) => Node::Variable(*ptr),
+            Node::UnOp(op) => {
+                let a = self.copy_subtree(donor, op.a);
+                Node::UnOp(UnOp {
+                    op: op.op.clone(),
+                    a,
+                })
+            }
+            Node::BinOp(op) => {
+                let a = self.copy_subtree(donor, op.a);
+                let b = self.copy_subtree(donor, op.b);
+                Node::BinOp(BinOp {
+                    op: op.op.clone(),
+                    a,
+                    b,
+                })
+            }
+            Node::IfPositive(op) => {
+                let cond = self.copy_subtree(donor, op.cond);
+                let then = self.copy_subtree(donor, op.then);
+                let otherwise = self.copy_subtree(donor, op.otherwise);
+                Node::IfPositive(IfPositive {
+                    cond,
+                    then,
+                    otherwise,
+                })
+            }
+        }
+    }
+
+    fn copy_subtree(&mut self, donor: &Expr, node: usize) -> usize {
+        let node = self.copy_node(donor, node);
+        self.nodes.push(node);
+        self.nodes.len() - 1
+    }
+
+    pub(crate) fn subtree_eq(&self, node: usize, other: &Expr, other_node: usize) -> bool {
+        match (&self.nodes[node], &other.nodes[other_node]) {
+            (Node::Number(x), Node::Number(y)) => x == y,
+            (Node::Variable(x), Node::Variable(y)) => x == y,
+            (Node::UnOp(x), Node::UnOp(y)) => x.op == y.op && self.subtree_eq(x.a, other, y.a),
+            (Node::BinOp(x), Node::BinOp(y)) => {
+                x.op == y.op && self.subtree_eq(x.a, other, y.a) && self.subtree_eq(x.b, other, y.b)
+            }
+            (Node::IfPositive(x), Node::IfPositive(y)) => {
+                self.subtree_eq(x.cond, other, y.cond)
+                    && self.subtree_eq(x.then, other, y.then)
+                    && self.subtree_eq(x.otherwise, other, y.otherwise)
+            }
+            _ => false,
+        }
+    }
+}
+
+// Structural equality, two expressions are equal when the trees
+// reachable from their roots are equal, regardless of where
+// the nodes live in the arenas.
+impl PartialEq for Expr {
+    fn eq(&self, other: &Expr) -> bool {
+        self.n_inputs == other.n_inputs && self.subtree_eq(self.root, other, other.root)
+    }
 }
 
 fn generate_subtree(
@@ -253,7 +622,20 @@ fn generate_subtree(
             Node::Number(x)
         }
     } else {
-        if rand::random::<bool>() {
+        let kind = rng.gen::<f64>();
+        if kind < 0.1 {
+            // generate conditional
+
+            let cond = generate_subtree(nodes, depth - 1, n_inputs, index_offset, rng);
+            let then = generate_subtree(nodes, depth - 1, n_inputs, index_offset, rng);
+            let otherwise = generate_subtree(nodes, depth - 1, n_inputs, index_offset, rng);
+
+            Node::IfPositive(IfPositive {
+                cond,
+                then,
+                otherwise,
+            })
+        } else if kind < 0.55 {
             // generate binop
 
             let a = generate_subtree(nodes, depth - 1, n_inputs, index_offset, rng);
@@ -285,7 +667,7 @@ fn sometimes(rng: &mut ThreadRng, rate: f64) -> bool {
 }
 
 fn jiggle(x: f64, rate: f64) -> f64 {
-    x + rand::random::<f64>() * rate * 10.0
+    x + (rand::random::<f64>() * 2.0 - 1.0) * rate * 10.0
 }
 
 fn random_unop() -> UnaryOp {
@@ -305,12 +687,724 @@ fn random_unop() -> UnaryOp {
 
 fn random_binop() -> BinaryOp {
     let mut rng = rand::thread_rng();
-    match rng.gen_range(0..=4) {
+    match rng.gen_range(0..=8) {
         0 => BinaryOp::Add,
         1 => BinaryOp::Sub,
         2 => BinaryOp::Mul,
         3 => BinaryOp::Div,
         4 => BinaryOp::Pow,
+        5 => BinaryOp::Lt,
+        6 => BinaryOp::Gt,
+        7 => BinaryOp::Min,
+        8 => BinaryOp::Max,
         _ => unreachable!(),
     }
 }
+
+/// What went wrong while parsing.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseErrorKind {
+    /// A character that starts no token.
+    UnexpectedCharacter(char),
+    /// A number that doesn't parse.
+    InvalidNumber(String),
+    /// A name that is neither a function nor a column.
+    UnknownIdentifier(String),
+    /// A variable index beyond the inputs.
+    UnknownVariable(usize),
+    /// A token where it doesn't belong.
+    UnexpectedToken(String),
+    /// The source ends in the middle of an expression.
+    UnexpectedEnd,
+    /// An opening parenthesis that is never closed.
+    UnclosedParenthesis,
+
+    // RPN only
+    /// An operator with too few operands before it.
+    MissingOperand,
+    /// Operands left over besides the result.
+    LeftoverOperands(usize),
+}
+
+/// An error from [`Expr::from_infix`] or [`Expr::from_rpn`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    /// What went wrong.
+    pub kind: ParseErrorKind,
+
+    /// Byte offsets into the source string.
+    pub span: Range<usize>,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ParseErrorKind::UnexpectedCharacter(c) => write!(f, "unexpected character '{c}'")?,
+            ParseErrorKind::InvalidNumber(s) => write!(f, "invalid number '{s}'")?,
+            ParseErrorKind::UnknownIdentifier(s) => write!(f, "unknown identifier '{s}'")?,
+            ParseErrorKind::UnknownVariable(i) => write!(f, "variable ${i} is out of range")?,
+            ParseErrorKind::UnexpectedToken(s) => write!(f, "unexpected '{s}'")?,
+            ParseErrorKind::UnexpectedEnd => write!(f, "unexpected end of input")?,
+            ParseErrorKind::UnclosedParenthesis => write!(f, "unclosed parenthesis")?,
+            ParseErrorKind::MissingOperand => write!(f, "missing operand")?,
+            ParseErrorKind::LeftoverOperands(n) => write!(f, "{n} operands left over")?,
+        }
+        write!(f, " at {}..{}", self.span.start, self.span.end)
+    }
+}
+
+impl Error for ParseError {}
+
+impl Expr {
+    /// Parses the format written by `Expr::rpn`, for example
+    /// `$0 1.5 * sin`. Variables are written as `$<index>`.
+    pub fn from_rpn(src: &str, n_inputs: usize) -> Result<Expr, ParseError> {
+        let mut expr = Expr::new(n_inputs);
+        let mut stack = Vec::new();
+
+        for (span, token) in split_whitespace_spans(src) {
+            let pop = |stack: &mut Vec<usize>| {
+                stack.pop().ok_or(ParseError {
+                    kind: ParseErrorKind::MissingOperand,
+                    span: span.clone(),
+                })
+            };
+
+            let node = if token == "if_positive" {
+                let otherwise = pop(&mut stack)?;
+                let then = pop(&mut stack)?;
+                let cond = pop(&mut stack)?;
+                Node::IfPositive(IfPositive {
+                    cond,
+                    then,
+                    otherwise,
+                })
+            } else if let Some(op) = binop_from_symbol(token).or_else(|| binop_from_name(token)) {
+                let b = pop(&mut stack)?;
+                let a = pop(&mut stack)?;
+                Node::BinOp(BinOp { op, a, b })
+            } else if let Some(op) = unop_from_name(token) {
+                let a = pop(&mut stack)?;
+                Node::UnOp(UnOp { op, a })
+            } else if let Some(index) = token.strip_prefix('$') {
+                let ptr = index.parse::<usize>().map_err(|_| ParseError {
+                    kind: ParseErrorKind::UnknownIdentifier(token.to_string()),
+                    span: span.clone(),
+                })?;
+                if ptr >= n_inputs {
+                    return Err(ParseError {
+                        kind: ParseErrorKind::UnknownVariable(ptr),
+                        span,
+                    });
+                }
+                Node::Variable(ptr)
+            } else {
+                let x = token.parse::<f64>().map_err(|_| ParseError {
+                    kind: ParseErrorKind::InvalidNumber(token.to_string()),
+                    span: span.clone(),
+                })?;
+                Node::Number(x)
+            };
+
+            expr.nodes.push(node);
+            stack.push(expr.nodes.len() - 1);
+        }
+
+        match stack.len() {
+            0 => Err(ParseError {
+                kind: ParseErrorKind::UnexpectedEnd,
+                span: src.len()..src.len(),
+            }),
+            1 => {
+                expr.root = stack[0];
+                Ok(expr)
+            }
+            n => Err(ParseError {
+                kind: ParseErrorKind::LeftoverOperands(n - 1),
+                span: 0..src.len(),
+            }),
+        }
+    }
+
+    /// Parses conventional infix notation, like the `Display` output.
+    /// Variables are looked up from `names` first, and can otherwise
+    /// be written as `x<index>` or `$<index>`.
+    ///
+    /// `^` binds tightest and associates to the right, then unary
+    /// minus, then `*` and `/`, then `+` and `-`, and then `<` and
+    /// `>`. `min(a, b)`, `max(a, b)` and `if_positive(c, a, b)` are
+    /// written as calls.
+    pub fn from_infix(src: &str, n_inputs: usize, names: &[&str]) -> Result<Expr, ParseError> {
+        let tokens = tokenize(src)?;
+        let mut parser = Parser {
+            tokens,
+            pos: 0,
+            src_len: src.len(),
+            names,
+            expr: Expr::new(n_inputs),
+        };
+
+        let root = parser.parse_comparison()?;
+        if let Some((token, span)) = parser.tokens.get(parser.pos) {
+            return Err(ParseError {
+                kind: ParseErrorKind::UnexpectedToken(token.to_string()),
+                span: span.clone(),
+            });
+        }
+
+        parser.expr.root = root;
+        Ok(parser.expr)
+    }
+}
+
+fn split_whitespace_spans(src: &str) -> Vec<(Range<usize>, &str)> {
+    let mut res = Vec::new();
+    let mut start = None;
+
+    for (i, c) in src.char_indices() {
+        match (c.is_whitespace(), start) {
+            (true, Some(s)) => {
+                res.push((s..i, &src[s..i]));
+                start = None;
+            }
+            (false, None) => start = Some(i),
+            _ => (),
+        }
+    }
+    if let Some(s) = start {
+        res.push((s..src.len(), &src[s..]));
+    }
+
+    res
+}
+
+fn binop_from_symbol(symbol: &str) -> Option<BinaryOp> {
+    match symbol {
+        "+" => Some(BinaryOp::Add),
+        "-" => Some(BinaryOp::Sub),
+        "*" => Some(BinaryOp::Mul),
+        "/" => Some(BinaryOp::Div),
+        "^" => Some(BinaryOp::Pow),
+        "<" => Some(BinaryOp::Lt),
+        ">" => Some(BinaryOp::Gt),
+        _ => None,
+    }
+}
+
+fn binop_from_name(name: &str) -> Option<BinaryOp> {
+    match name {
+        "min" => Some(BinaryOp::Min),
+        "max" => Some(BinaryOp::Max),
+        _ => None,
+    }
+}
+
+fn unop_from_name(name: &str) -> Option<UnaryOp> {
+    match name {
+        "neg" => Some(UnaryOp::Neg),
+        "abs" => Some(UnaryOp::Abs),
+        "ln" => Some(UnaryOp::Loge),
+        "log2" => Some(UnaryOp::Log2),
+        "log10" => Some(UnaryOp::Log10),
+        "sin" => Some(UnaryOp::Sin),
+        "cos" => Some(UnaryOp::Cos),
+        "tan" => Some(UnaryOp::Tan),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Ident(String),
+    Symbol(char),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Number(x) => write!(f, "{x}"),
+            Token::Ident(s) => write!(f, "{s}"),
+            Token::Symbol(c) => write!(f, "{c}"),
+        }
+    }
+}
+
+fn tokenize(src: &str) -> Result<Vec<(Token, Range<usize>)>, ParseError> {
+    let bytes = src.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let c = bytes[i];
+        let start = i;
+
+        if c.is_ascii_whitespace() {
+            i += 1;
+            continue;
+        }
+
+        let token = match c {
+            b'+' | b'-' | b'*' | b'/' | b'^' | b'<' | b'>' | b'(' | b')' | b',' => {
+                i += 1;
+                Token::Symbol(c as char)
+            }
+            b'0'..=b'9' | b'.' => {
+                while i < bytes.len() && (bytes[i].is_ascii_digit() || bytes[i] == b'.') {
+                    i += 1;
+                }
+                // Exponent, like `1e-5`
+                if i < bytes.len() && (bytes[i] == b'e' || bytes[i] == b'E') {
+                    let mut j = i + 1;
+                    if j < bytes.len() && (bytes[j] == b'+' || bytes[j] == b'-') {
+                        j += 1;
+                    }
+                    if j < bytes.len() && bytes[j].is_ascii_digit() {
+                        i = j;
+                        while i < bytes.len() && bytes[i].is_ascii_digit() {
+                            i += 1;
+                        }
+                    }
+                }
+
+                let s = &src[start..i];
+                let x = s.parse::<f64>().map_err(|_| ParseError {
+                    kind: ParseErrorKind::InvalidNumber(s.to_string()),
+                    span: start..i,
+                })?;
+                Token::Number(x)
+            }
+            b'$' | b'_' | b'a'..=b'z' | b'A'..=b'Z' => {
+                i += 1;
+                while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                    i += 1;
+                }
+                match &src[start..i] {
+                    "inf" => Token::Number(f64::INFINITY),
+                    "NaN" => Token::Number(f64::NAN),
+                    s => Token::Ident(s.to_string()),
+                }
+            }
+            _ => {
+                let c = src[start..].chars().next().unwrap();
+                return Err(ParseError {
+                    kind: ParseErrorKind::UnexpectedCharacter(c),
+                    span: start..start + c.len_utf8(),
+                });
+            }
+        };
+
+        tokens.push((token, start..i));
+    }
+
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: Vec<(Token, Range<usize>)>,
+    pos: usize,
+    src_len: usize,
+    names: &'a [&'a str],
+    expr: Expr,
+}
+
+impl Parser<'_> {
+    fn peek(&self, offset: usize) -> Option<&Token> {
+        self.tokens.get(self.pos + offset).map(|(token, _)| token)
+    }
+
+    fn next(&mut self) -> Result<(Token, Range<usize>), ParseError> {
+        let token = self.tokens.get(self.pos).cloned().ok_or(ParseError {
+            kind: ParseErrorKind::UnexpectedEnd,
+            span: self.src_len..self.src_len,
+        })?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn push(&mut self, node: Node) -> usize {
+        self.expr.nodes.push(node);
+        self.expr.nodes.len() - 1
+    }
+
+    fn parse_comparison(&mut self) -> Result<usize, ParseError> {
+        let mut a = self.parse_sum()?;
+
+        while let Some(Token::Symbol(c @ ('<' | '>'))) = self.peek(0) {
+            let op = if *c == '<' {
+                BinaryOp::Lt
+            } else {
+                BinaryOp::Gt
+            };
+            self.pos += 1;
+            let b = self.parse_sum()?;
+            a = self.push(Node::BinOp(BinOp { op, a, b }));
+        }
+
+        Ok(a)
+    }
+
+    fn parse_sum(&mut self) -> Result<usize, ParseError> {
+        let mut a = self.parse_product()?;
+
+        while let Some(Token::Symbol(c @ ('+' | '-'))) = self.peek(0) {
+            let op = if *c == '+' {
+                BinaryOp::Add
+            } else {
+                BinaryOp::Sub
+            };
+            self.pos += 1;
+            let b = self.parse_product()?;
+            a = self.push(Node::BinOp(BinOp { op, a, b }));
+        }
+
+        Ok(a)
+    }
+
+    fn parse_product(&mut self) -> Result<usize, ParseError> {
+        let mut a = self.parse_unary()?;
+
+        while let Some(Token::Symbol(c @ ('*' | '/'))) = self.peek(0) {
+            let op = if *c == '*' {
+                BinaryOp::Mul
+            } else {
+                BinaryOp::Div
+            };
+            self.pos += 1;
+            let b = self.parse_unary()?;
+            a = self.push(Node::BinOp(BinOp { op, a, b }));
+        }
+
+        Ok(a)
+    }
+
+    fn parse_unary(&mut self) -> Result<usize, ParseError> {
+        if let Some(Token::Symbol('-')) = self.peek(0) {
+            // A minus sign right before a number is part of the number,
+            // unless the number is the base of a power: `-2^x = -(2^x)`.
+            if let Some(Token::Number(x)) = self.peek(1) {
+                if self.peek(2) != Some(&Token::Symbol('^')) {
+                    let x = -*x;
+                    self.pos += 2;
+                    return Ok(self.push(Node::Number(x)));
+                }
+            }
+
+            self.pos += 1;
+            let a = self.parse_unary()?;
+            return Ok(self.push(Node::UnOp(UnOp {
+                op: UnaryOp::Neg,
+                a,
+            })));
+        }
+
+        self.parse_power()
+    }
+
+    fn parse_power(&mut self) -> Result<usize, ParseError> {
+        let a = self.parse_atom()?;
+
+        if let Some(Token::Symbol('^')) = self.peek(0) {
+            self.pos += 1;
+            // Right associative, and allows `x^-y`
+            let b = self.parse_unary()?;
+            return Ok(self.push(Node::BinOp(BinOp {
+                op: BinaryOp::Pow,
+                a,
+                b,
+            })));
+        }
+
+        Ok(a)
+    }
+
+    fn parse_atom(&mut self) -> Result<usize, ParseError> {
+        let (token, span) = self.next()?;
+
+        match token {
+            Token::Number(x) => Ok(self.push(Node::Number(x))),
+            Token::Symbol('(') => {
+                let a = self.parse_comparison()?;
+                self.expect_closing(span)?;
+                Ok(a)
+            }
+            Token::Ident(name) => {
+                let is_call = self.peek(0) == Some(&Token::Symbol('('));
+                match (unop_from_name(&name), binop_from_name(&name)) {
+                    (Some(op), _) if is_call && op != UnaryOp::Neg => {
+                        let [a] = self.parse_arguments()?;
+                        Ok(self.push(Node::UnOp(UnOp { op, a })))
+                    }
+                    (_, Some(op)) if is_call => {
+                        let [a, b] = self.parse_arguments()?;
+                        Ok(self.push(Node::BinOp(BinOp { op, a, b })))
+                    }
+                    _ if is_call && name == "if_positive" => {
+                        let [cond, then, otherwise] = self.parse_arguments()?;
+                        Ok(self.push(Node::IfPositive(IfPositive {
+                            cond,
+                            then,
+                            otherwise,
+                        })))
+                    }
+                    _ => {
+                        let ptr = self.variable(&name, span)?;
+                        Ok(self.push(Node::Variable(ptr)))
+                    }
+                }
+            }
+            token => Err(ParseError {
+                kind: ParseErrorKind::UnexpectedToken(token.to_string()),
+                span,
+            }),
+        }
+    }
+
+    // `(a, b, ...)` with exactly `N` arguments.
+    fn parse_arguments<const N: usize>(&mut self) -> Result<[usize; N], ParseError> {
+        let (_, open) = self.next()?;
+        let mut args = [0; N];
+
+        for (i, arg) in args.iter_mut().enumerate() {
+            if i > 0 {
+                match self.next()? {
+                    (Token::Symbol(','), _) => (),
+                    (token, span) => {
+                        return Err(ParseError {
+                            kind: ParseErrorKind::UnexpectedToken(token.to_string()),
+                            span,
+                        })
+                    }
+                }
+            }
+            *arg = self.parse_comparison()?;
+        }
+
+        self.expect_closing(open)?;
+        Ok(args)
+    }
+
+    fn expect_closing(&mut self, open: Range<usize>) -> Result<(), ParseError> {
+        match self.tokens.get(self.pos) {
+            Some((Token::Symbol(')'), _)) => {
+                self.pos += 1;
+                Ok(())
+            }
+            Some((token, span)) => Err(ParseError {
+                kind: ParseErrorKind::UnexpectedToken(token.to_string()),
+                span: span.clone(),
+            }),
+            None => Err(ParseError {
+                kind: ParseErrorKind::UnclosedParenthesis,
+                span: open,
+            }),
+        }
+    }
+
+    fn variable(&self, name: &str, span: Range<usize>) -> Result<usize, ParseError> {
+        let ptr = if let Some(ptr) = self.names.iter().position(|n| *n == name) {
+            ptr
+        } else {
+            let index = name
+                .strip_prefix('$')
+                .or_else(|| name.strip_prefix('x'))
+                .and_then(|index| index.parse::<usize>().ok());
+
+            match index {
+                Some(ptr) => ptr,
+                None => {
+                    return Err(ParseError {
+                        kind: ParseErrorKind::UnknownIdentifier(name.to_string()),
+                        span,
+                    })
+                }
+            }
+        };
+
+        if ptr >= self.expr.n_inputs {
+            return Err(ParseError {
+                kind: ParseErrorKind::UnknownVariable(ptr),
+                span,
+            });
+        }
+
+        Ok(ptr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crossover() {
+        for _ in 0..1_000 {
+            let mut donor = Expr::new(3);
+            donor.random_tree(6);
+            let subtrees: Vec<String> = donor
+                .subtree(donor.root)
+                .into_iter()
+                .map(|i| donor.generate_rpn(i))
+                .collect();
+
+            // With a single node recipient the whole
+            // result must be one of the donor's subtrees.
+            let mut recipient = Expr::new(3);
+            recipient.nodes.push(Node::Number(1.0));
+            let child = recipient.crossover(&donor);
+            assert!(subtrees.contains(&child.rpn()));
+
+            let mut recipient = Expr::new(3);
+            recipient.random_tree(6);
+            let child = recipient.crossover(&donor);
+            for i in child.subtree(child.root) {
+                assert!(i < child.nodes.len());
+            }
+        }
+    }
+
+    #[test]
+    fn compaction() {
+        let n_vars = 3;
+        let vars: Vec<f64> = (0..n_vars).map(|_| rand::random::<f64>()).collect();
+        let mut expr = Expr::new(n_vars);
+        expr.random_tree(6);
+
+        for _ in 0..100 {
+            let mut garbage = expr.clone();
+            garbage.nodes.push(Node::Number(1.0));
+            let mut compacted = garbage.clone();
+            compacted.compact();
+
+            assert_eq!(compacted.nodes.len(), garbage.reachable_size());
+            assert_eq!(compacted.depth(), garbage.depth());
+            assert_eq!(compacted, garbage);
+            let (a, b) = (garbage.evaluate(&vars), compacted.evaluate(&vars));
+            assert!(a == b || (a.is_nan() && b.is_nan()));
+
+            expr = expr.mutate(0.1);
+            assert_eq!(expr.nodes.len(), expr.reachable_size());
+        }
+
+        let mut leaf = Expr::new(0);
+        leaf.random_tree(0);
+        assert_eq!(leaf.depth(), 0);
+        assert_eq!(leaf.reachable_size(), 1);
+    }
+
+    #[test]
+    fn structural_eq() {
+        let mut a = Expr::new(1);
+        a.nodes.push(Node::Variable(0));
+        a.nodes.push(Node::Number(2.0));
+        a.nodes.push(Node::BinOp(BinOp {
+            op: BinaryOp::Mul,
+            a: 0,
+            b: 1,
+        }));
+        a.root = 2;
+
+        // Same tree, different arena layout
+        // and an orphaned node.
+        let mut b = Expr::new(1);
+        b.nodes.push(Node::Number(7.0));
+        b.nodes.push(Node::BinOp(BinOp {
+            op: BinaryOp::Mul,
+            a: 2,
+            b: 3,
+        }));
+        b.nodes.push(Node::Variable(0));
+        b.nodes.push(Node::Number(2.0));
+        b.root = 1;
+        assert_eq!(a, b);
+
+        b.nodes[3] = Node::Number(3.0);
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn parse_infix() {
+        let src = "-2^x1 * sin(sepal_length) - 3.5e-1 / $2";
+        let expr = Expr::from_infix(src, 3, &["sepal_length"]).unwrap();
+        assert_eq!(expr.rpn(), "2.00 $1 ^ neg $0 sin * 0.35 $2 / -");
+        assert_eq!(expr.to_string(), "-2^x1 * sin(x0) - 0.35 / x2");
+
+        let err = Expr::from_infix("1 + (x0 * 2", 1, &[]).unwrap_err();
+        assert_eq!(err.kind, ParseErrorKind::UnclosedParenthesis);
+        assert_eq!(err.span, 4..5);
+
+        let err = Expr::from_infix("1 + foo", 1, &[]).unwrap_err();
+        assert_eq!(
+            err.kind,
+            ParseErrorKind::UnknownIdentifier("foo".to_string())
+        );
+        assert_eq!(err.span, 4..7);
+
+        let err = Expr::from_infix("x3", 2, &[]).unwrap_err();
+        assert_eq!(err.kind, ParseErrorKind::UnknownVariable(3));
+
+        let err = Expr::from_infix("1 2", 1, &[]).unwrap_err();
+        assert_eq!(err.span, 2..3);
+
+        let err = Expr::from_infix("2 * #", 1, &[]).unwrap_err();
+        assert_eq!(err.kind, ParseErrorKind::UnexpectedCharacter('#'));
+
+        let src = "if_positive(x0 - 1, min(x0, 2 * x1), x1) + (x0 > 1 < x1)";
+        let expr = Expr::from_infix(src, 2, &[]).unwrap();
+        assert!(expr.has_conditional());
+        let branch_free = Expr::from_infix("min(x0, 1) < x1", 2, &[]).unwrap();
+        assert!(!branch_free.has_conditional());
+        assert_eq!(
+            expr.rpn(),
+            "$0 1.00 - $0 2.00 $1 * min $1 if_positive $0 1.00 > $1 < +"
+        );
+        assert_eq!(expr.to_string(), src);
+        assert_eq!(expr.evaluate(&[3.0, 1.0]), 2.0);
+        assert_eq!(expr.evaluate(&[0.5, 7.0]), 8.0);
+
+        let err = Expr::from_infix("max(1)", 1, &[]).unwrap_err();
+        assert_eq!(err.kind, ParseErrorKind::UnexpectedToken(")".to_string()));
+        assert_eq!(err.span, 5..6);
+
+        let err = Expr::from_infix("min(1, 2, 3)", 1, &[]).unwrap_err();
+        assert_eq!(err.span, 8..9);
+    }
+
+    #[test]
+    fn parse_rpn() {
+        let expr = Expr::from_rpn("$0 1.5 * sin -2 neg +", 1).unwrap();
+        assert_eq!(expr.evaluate(&[2.0]), 3.0_f64.sin() + 2.0);
+
+        let err = Expr::from_rpn("$0 +", 1).unwrap_err();
+        assert_eq!(err.kind, ParseErrorKind::MissingOperand);
+        assert_eq!(err.span, 3..4);
+
+        let err = Expr::from_rpn("1 2", 1).unwrap_err();
+        assert_eq!(err.kind, ParseErrorKind::LeftoverOperands(1));
+
+        let err = Expr::from_rpn("$1", 1).unwrap_err();
+        assert_eq!(err.kind, ParseErrorKind::UnknownVariable(1));
+
+        let expr = Expr::from_rpn("$0 1 $0 neg if_positive $0 0 max $0 < +", 1).unwrap();
+        assert_eq!(expr.evaluate(&[2.0]), 1.0);
+        assert_eq!(expr.evaluate(&[-2.0]), 2.0);
+    }
+
+    #[test]
+    fn round_trip() {
+        let names = ["a", "b", "c"];
+        for _ in 0..1_000 {
+            let mut expr = Expr::new(3);
+            expr.random_tree(6);
+
+            let infix = Expr::from_infix(&expr.to_string(), 3, &[]).unwrap();
+            assert_eq!(infix, expr, "{expr}");
+            let named = Expr::from_infix(&expr.infix(&names).to_string(), 3, &names).unwrap();
+            assert_eq!(named, expr);
+
+            // The RPN format rounds the constants
+            let rpn = Expr::from_rpn(&expr.rpn(), 3).unwrap();
+            assert_eq!(rpn.rpn(), expr.rpn());
+        }
+    }
+}
@@ -0,0 +1,238 @@
+use crate::{
+    dual::{Dual, Scalar},
+    expr::{Expr, Node},
+    vec2d::Vec2d,
+};
+
+/// Tunes every constant reachable from the root of `expr` to
+/// minimize the squared error on `x`, `y` with Levenberg-Marquardt.
+/// The Jacobian comes from forward-mode automatic differentiation,
+/// with each constant seeded as its own dual number parameter.
+pub fn fit_constants(expr: &Expr, x: &Vec2d<f64>, y: &[f64], iterations: usize) -> Expr {
+    let params: Vec<usize> = expr
+        .subtree(expr.root)
+        .into_iter()
+        .filter(|i| matches!(expr.nodes[*i], Node::Number(_)))
+        .collect();
+    if params.is_empty() {
+        return expr.clone();
+    }
+
+    let mut theta: Vec<f64> = params
+        .iter()
+        .map(|i| match expr.nodes[*i] {
+            Node::Number(c) => c,
+            _ => unreachable!(),
+        })
+        .collect();
+
+    let residuals = Residuals::new(expr, &params, x, y);
+    let (mut loss, mut jacobian, mut r) = residuals.linearize(&theta);
+    if !loss.is_finite() {
+        return expr.clone();
+    }
+
+    let n = params.len();
+    let mut lambda = 1e-3;
+
+    'outer: for _ in 0..iterations {
+        // Normal equations: J^T J and J^T r
+        let mut jtj = vec![0.0; n * n];
+        let mut jtr = vec![0.0; n];
+        for (row, ri) in jacobian.chunks(n).zip(&r) {
+            for i in 0..n {
+                jtr[i] += row[i] * ri;
+                for j in 0..n {
+                    jtj[i * n + j] += row[i] * row[j];
+                }
+            }
+        }
+
+        loop {
+            let mut a = jtj.clone();
+            for i in 0..n {
+                a[i * n + i] += lambda * jtj[i * n + i].max(1e-12);
+            }
+            let b: Vec<f64> = jtr.iter().map(|v| -v).collect();
+
+            let step = solve(a, b, n);
+            let candidate: Option<Vec<f64>> =
+                step.map(|step| theta.iter().zip(&step).map(|(t, s)| t + s).collect());
+
+            if let Some(candidate) = candidate {
+                let candidate_loss = residuals.loss(&candidate);
+                if candidate_loss < loss {
+                    theta = candidate;
+                    lambda = (lambda / 10.0).max(1e-12);
+
+                    let converged = loss - candidate_loss <= 1e-12 * loss;
+                    (loss, jacobian, r) = residuals.linearize(&theta);
+                    if converged {
+                        break 'outer;
+                    }
+                    break;
+                }
+            }
+
+            lambda *= 10.0;
+            if lambda > 1e10 {
+                break 'outer;
+            }
+        }
+    }
+
+    let mut fit = expr.clone();
+    for (node, value) in params.iter().zip(&theta) {
+        fit.nodes[*node] = Node::Number(*value);
+    }
+    fit
+}
+
+// The residuals `f(x) - y` as a function of the constants.
+struct Residuals<'a> {
+    expr: &'a Expr,
+
+    // Parameter index of every node, if it is one
+    index: Vec<Option<usize>>,
+    x: &'a Vec2d<f64>,
+    y: &'a [f64],
+}
+
+impl<'a> Residuals<'a> {
+    fn new(expr: &'a Expr, params: &'a [usize], x: &'a Vec2d<f64>, y: &'a [f64]) -> Residuals<'a> {
+        let mut index = vec![None; expr.nodes.len()];
+        for (i, node) in params.iter().enumerate() {
+            index[*node] = Some(i);
+        }
+
+        Residuals { expr, index, x, y }
+    }
+
+    // Sum of squared residuals.
+    fn loss(&self, theta: &[f64]) -> f64 {
+        let (rows, _cols) = self.x.shape();
+        let number = |node: usize, x: f64| self.index[node].map_or(x, |i| theta[i]);
+
+        (0..rows)
+            .map(|i_row| {
+                let x_row = self.x.get_row(i_row).unwrap();
+                let pred = self.expr.evaluate_scalar(x_row, &number);
+                (pred - self.y[i_row]).powi(2)
+            })
+            .sum()
+    }
+
+    // Sum of squared residuals, the row-major
+    // Jacobian and the residuals themselves.
+    fn linearize(&self, theta: &[f64]) -> (f64, Vec<f64>, Vec<f64>) {
+        let (rows, _cols) = self.x.shape();
+        let n = theta.len();
+        let number = |node: usize, x: f64| match self.index[node] {
+            Some(i) => Dual::variable(theta[i], i, n),
+            None => Dual::constant(x),
+        };
+
+        let mut loss = 0.0;
+        let mut jacobian = Vec::with_capacity(rows * n);
+        let mut residuals = Vec::with_capacity(rows);
+        for i_row in 0..rows {
+            let x_row: Vec<Dual> = self
+                .x
+                .get_row(i_row)
+                .unwrap()
+                .iter()
+                .map(|x| Dual::constant(*x))
+                .collect();
+            let pred = self.expr.evaluate_scalar(&x_row, &number);
+            let residual = pred.value() - self.y[i_row];
+
+            loss += residual * residual;
+            residuals.push(residual);
+            jacobian.extend((0..n).map(|i| pred.grad.get(i).copied().unwrap_or(0.0)));
+        }
+
+        (loss, jacobian, residuals)
+    }
+}
+
+// Solves the `n` by `n` system `a x = b` by Gaussian
+// elimination with partial pivoting. None if singular.
+fn solve(mut a: Vec<f64>, mut b: Vec<f64>, n: usize) -> Option<Vec<f64>> {
+    for col in 0..n {
+        let pivot =
+            (col..n).max_by(|i, j| a[i * n + col].abs().total_cmp(&a[j * n + col].abs()))?;
+        if a[pivot * n + col].abs() < 1e-300 || !a[pivot * n + col].is_finite() {
+            return None;
+        }
+
+        if pivot != col {
+            for k in 0..n {
+                a.swap(pivot * n + k, col * n + k);
+            }
+            b.swap(pivot, col);
+        }
+
+        for row in col + 1..n {
+            let factor = a[row * n + col] / a[col * n + col];
+            for k in col..n {
+                a[row * n + k] -= factor * a[col * n + k];
+            }
+            b[row] -= factor * b[col];
+        }
+    }
+
+    let mut x = vec![0.0; n];
+    for row in (0..n).rev() {
+        let sum: f64 = (row + 1..n).map(|k| a[row * n + k] * x[k]).sum();
+        x[row] = (b[row] - sum) / a[row * n + row];
+    }
+
+    if x.iter().all(|v| v.is_finite()) {
+        Some(x)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dataset(f: impl Fn(f64) -> f64) -> (Vec2d<f64>, Vec<f64>) {
+        let mut x = Vec2d::new(1);
+        let mut y = Vec::new();
+        for i in 0..50 {
+            let xi = i as f64 / 10.0 - 2.5;
+            x.push(xi);
+            y.push(f(xi));
+        }
+        (x, y)
+    }
+
+    #[test]
+    fn linear() {
+        let (x, y) = dataset(|x| 2.5 * x - 1.3);
+        let expr = Expr::from_infix("1 * x + 0", 1, &["x"]).unwrap();
+        let fit = fit_constants(&expr, &x, &y, 20);
+        assert_eq!(
+            format!("{:.6}", fit.infix(&["x"])),
+            "2.500000 * x + -1.300000"
+        );
+    }
+
+    #[test]
+    fn nonlinear() {
+        let (x, y) = dataset(|x| 3.0 * (1.7 * x).sin());
+        let expr = Expr::from_infix("2 * sin(1.5 * x)", 1, &["x"]).unwrap();
+        let fit = fit_constants(&expr, &x, &y, 100);
+        assert_eq!(
+            format!("{:.6}", fit.infix(&["x"])),
+            "3.000000 * sin(1.700000 * x)"
+        );
+
+        // Never makes things worse
+        let expr = Expr::from_infix("ln(x)", 1, &["x"]).unwrap();
+        let fit = fit_constants(&expr, &x, &y, 100);
+        assert_eq!(fit, expr);
+    }
+}
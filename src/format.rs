@@ -0,0 +1,285 @@
+use std::fmt;
+
+use crate::expr::{BinaryOp, Expr, Node, UnaryOp};
+
+/// Infix formatter that names the variables after the
+/// dataset columns. Variables without a name are
+/// printed as `x0`, `x1`, ...
+///
+/// The precision of the constants can be set with the
+/// usual format syntax, `format!("{:.2}", expr)`.
+pub struct Infix<'a> {
+    expr: &'a Expr,
+    names: &'a [&'a str],
+}
+
+impl Expr {
+    /// Infix notation, with the variables named after `names`.
+    pub fn infix<'a>(&'a self, names: &'a [&'a str]) -> Infix<'a> {
+        Infix { expr: self, names }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.infix(&[]).fmt(f)
+    }
+}
+
+impl fmt::Display for Infix<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write_node(f, self.expr.root)
+    }
+}
+
+impl Infix<'_> {
+    fn write_node(&self, f: &mut fmt::Formatter<'_>, node: usize) -> fmt::Result {
+        let expr = self.expr;
+
+        match &expr.nodes[node] {
+            Node::Number(x) => match f.precision() {
+                Some(precision) => write!(f, "{x:.precision$}"),
+                None => write!(f, "{x}"),
+            },
+            Node::Variable(ptr) => match self.names.get(*ptr) {
+                Some(name) => write!(f, "{name}"),
+                None => write!(f, "x{ptr}"),
+            },
+            Node::UnOp(op) => match op.op {
+                UnaryOp::Neg => {
+                    write!(f, "-")?;
+                    self.write_operand(f, op.a, child_needs_parens(expr, node, op.a))
+                }
+                _ => {
+                    write!(f, "{}(", function_name(&op.op))?;
+                    self.write_node(f, op.a)?;
+                    write!(f, ")")
+                }
+            },
+            Node::BinOp(op) if matches!(op.op, BinaryOp::Min | BinaryOp::Max) => {
+                let name = if op.op == BinaryOp::Min { "min" } else { "max" };
+                self.write_call(f, name, &[op.a, op.b])
+            }
+            Node::BinOp(op) => {
+                self.write_operand(f, op.a, child_needs_parens(expr, node, op.a))?;
+                let symbol = match op.op {
+                    BinaryOp::Add => " + ",
+                    BinaryOp::Sub => " - ",
+                    BinaryOp::Mul => " * ",
+                    BinaryOp::Div => " / ",
+                    BinaryOp::Pow => "^",
+                    BinaryOp::Lt => " < ",
+                    BinaryOp::Gt => " > ",
+                    BinaryOp::Min | BinaryOp::Max => unreachable!(),
+                };
+                write!(f, "{symbol}")?;
+                self.write_operand(f, op.b, child_needs_parens(expr, node, op.b))
+            }
+            Node::IfPositive(op) => {
+                self.write_call(f, "if_positive", &[op.cond, op.then, op.otherwise])
+            }
+        }
+    }
+
+    fn write_call(&self, f: &mut fmt::Formatter<'_>, name: &str, args: &[usize]) -> fmt::Result {
+        write!(f, "{name}(")?;
+        for (i, arg) in args.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            self.write_node(f, *arg)?;
+        }
+        write!(f, ")")
+    }
+
+    fn write_operand(&self, f: &mut fmt::Formatter<'_>, node: usize, parens: bool) -> fmt::Result {
+        if parens {
+            write!(f, "(")?;
+            self.write_node(f, node)?;
+            write!(f, ")")
+        } else {
+            self.write_node(f, node)
+        }
+    }
+}
+
+// Names of the built-in functions, as
+// written in the infix notation.
+pub(crate) fn function_name(op: &UnaryOp) -> &'static str {
+    match op {
+        UnaryOp::Neg => "neg",
+        UnaryOp::Abs => "abs",
+        UnaryOp::Loge => "ln",
+        UnaryOp::Log2 => "log2",
+        UnaryOp::Log10 => "log10",
+        UnaryOp::Sin => "sin",
+        UnaryOp::Cos => "cos",
+        UnaryOp::Tan => "tan",
+    }
+}
+
+const PREC_CMP: u8 = 0;
+const PREC_ADD: u8 = 1;
+const PREC_MUL: u8 = 2;
+const PREC_NEG: u8 = 3;
+const PREC_POW: u8 = 4;
+const PREC_ATOM: u8 = 5;
+
+// How tightly a node binds in the infix notation.
+pub(crate) fn precedence(expr: &Expr, node: usize) -> u8 {
+    match &expr.nodes[node] {
+        // Negative numbers print with a leading minus
+        // sign, so they bind like a negation.
+        Node::Number(x) if x.is_sign_negative() => PREC_NEG,
+        Node::Number(_) | Node::Variable(_) => PREC_ATOM,
+        Node::UnOp(op) => match op.op {
+            UnaryOp::Neg => PREC_NEG,
+            _ => PREC_ATOM,
+        },
+        Node::BinOp(op) => match op.op {
+            BinaryOp::Add | BinaryOp::Sub => PREC_ADD,
+            BinaryOp::Mul | BinaryOp::Div => PREC_MUL,
+            BinaryOp::Pow => PREC_POW,
+            BinaryOp::Lt | BinaryOp::Gt => PREC_CMP,
+            BinaryOp::Min | BinaryOp::Max => PREC_ATOM,
+        },
+        Node::IfPositive(_) => PREC_ATOM,
+    }
+}
+
+// Does `child` need parentheses when written as an operand of
+// `parent`? All binary operators associate to the left except
+// `^`, which associates to the right like in mathematics.
+pub(crate) fn child_needs_parens(expr: &Expr, parent: usize, child: usize) -> bool {
+    let parent_prec = precedence(expr, parent);
+    let child_prec = precedence(expr, child);
+
+    match &expr.nodes[parent] {
+        Node::UnOp(op) => match op.op {
+            // Always `-(-x)`, never `--x`, and `-(2)` so
+            // that it doesn't read as the number `-2`.
+            UnaryOp::Neg => child_prec <= PREC_NEG || matches!(expr.nodes[child], Node::Number(_)),
+            _ => false,
+        },
+        Node::BinOp(op) => {
+            let is_left = op.a == child;
+            match (is_left, &op.op) {
+                // Arguments of a call
+                (_, BinaryOp::Min | BinaryOp::Max) => false,
+                (true, BinaryOp::Pow) => child_prec <= parent_prec,
+                (true, _) => child_prec < parent_prec,
+                (false, BinaryOp::Pow) => child_prec < parent_prec,
+                (false, _) => child_prec <= parent_prec,
+            }
+        }
+        Node::Number(_) | Node::Variable(_) | Node::IfPositive(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::expr::{BinOp, IfPositive, UnOp};
+
+    use super::*;
+
+    fn var(expr: &mut Expr, i: usize) -> usize {
+        expr.nodes.push(Node::Variable(i));
+        expr.nodes.len() - 1
+    }
+
+    fn num(expr: &mut Expr, x: f64) -> usize {
+        expr.nodes.push(Node::Number(x));
+        expr.nodes.len() - 1
+    }
+
+    fn bin(expr: &mut Expr, op: BinaryOp, a: usize, b: usize) -> usize {
+        expr.nodes.push(Node::BinOp(BinOp { op, a, b }));
+        expr.root = expr.nodes.len() - 1;
+        expr.root
+    }
+
+    fn un(expr: &mut Expr, op: UnaryOp, a: usize) -> usize {
+        expr.nodes.push(Node::UnOp(UnOp { op, a }));
+        expr.root = expr.nodes.len() - 1;
+        expr.root
+    }
+
+    #[test]
+    fn precedence() {
+        let mut expr = Expr::new(3);
+        let (a, b, c) = (var(&mut expr, 0), var(&mut expr, 1), var(&mut expr, 2));
+
+        bin(&mut expr, BinaryOp::Add, a, b);
+        let sum = expr.root;
+        bin(&mut expr, BinaryOp::Mul, sum, c);
+        assert_eq!(expr.to_string(), "(x0 + x1) * x2");
+        bin(&mut expr, BinaryOp::Mul, c, sum);
+        assert_eq!(expr.to_string(), "x2 * (x0 + x1)");
+
+        let diff = bin(&mut expr, BinaryOp::Sub, a, b);
+        bin(&mut expr, BinaryOp::Sub, diff, c);
+        assert_eq!(expr.to_string(), "x0 - x1 - x2");
+        bin(&mut expr, BinaryOp::Sub, c, diff);
+        assert_eq!(expr.to_string(), "x2 - (x0 - x1)");
+
+        let pow = bin(&mut expr, BinaryOp::Pow, a, b);
+        bin(&mut expr, BinaryOp::Pow, pow, c);
+        assert_eq!(expr.to_string(), "(x0^x1)^x2");
+        bin(&mut expr, BinaryOp::Pow, c, pow);
+        assert_eq!(expr.to_string(), "x2^x0^x1");
+
+        un(&mut expr, UnaryOp::Neg, pow);
+        assert_eq!(expr.to_string(), "-x0^x1");
+        let neg = un(&mut expr, UnaryOp::Neg, a);
+        bin(&mut expr, BinaryOp::Pow, neg, b);
+        assert_eq!(expr.to_string(), "(-x0)^x1");
+        un(&mut expr, UnaryOp::Neg, neg);
+        assert_eq!(expr.to_string(), "-(-x0)");
+
+        let sin = un(&mut expr, UnaryOp::Sin, sum);
+        let half = num(&mut expr, -0.5);
+        bin(&mut expr, BinaryOp::Mul, half, sin);
+        assert_eq!(expr.to_string(), "-0.5 * sin(x0 + x1)");
+        bin(&mut expr, BinaryOp::Pow, half, a);
+        assert_eq!(expr.to_string(), "(-0.5)^x0");
+        let two = num(&mut expr, 2.0);
+        un(&mut expr, UnaryOp::Neg, two);
+        assert_eq!(expr.to_string(), "-(2)");
+
+        let lt = bin(&mut expr, BinaryOp::Lt, sum, c);
+        assert_eq!(expr.to_string(), "x0 + x1 < x2");
+        bin(&mut expr, BinaryOp::Mul, lt, a);
+        assert_eq!(expr.to_string(), "(x0 + x1 < x2) * x0");
+        bin(&mut expr, BinaryOp::Gt, a, lt);
+        assert_eq!(expr.to_string(), "x0 > (x0 + x1 < x2)");
+        let min = bin(&mut expr, BinaryOp::Min, lt, sum);
+        assert_eq!(expr.to_string(), "min(x0 + x1 < x2, x0 + x1)");
+        expr.nodes.push(Node::IfPositive(IfPositive {
+            cond: a,
+            then: min,
+            otherwise: half,
+        }));
+        expr.root = expr.nodes.len() - 1;
+        assert_eq!(
+            expr.to_string(),
+            "if_positive(x0, min(x0 + x1 < x2, x0 + x1), -0.5)"
+        );
+    }
+
+    #[test]
+    fn names_and_precision() {
+        let mut expr = Expr::new(2);
+        let a = var(&mut expr, 0);
+        let b = var(&mut expr, 1);
+        let x = num(&mut expr, 1.23456);
+        let mul = bin(&mut expr, BinaryOp::Mul, x, a);
+        bin(&mut expr, BinaryOp::Div, mul, b);
+
+        assert_eq!(format!("{expr:.2}"), "1.23 * x0 / x1");
+        assert_eq!(
+            format!("{:.3}", expr.infix(&["sepal_length"])),
+            "1.235 * sepal_length / x1"
+        );
+        assert_eq!(expr.infix(&["a", "b"]).to_string(), "1.23456 * a / b");
+    }
+}
@@ -0,0 +1,439 @@
+use std::{
+    f64::consts::{FRAC_PI_2, PI},
+    ops::{Add, Div, Mul, Neg, Sub},
+};
+
+use crate::{dual::Scalar, vec2d::Vec2d};
+
+/// Operations that may have produced NaN or
+/// infinity somewhere inside an interval.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Violations {
+    /// Log of a non-positive number.
+    pub log: bool,
+    /// Division by zero, including `0^-n`.
+    pub division: bool,
+    /// Tan at a pole.
+    pub tan: bool,
+    /// Negative base to a fractional exponent.
+    pub pow: bool,
+    /// An unbounded intermediate result.
+    pub overflow: bool,
+}
+
+impl Violations {
+    /// Was anything violated?
+    pub fn any(&self) -> bool {
+        self.log || self.division || self.tan || self.pow || self.overflow
+    }
+
+    fn union(self, other: Violations) -> Violations {
+        Violations {
+            log: self.log || other.log,
+            division: self.division || other.division,
+            tan: self.tan || other.tan,
+            pow: self.pow || other.pow,
+            overflow: self.overflow || other.overflow,
+        }
+    }
+}
+
+/// Closed interval `[lo, hi]` that is guaranteed to contain the value
+/// for any inputs within the input intervals, unless `violations` says
+/// otherwise. Computed bounds are widened by an ulp to cover rounding,
+/// assuming the library functions are accurate to within an ulp. Evaluating an `Expr` over intervals through `Scalar`
+/// bounds it over the whole box at the cost of a single evaluation.
+///
+/// Programs branch on the midpoint, so the bounds of a
+/// program with jumps only hold for the branches taken.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Interval {
+    /// Lower bound.
+    pub lo: f64,
+    /// Upper bound.
+    pub hi: f64,
+    /// What may have produced NaN or infinity.
+    pub violations: Violations,
+}
+
+impl Interval {
+    /// The interval `[lo, hi]`, without violations.
+    pub fn new(lo: f64, hi: f64) -> Interval {
+        Interval::with(lo, hi, Violations::default())
+    }
+
+    // Normalizes NaN bounds to unbounded, and
+    // flags any unbounded result as an overflow.
+    fn with(lo: f64, hi: f64, mut violations: Violations) -> Interval {
+        let lo = if lo.is_nan() { f64::NEG_INFINITY } else { lo };
+        let hi = if hi.is_nan() { f64::INFINITY } else { hi };
+        violations.overflow |= !lo.is_finite() || !hi.is_finite();
+        Interval { lo, hi, violations }
+    }
+
+    /// Every possible value is finite.
+    pub fn is_valid(&self) -> bool {
+        !self.violations.any()
+    }
+
+    /// Is `x` within the bounds?
+    pub fn contains(&self, x: f64) -> bool {
+        self.lo <= x && x <= self.hi
+    }
+
+    // Widened by an ulp on both sides, for bounds
+    // that had to be rounded to the nearest float.
+    fn rounded(lo: f64, hi: f64, violations: Violations) -> Interval {
+        Interval::with(lo.next_down(), hi.next_up(), violations)
+    }
+
+    fn unbounded(violations: Violations) -> Interval {
+        Interval::with(f64::NEG_INFINITY, f64::INFINITY, violations)
+    }
+
+    // Applies a monotonically non-decreasing function.
+    fn monotonic(self, f: impl Fn(f64) -> f64) -> Interval {
+        Interval::rounded(f(self.lo), f(self.hi), self.violations)
+    }
+
+    // Smallest interval containing all the
+    // points, widened for their rounding.
+    fn hull(points: &[f64], violations: Violations) -> Interval {
+        let lo = points.iter().copied().fold(f64::INFINITY, f64::min);
+        let hi = points.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        Interval::rounded(lo, hi, violations)
+    }
+
+    // Sine or cosine, given as `f` and the `phase` that turns
+    // it into a sine. Bounds far from zero can't place the peaks
+    // accurately enough, so they get the full range.
+    fn periodic(self, f: impl Fn(f64) -> f64, phase: f64) -> Interval {
+        let (lo, hi) = (self.lo + phase, self.hi + phase);
+        if !(lo.abs() < 1e6 && hi.abs() < 1e6) || hi - lo >= 2.0 * PI {
+            return Interval::with(-1.0, 1.0, self.violations);
+        }
+
+        // Is there an `x = offset + 2k pi` in `[lo, hi]`?
+        let crosses = |offset: f64| {
+            let k = ((lo - offset) / (2.0 * PI)).ceil();
+            offset + 2.0 * PI * k <= hi
+        };
+
+        let (a, b) = (f(self.lo), f(self.hi));
+        let lo_value = if crosses(-FRAC_PI_2) {
+            -1.0
+        } else {
+            a.min(b).next_down()
+        };
+        let hi_value = if crosses(FRAC_PI_2) {
+            1.0
+        } else {
+            a.max(b).next_up()
+        };
+        Interval::with(lo_value, hi_value, self.violations)
+    }
+
+    fn log(self, f: impl Fn(f64) -> f64) -> Interval {
+        let mut violations = self.violations;
+        violations.log |= self.lo <= 0.0;
+        let bound = |x: f64| if x > 0.0 { f(x) } else { f64::NEG_INFINITY };
+        Interval::rounded(bound(self.lo), bound(self.hi), violations)
+    }
+}
+
+// Multiplication where `0 * inf` is `0`, as an
+// infinite bound is never actually attained.
+fn mul_bound(a: f64, b: f64) -> f64 {
+    if a == 0.0 || b == 0.0 {
+        0.0
+    } else {
+        a * b
+    }
+}
+
+impl Add for Interval {
+    type Output = Interval;
+
+    fn add(self, rhs: Interval) -> Interval {
+        let violations = self.violations.union(rhs.violations);
+        Interval::rounded(self.lo + rhs.lo, self.hi + rhs.hi, violations)
+    }
+}
+
+impl Sub for Interval {
+    type Output = Interval;
+
+    fn sub(self, rhs: Interval) -> Interval {
+        let violations = self.violations.union(rhs.violations);
+        Interval::rounded(self.lo - rhs.hi, self.hi - rhs.lo, violations)
+    }
+}
+
+impl Mul for Interval {
+    type Output = Interval;
+
+    fn mul(self, rhs: Interval) -> Interval {
+        let violations = self.violations.union(rhs.violations);
+        let corners = [
+            mul_bound(self.lo, rhs.lo),
+            mul_bound(self.lo, rhs.hi),
+            mul_bound(self.hi, rhs.lo),
+            mul_bound(self.hi, rhs.hi),
+        ];
+        Interval::hull(&corners, violations)
+    }
+}
+
+impl Div for Interval {
+    type Output = Interval;
+
+    fn div(self, rhs: Interval) -> Interval {
+        let mut violations = self.violations.union(rhs.violations);
+        if rhs.contains(0.0) {
+            violations.division = true;
+            return Interval::unbounded(violations);
+        }
+
+        let corners = [
+            self.lo / rhs.lo,
+            self.lo / rhs.hi,
+            self.hi / rhs.lo,
+            self.hi / rhs.hi,
+        ];
+        Interval::hull(&corners, violations)
+    }
+}
+
+impl Neg for Interval {
+    type Output = Interval;
+
+    fn neg(self) -> Interval {
+        Interval::with(-self.hi, -self.lo, self.violations)
+    }
+}
+
+impl Scalar for Interval {
+    fn constant(x: f64) -> Interval {
+        Interval::new(x, x)
+    }
+
+    // The midpoint, only used for branching.
+    fn value(&self) -> f64 {
+        (self.lo + self.hi) / 2.0
+    }
+
+    fn abs(self) -> Interval {
+        if self.lo >= 0.0 {
+            self
+        } else if self.hi <= 0.0 {
+            -self
+        } else {
+            Interval::with(0.0, self.hi.max(-self.lo), self.violations)
+        }
+    }
+
+    fn ln(self) -> Interval {
+        self.log(f64::ln)
+    }
+
+    fn log2(self) -> Interval {
+        self.log(f64::log2)
+    }
+
+    fn log10(self) -> Interval {
+        self.log(f64::log10)
+    }
+
+    fn sin(self) -> Interval {
+        self.periodic(f64::sin, 0.0)
+    }
+
+    fn cos(self) -> Interval {
+        self.periodic(f64::cos, FRAC_PI_2)
+    }
+
+    fn tan(self) -> Interval {
+        // Poles at `pi / 2 + k pi`
+        let k = ((self.lo - FRAC_PI_2) / PI).ceil();
+        let pole = FRAC_PI_2 + PI * k;
+        if !(self.lo.abs() < 1e6 && self.hi.abs() < 1e6) || pole <= self.hi {
+            let mut violations = self.violations;
+            violations.tan = true;
+            return Interval::unbounded(violations);
+        }
+        self.monotonic(f64::tan)
+    }
+
+    fn powf(self, exponent: Interval) -> Interval {
+        let mut violations = self.violations.union(exponent.violations);
+        let (lo, hi) = (self.lo, self.hi);
+
+        if lo > 0.0 || (lo == 0.0 && exponent.lo >= 0.0) {
+            // Monotonic in both the base and the
+            // exponent, so the corners bound it.
+            let corners = [
+                lo.powf(exponent.lo),
+                lo.powf(exponent.hi),
+                hi.powf(exponent.lo),
+                hi.powf(exponent.hi),
+            ];
+            return Interval::hull(&corners, violations);
+        }
+
+        let n = exponent.lo;
+        if n != exponent.hi || n.fract() != 0.0 {
+            violations.pow = true;
+            return Interval::unbounded(violations);
+        }
+
+        if n < 0.0 && self.contains(0.0) {
+            violations.division = true;
+            return Interval::unbounded(violations);
+        }
+
+        let even = (n / 2.0).fract() == 0.0;
+        let (a, b) = (lo.powf(n), hi.powf(n));
+        if n == 0.0 {
+            Interval::with(1.0, 1.0, violations)
+        } else if even && self.contains(0.0) {
+            Interval::with(0.0, a.max(b).next_up(), violations)
+        } else {
+            Interval::hull(&[a, b], violations)
+        }
+    }
+
+    // Either branch, unless the condition is decided over the
+    // whole interval. Its violations carry over, as a NaN
+    // condition would silently pick `otherwise`.
+    fn if_positive(self, then: Interval, otherwise: Interval) -> Interval {
+        let branch = if self.lo > 0.0 {
+            then
+        } else if self.hi <= 0.0 {
+            otherwise
+        } else {
+            let violations = then.violations.union(otherwise.violations);
+            Interval::with(
+                then.lo.min(otherwise.lo),
+                then.hi.max(otherwise.hi),
+                violations,
+            )
+        };
+        let violations = branch.violations.union(self.violations);
+        Interval::with(branch.lo, branch.hi, violations)
+    }
+}
+
+/// The range of every column of `x`.
+pub fn column_bounds(x: &Vec2d<f64>) -> Vec<Interval> {
+    let (rows, cols) = x.shape();
+    let mut lo = vec![f64::INFINITY; cols];
+    let mut hi = vec![f64::NEG_INFINITY; cols];
+
+    for i_row in 0..rows {
+        for (i_col, value) in x.get_row(i_row).unwrap().iter().enumerate() {
+            lo[i_col] = lo[i_col].min(*value);
+            hi[i_col] = hi[i_col].max(*value);
+        }
+    }
+
+    lo.into_iter()
+        .zip(hi)
+        .map(|(lo, hi)| Interval::new(lo, hi))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{expr::Expr, vm::compile_expr};
+
+    fn bounds(src: &str, lo: f64, hi: f64) -> Interval {
+        let expr = Expr::from_infix(src, 1, &["x"]).unwrap();
+        expr.evaluate_scalar(&[Interval::new(lo, hi)], &|_, c| Interval::constant(c))
+    }
+
+    // Widened by an ulp for the rounding.
+    fn outward(lo: f64, hi: f64) -> Interval {
+        Interval::new(lo.next_down(), hi.next_up())
+    }
+
+    #[test]
+    fn rules() {
+        assert_eq!(bounds("1 / x", 1.0, 2.0), outward(0.5, 1.0));
+        assert_eq!(bounds("x * x", -1.0, 2.0), outward(-2.0, 4.0));
+        assert_eq!(
+            bounds("x^2", -1.0, 2.0),
+            Interval::new(0.0, 4.0_f64.next_up())
+        );
+        assert_eq!(
+            bounds("abs(x - 1)", -1.0, 2.0),
+            Interval::new(0.0, 2.0_f64.next_up())
+        );
+        assert_eq!(
+            bounds("sin(x)", 0.0, PI),
+            Interval::new(0.0_f64.next_down(), 1.0)
+        );
+        assert_eq!(bounds("cos(x)", 0.0, 1.0).hi, 1.0);
+
+        assert!(bounds("ln(x)", -1.0, 1.0).violations.log);
+        assert!(bounds("1 / x", -1.0, 1.0).violations.division);
+        assert!(bounds("tan(x)", 1.0, 2.0).violations.tan);
+        assert!(bounds("x^0.5", -1.0, 1.0).violations.pow);
+        assert!(bounds("x^1000", 0.0, 10.0).violations.overflow);
+        assert!(bounds("ln(x) + tan(x)", 0.1, 1.0).is_valid());
+
+        assert_eq!(bounds("x < 1", 2.0, 3.0), Interval::new(0.0, 0.0));
+        assert_eq!(bounds("x < 1", 0.0, 3.0), Interval::new(0.0, 1.0));
+        assert_eq!(bounds("max(x, 0)", -1.0, 2.0), Interval::new(-1.0, 2.0));
+        assert_eq!(
+            bounds("if_positive(x, 1 / x, 0)", 1.0, 2.0),
+            outward(0.5, 1.0)
+        );
+        assert!(
+            bounds("if_positive(x, 1 / x, 0)", -1.0, 2.0)
+                .violations
+                .division
+        );
+        assert!(bounds("if_positive(ln(x), 1, 0)", -1.0, 2.0).violations.log);
+    }
+
+    #[test]
+    fn containment() {
+        let n_vars = 3;
+        for _ in 0..10_000 {
+            let mut expr = Expr::new(n_vars);
+            expr.random_tree(6);
+
+            let box_bounds: Vec<Interval> = (0..n_vars)
+                .map(|_| {
+                    let lo = rand::random::<f64>() * 4.0 - 2.0;
+                    Interval::new(lo, lo + rand::random::<f64>() * 2.0)
+                })
+                .collect();
+            let constant = |_, c| Interval::constant(c);
+            let result = expr.evaluate_scalar(&box_bounds, &constant);
+            if !expr.has_conditional() {
+                let program_result = compile_expr(&expr)
+                    .evaluate_scalar(&box_bounds, &constant)
+                    .unwrap();
+                assert_eq!(result, program_result);
+            }
+
+            if !result.is_valid() {
+                continue;
+            }
+
+            for _ in 0..20 {
+                let vars: Vec<f64> = box_bounds
+                    .iter()
+                    .map(|b| b.lo + rand::random::<f64>() * (b.hi - b.lo))
+                    .collect();
+                let value = expr.evaluate(&vars);
+                assert!(
+                    result.contains(value),
+                    "{expr} = {value} at {vars:?}, outside {result:?}"
+                );
+            }
+        }
+    }
+}
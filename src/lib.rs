@@ -0,0 +1,58 @@
+//! Symbolic regression: searching for a mathematical expression
+//! that fits a dataset.
+//!
+//! Candidates are [`Expr`] trees, evolved by [`genetic_optimizer`]
+//! or [`nsga2_optimizer`] and scored on a [`Vec2d`] of inputs, which
+//! [`DataLoader`] reads from CSV. Trees are compiled to a stack
+//! [`Program`] for fast evaluation, which can also be saved, or
+//! exported as assembly, C, WebAssembly or, from the tree, Rust.
+//!
+//! ```no_run
+//! use symreg_rs::{categorize_cols, compile_expr, genetic_optimizer, DataLoader, GeneticParameters};
+//!
+//! let data_loader = DataLoader::new("data/IRIS.csv").unwrap();
+//! let mut data = data_loader.vec2d();
+//! let headers = data.pop_head();
+//! let (x, y) = categorize_cols(data).split_right();
+//!
+//! let hall_of_fame = genetic_optimizer(10, &x, &y, &GeneticParameters::default());
+//! let (loss, expr) = hall_of_fame.best().unwrap();
+//! println!("{loss}: {}", expr.simplify().infix(&headers));
+//! println!("{}", compile_expr(expr).optimize());
+//! ```
+
+/// Reading CSV files.
+pub mod dataloader;
+mod derivative;
+/// Dual numbers, and the `Scalar` trait expressions are evaluated over.
+pub mod dual;
+mod export;
+/// Expression trees, their evaluation, mutation and parsing.
+pub mod expr;
+/// Fitting the constants of an expression to the data.
+pub mod fitting;
+/// Printing expressions as infix, LaTeX, SymPy or Mathematica.
+pub mod format;
+/// Interval arithmetic, for bounding expressions over a range of inputs.
+pub mod interval;
+/// Losses and complexity measures.
+pub mod metrics;
+/// The searches for an expression fitting a dataset.
+pub mod optimizer;
+/// Saving and loading expressions and programs.
+pub mod serialize;
+mod simplify;
+/// Tables of inputs.
+pub mod vec2d;
+/// Compiling expressions to bytecode for a stack machine, and running it.
+pub mod vm;
+
+pub use dataloader::DataLoader;
+pub use expr::{Expr, ParseError};
+pub use optimizer::{genetic_optimizer, nsga2_optimizer, GeneticParameters, HallOfFame};
+pub use serialize::{FormatError, Saved};
+pub use vec2d::{categorize_cols, Vec2d};
+pub use vm::{
+    asm::{AsmError, AsmErrorKind},
+    compile_expr, Program, VmError,
+};
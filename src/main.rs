@@ -1,96 +1,113 @@
-#![feature(test)]
+use std::{env, process};
 
-extern crate test;
+use symreg_rs::{
+    categorize_cols, compile_expr, genetic_optimizer,
+    optimizer::{ConstantFitting, Selection},
+    DataLoader, GeneticParameters,
+};
 
-use dataloader::DataLoader;
-use optimizer::{genetic_optimizer, GeneticParameters};
-use vec2d::{categorize_cols, Vec2d};
-use vm::compile_expr;
+const USAGE: &str = "\
+Usage: symreg-rs [CSV] [--generations N] [--population N] [--emit FORMAT]
 
-mod dataloader;
-mod expr;
-mod metrics;
-mod optimizer;
-mod vec2d;
-mod vm;
+Searches for an expression predicting the last column of CSV
+(data/IRIS.csv by default) from the others, and prints it along
+with the compiled program.
 
-fn main() {
-    let data_loader = DataLoader::new("data/IRIS.csv").unwrap();
-    let mut data = data_loader.vec2d();
-    let _headers = data.pop_head();
-    let data = categorize_cols(data);
-    let (x, y) = data.split_right();
-    let params = GeneticParameters {
-        population_size: 100000,
-        cutoff: 0.1,
-        mutation_rate: 0.1,
-    };
+FORMAT is one of asm (the default), c, rust, latex, sympy or
+mathematica.";
+
+const FORMATS: [&str; 6] = ["asm", "c", "rust", "latex", "sympy", "mathematica"];
 
-    let (_loss, tree) = genetic_optimizer(10, &x, &y, &params);
-    let program = compile_expr(&tree);
-    program.pprint();
+struct Args {
+    path: String,
+    generations: usize,
+    population_size: usize,
+    emit: String,
 }
 
-#[cfg(test)]
-mod tests {
-    use crate::{
-        dataloader::DataLoader,
-        optimizer::{genetic_optimizer, GeneticParameters},
-        vec2d::categorize_cols,
+fn parse_args() -> Result<Args, String> {
+    let mut args = Args {
+        path: "data/IRIS.csv".to_string(),
+        generations: 10,
+        population_size: 100_000,
+        emit: "asm".to_string(),
     };
 
-    use self::{expr::Expr, vm::Program};
-
-    use super::*;
-    use test::Bencher;
-
-    #[test]
-    fn iris() {
-        let data_loader = DataLoader::new("data/IRIS.csv").unwrap();
-        let mut data = data_loader.vec2d();
-        let _headers = data.pop_head();
-        let data = categorize_cols(data);
-        let (x, y) = data.split_right();
-        let params = GeneticParameters {
-            population_size: 1000,
-            cutoff: 0.1,
-            mutation_rate: 0.1,
-        };
-
-        let (_loss, _tree) = genetic_optimizer(20, &x, &y, &params);
+    let mut iter = env::args().skip(1);
+    while let Some(arg) = iter.next() {
+        let mut value = || iter.next().ok_or(format!("{arg} needs a value"));
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                process::exit(0);
+            }
+            "--generations" => {
+                args.generations = value()?.parse().map_err(|err| format!("{arg}: {err}"))?
+            }
+            "--population" => {
+                args.population_size = value()?.parse().map_err(|err| format!("{arg}: {err}"))?
+            }
+            "--emit" => {
+                args.emit = value()?;
+                if !FORMATS.contains(&args.emit.as_str()) {
+                    return Err(format!("unknown format {}", args.emit));
+                }
+            }
+            _ if arg.starts_with('-') => return Err(format!("unknown option {arg}")),
+            _ => args.path = arg,
+        }
     }
 
-    #[bench]
-    fn exprs(b: &mut Bencher) {
-        let exprs: Vec<Expr> = (0..10_000)
-            .map(|_| {
-                let mut expr = Expr::new(0);
-                expr.random_tree(10);
-                expr
-            })
-            .collect();
+    Ok(args)
+}
 
-        b.iter(|| {
-            exprs.iter().for_each(|e| {
-                e.evaluate(&[]);
-            });
-        });
-    }
+fn main() {
+    let args = parse_args().unwrap_or_else(|err| {
+        eprintln!("{err}\n\n{USAGE}");
+        process::exit(2);
+    });
+
+    let data_loader = DataLoader::new(&args.path).unwrap_or_else(|err| {
+        eprintln!("{}: {err}", args.path);
+        process::exit(1);
+    });
+    let mut data = data_loader.vec2d();
+    let headers = data.pop_head();
+    let data = categorize_cols(data);
+    let (x, y) = data.split_right();
+    let params = GeneticParameters {
+        population_size: args.population_size,
+        selection: Selection::Truncation(0.1),
+        mutation_rate: 0.1,
+        crossover_rate: 0.5,
+        elitism: 10,
+        hall_of_fame_size: 10,
+        simplify: false,
+        constant_fitting: Some(ConstantFitting {
+            every: 2,
+            top_k: 100,
+            iterations: 20,
+        }),
+        interval_pruning: true,
+    };
 
-    #[bench]
-    fn compiled_exprs(b: &mut Bencher) {
-        let exprs: Vec<Program> = (0..10_000)
-            .map(|_| {
-                let mut expr = Expr::new(0);
-                expr.random_tree(10);
-                compile_expr(&expr)
-            })
-            .collect();
+    let hall_of_fame = genetic_optimizer(args.generations, &x, &y, &params);
+    let (_loss, tree) = hall_of_fame.best().expect("no finite loss was found");
+    let tree = tree.simplify();
+    println!("{:.4}", tree.infix(&headers));
 
-        b.iter(|| {
-            exprs.iter().for_each(|e| {
-                e.evaluate(&[]);
-            });
-        });
+    let program = compile_expr(&tree).optimize();
+    let (_rows, cols) = x.shape();
+    let names = &headers[..cols];
+    match args.emit.as_str() {
+        "asm" => println!("{program}"),
+        "c" => {
+            let c = program.to_c("model", cols, names, true).unwrap();
+            print!("{}\n{}", c.header, c.source);
+        }
+        "rust" => print!("{}", tree.to_rust(names)),
+        "latex" => println!("{}", tree.to_latex(names)),
+        "sympy" => print!("{}", tree.to_sympy(names)),
+        _ => println!("{}", tree.to_mathematica(names)),
     }
 }
@@ -1,6 +1,7 @@
 use crate::expr::{BinaryOp, Expr, Node, UnaryOp};
 
-pub fn mae(y_pred: &Vec<f64>, y_true: &Vec<f64>) -> f64 {
+/// Mean absolute error.
+pub fn mae(y_pred: &[f64], y_true: &[f64]) -> f64 {
     assert_eq!(y_pred.len(), y_true.len());
 
     y_pred
@@ -11,6 +12,7 @@ pub fn mae(y_pred: &Vec<f64>, y_true: &Vec<f64>) -> f64 {
         / y_pred.len() as f64
 }
 
+/// Mean squared error.
 #[inline(always)]
 pub fn mse(y_pred: &[f64], y_true: &[f64]) -> f64 {
     assert_eq!(y_pred.len(), y_true.len());
@@ -23,27 +25,36 @@ pub fn mse(y_pred: &[f64], y_true: &[f64]) -> f64 {
         / (y_pred.len() as f64)
 }
 
+/// Penalty added to the loss, `alpha` times the complexity.
 #[inline(always)]
 pub fn regularize(model: &Expr, alpha: f64) -> f64 {
-    alpha
-        * model
-            .nodes
-            .iter()
-            .map(|n| match n {
-                Node::Number(_) => 1.0,
-                Node::Variable(_) => 2.0,
-                Node::UnOp(op) => match op.op {
-                    UnaryOp::Neg => 1.0,
-                    UnaryOp::Abs => 2.0,
-                    _ => 5.0,
-                },
-                Node::BinOp(op) => match op.op {
-                    BinaryOp::Add => 1.0,
-                    BinaryOp::Sub => 1.0,
-                    BinaryOp::Mul => 2.0,
-                    BinaryOp::Div => 2.0,
-                    BinaryOp::Pow => 3.0,
-                },
-            })
-            .sum::<f64>()
+    alpha * complexity(model)
+}
+
+/// Only counts the nodes reachable from the root.
+pub fn complexity(model: &Expr) -> f64 {
+    model
+        .subtree(model.root)
+        .into_iter()
+        .map(|i| match &model.nodes[i] {
+            Node::Number(_) => 1.0,
+            Node::Variable(_) => 2.0,
+            Node::UnOp(op) => match op.op {
+                UnaryOp::Neg => 1.0,
+                UnaryOp::Abs => 2.0,
+                _ => 5.0,
+            },
+            Node::BinOp(op) => match op.op {
+                BinaryOp::Add => 1.0,
+                BinaryOp::Sub => 1.0,
+                BinaryOp::Mul => 2.0,
+                BinaryOp::Div => 2.0,
+                BinaryOp::Pow => 3.0,
+                BinaryOp::Lt | BinaryOp::Gt => 3.0,
+                BinaryOp::Min | BinaryOp::Max => 3.0,
+            },
+            // Piecewise models are harder to read than smooth ones
+            Node::IfPositive(_) => 4.0,
+        })
+        .sum::<f64>()
 }
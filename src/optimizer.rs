@@ -1,14 +1,19 @@
 use indicatif::ParallelProgressIterator;
-use rand::Rng;
+use rand::{rngs::ThreadRng, seq::SliceRandom, Rng};
 use rayon::iter::{IntoParallelRefMutIterator, ParallelIterator};
 
 use crate::{
+    dual::Scalar,
     expr::Expr,
-    metrics::{mse, regularize},
+    fitting::fit_constants,
+    interval::{column_bounds, Interval},
+    metrics::{complexity, mse, regularize},
     vec2d::Vec2d,
     vm::{compile_expr, Program},
 };
 
+/// Baseline that evaluates `iterations` random trees and
+/// returns the best loss and expression.
 pub fn naive_montecarlo(iterations: usize, x: Vec2d<f64>, y: Vec<f64>) -> (f64, Expr) {
     let (rows, cols) = x.shape();
 
@@ -28,11 +33,10 @@ pub fn naive_montecarlo(iterations: usize, x: Vec2d<f64>, y: Vec<f64>) -> (f64,
         let mut expr = Expr::new(cols);
         expr.random_tree(10);
 
-        for ii in 0..rows - 2 {
+        for (ii, &y_row) in y.iter().enumerate().take(rows - 2) {
             let x_row = x.get_row(ii).unwrap();
-            let y_row = y[ii];
 
-            let result = expr.evaluate(&x_row);
+            let result = expr.evaluate(x_row);
 
             // discard nan results
             if result.is_nan() {
@@ -54,19 +58,145 @@ pub fn naive_montecarlo(iterations: usize, x: Vec2d<f64>, y: Vec<f64>) -> (f64,
     (best_loss, best_expr)
 }
 
+/// How parents are picked for the next generation.
+#[derive(Debug, Clone)]
+pub enum Selection {
+    /// Pick uniformly among the best `cutoff`
+    /// fraction of the population.
+    Truncation(f64),
+
+    /// Best out of `k` uniformly picked individuals.
+    Tournament(usize),
+
+    /// Fitness proportional, the fitness
+    /// of an individual is `1 / (1 + loss)`.
+    Roulette,
+
+    /// Linear ranking, the best individual has
+    /// weight `n` and the worst weight `1`.
+    Rank,
+
+    /// Epsilon-lexicase over the per row errors.
+    Lexicase,
+}
+
+/// Settings for [`genetic_optimizer`] and [`nsga2_optimizer`].
 pub struct GeneticParameters {
+    /// Individuals in every generation.
     pub population_size: usize,
-    pub cutoff: f64,
+    /// How parents are picked.
+    pub selection: Selection,
+    /// Chance of each node of an offspring being mutated.
     pub mutation_rate: f64,
+    /// Chance of an offspring crossing its parent with a second one.
+    pub crossover_rate: f64,
+
+    /// Number of best individuals copied
+    /// unchanged to the next generation.
+    pub elitism: usize,
+    /// Capacity of the returned [`HallOfFame`].
+    pub hall_of_fame_size: usize,
+
+    /// Simplify every offspring to fight bloat.
+    pub simplify: bool,
+
+    /// Tune the constants of the best individuals
+    /// with a local gradient based search.
+    pub constant_fitting: Option<ConstantFitting>,
+
+    /// Discard individuals that may hit NaN or infinity within
+    /// the range of the training data, as bounded by interval
+    /// arithmetic, without evaluating them on the whole dataset.
+    pub interval_pruning: bool,
 }
 
-impl GeneticParameters {
-    pub fn default() -> GeneticParameters {
+/// When to run [`fit_constants`], and on how many.
+#[derive(Debug, Clone)]
+pub struct ConstantFitting {
+    /// Fit every `every` generations,
+    /// the `top_k` best individuals.
+    pub every: usize,
+    /// Number of individuals fitted each time.
+    pub top_k: usize,
+
+    /// Levenberg-Marquardt iterations per individual.
+    pub iterations: usize,
+}
+
+impl Default for GeneticParameters {
+    fn default() -> GeneticParameters {
         GeneticParameters {
             population_size: 1_000,
-            cutoff: 0.1,
+            selection: Selection::Truncation(0.1),
             mutation_rate: 0.01,
+            crossover_rate: 0.5,
+            elitism: 1,
+            hall_of_fame_size: 10,
+            simplify: false,
+            constant_fitting: None,
+            interval_pruning: false,
+        }
+    }
+}
+
+/// The best distinct expressions seen during a run.
+#[derive(Debug, Clone)]
+pub struct HallOfFame {
+    capacity: usize,
+
+    // Sorted by loss, best first.
+    entries: Vec<(f64, Expr)>,
+}
+
+impl HallOfFame {
+    /// Keeps up to `capacity` expressions.
+    pub fn new(capacity: usize) -> HallOfFame {
+        HallOfFame {
+            capacity,
+            entries: Vec::with_capacity(capacity),
+        }
+    }
+
+    /// Losses and expressions, best first.
+    pub fn entries(&self) -> &[(f64, Expr)] {
+        &self.entries
+    }
+
+    /// The entry with the lowest loss.
+    pub fn best(&self) -> Option<&(f64, Expr)> {
+        self.entries.first()
+    }
+
+    /// Would an expression with `loss` make it in?
+    pub fn accepts(&self, loss: f64) -> bool {
+        if !loss.is_finite() || self.capacity == 0 {
+            return false;
+        }
+
+        match self.entries.last() {
+            Some((worst, _)) if self.entries.len() == self.capacity => loss < *worst,
+            _ => true,
+        }
+    }
+
+    /// Returns true if the expression was added. Structural
+    /// duplicates of existing entries are only kept once.
+    pub fn insert(&mut self, loss: f64, expr: &Expr) -> bool {
+        if !self.accepts(loss) {
+            return false;
+        }
+
+        if let Some(i) = self.entries.iter().position(|(_, e)| e == expr) {
+            if loss >= self.entries[i].0 {
+                return false;
+            }
+            self.entries.remove(i);
         }
+
+        let i = self.entries.partition_point(|(l, _)| *l <= loss);
+        self.entries.insert(i, (loss, expr.clone()));
+        self.entries.truncate(self.capacity);
+        true
     }
 }
 
@@ -75,57 +205,241 @@ struct Individual {
     expr: Expr,
     compiled_expr: Program,
     loss: f64,
+
+    // Squared error for each row. Only
+    // kept around for lexicase selection.
+    errors: Vec<f64>,
 }
 
+impl Individual {
+    fn new(expr: Expr) -> Individual {
+        let compiled_expr = compile_expr(&expr).optimize();
+        Individual {
+            expr,
+            compiled_expr,
+            loss: f64::INFINITY,
+            errors: Vec::new(),
+        }
+    }
+
+    fn evaluate(
+        &mut self,
+        x: &Vec2d<f64>,
+        y: &[f64],
+        bounds: Option<&[Interval]>,
+        keep_errors: bool,
+    ) {
+        if let Some(bounds) = bounds {
+            // Through the tree, as the program would only
+            // bound the branches taken at the midpoints.
+            let range = self
+                .expr
+                .evaluate_scalar(bounds, &|_, c| Interval::constant(c));
+            if !range.is_valid() {
+                self.loss = f64::INFINITY;
+                if keep_errors {
+                    self.errors = vec![f64::INFINITY; y.len()];
+                }
+                return;
+            }
+        }
+
+        let preds = predict(&self.compiled_expr, x);
+        self.loss = mse(&preds, y) + regularize(&self.expr, 0.001);
+
+        if keep_errors {
+            self.errors = preds.iter().zip(y).map(|(a, b)| (b - a).powi(2)).collect();
+        }
+    }
+}
+
+// Selection state that only has to
+// be computed once per generation.
+struct Selector<'a> {
+    selection: &'a Selection,
+    population: &'a [Individual],
+
+    // Cumulative weights for
+    // roulette and rank selection.
+    weights: Vec<f64>,
+
+    // Per row epsilons for lexicase selection.
+    epsilons: Vec<f64>,
+}
+
+impl<'a> Selector<'a> {
+    // Expects the population to be sorted by loss.
+    fn new(selection: &'a Selection, population: &'a [Individual]) -> Selector<'a> {
+        let n = population.len();
+        let mut weights = Vec::new();
+        let mut epsilons = Vec::new();
+
+        match selection {
+            Selection::Truncation(cutoff) => {
+                let n_selected = (n as f64 * cutoff) as usize;
+                assert_ne!(n_selected, 0);
+            }
+            Selection::Tournament(k) => assert_ne!(*k, 0),
+            Selection::Roulette => {
+                let mut total = 0.0;
+                for individual in population {
+                    if individual.loss.is_finite() {
+                        total += 1.0 / (1.0 + individual.loss.max(0.0));
+                    }
+                    weights.push(total);
+                }
+            }
+            Selection::Rank => {
+                let mut total = 0.0;
+                for i in 0..n {
+                    total += (n - i) as f64;
+                    weights.push(total);
+                }
+            }
+            Selection::Lexicase => {
+                let n_cases = population[0].errors.len();
+                for case in 0..n_cases {
+                    let errors: Vec<f64> = population
+                        .iter()
+                        .map(|individual| individual.errors[case])
+                        .filter(|e| e.is_finite())
+                        .collect();
+                    epsilons.push(median_absolute_deviation(errors));
+                }
+            }
+        }
+
+        Selector {
+            selection,
+            population,
+            weights,
+            epsilons,
+        }
+    }
+
+    fn select(&self, rng: &mut ThreadRng) -> &'a Individual {
+        let n = self.population.len();
+
+        let i = match self.selection {
+            Selection::Truncation(cutoff) => {
+                let n_selected = (n as f64 * cutoff) as usize;
+                rng.gen_range(0..n_selected)
+            }
+            Selection::Tournament(k) => (0..*k)
+                .map(|_| rng.gen_range(0..n))
+                .min_by(|a, b| {
+                    self.population[*a]
+                        .loss
+                        .total_cmp(&self.population[*b].loss)
+                })
+                .unwrap(),
+            Selection::Roulette | Selection::Rank => {
+                let total = self.weights[n - 1];
+                if total == 0.0 {
+                    // Nobody has a finite loss
+                    rng.gen_range(0..n)
+                } else {
+                    let target = rng.gen::<f64>() * total;
+                    self.weights.partition_point(|w| *w <= target).min(n - 1)
+                }
+            }
+            Selection::Lexicase => {
+                let mut candidates: Vec<usize> = (0..n).collect();
+                let mut cases: Vec<usize> = (0..self.epsilons.len()).collect();
+                cases.shuffle(rng);
+
+                for case in cases {
+                    if candidates.len() == 1 {
+                        break;
+                    }
+
+                    let best = candidates
+                        .iter()
+                        .map(|i| self.population[*i].errors[case])
+                        .fold(f64::INFINITY, f64::min);
+                    let threshold = best + self.epsilons[case];
+                    candidates.retain(|i| self.population[*i].errors[case] <= threshold);
+                }
+
+                candidates[rng.gen_range(0..candidates.len())]
+            }
+        };
+
+        &self.population[i]
+    }
+}
+
+fn median_absolute_deviation(mut xs: Vec<f64>) -> f64 {
+    if xs.is_empty() {
+        return 0.0;
+    }
+
+    let center = median(&mut xs);
+    let mut deviations: Vec<f64> = xs.iter().map(|x| (x - center).abs()).collect();
+    median(&mut deviations)
+}
+
+fn median(xs: &mut [f64]) -> f64 {
+    xs.sort_by(|a, b| a.total_cmp(b));
+    let mid = xs.len() / 2;
+    if xs.len().is_multiple_of(2) {
+        (xs[mid - 1] + xs[mid]) / 2.0
+    } else {
+        xs[mid]
+    }
+}
+
+/// Evolves a population for `iterations` generations, minimizing the
+/// mean squared error of predicting `y` from `x` plus `regularize`,
+/// and returns the best expressions seen.
 pub fn genetic_optimizer(
     iterations: usize,
     x: &Vec2d<f64>,
-    y: &Vec<f64>,
+    y: &[f64],
     params: &GeneticParameters,
-) -> (f64, Expr) {
-    let (rows, cols) = x.shape();
+) -> HallOfFame {
+    let (_rows, cols) = x.shape();
     let mut population = Vec::new();
+    let mut hall_of_fame = HallOfFame::new(params.hall_of_fame_size);
 
     for _ in 0..params.population_size {
         let mut expr = Expr::new(cols);
         expr.random_tree(10);
-        let compiled_expr = compile_expr(&expr);
-        let loss = f64::INFINITY;
-        population.push(Individual {
-            expr,
-            compiled_expr,
-            loss,
-        });
+        population.push(Individual::new(expr));
     }
 
-    let n_selected = (population.len() as f64 * params.cutoff) as usize;
-    assert_ne!(n_selected, 0);
+    let keep_errors = matches!(params.selection, Selection::Lexicase);
+    let bounds = column_bounds(x);
+    let bounds = params.interval_pruning.then_some(&bounds[..]);
 
     for generation in 1..=iterations {
-        population.par_iter_mut().progress().for_each(|individual| {
-            let mut preds = Vec::new();
-            let mut trues = Vec::new();
-
-            for i_row in 0..rows {
-                let x_row = x.get_row(i_row).unwrap();
-                let y_row = y[i_row];
-
-                //let result = individual.expr.evaluate(x_row);
-                let result = individual.compiled_expr.evaluate(x_row).unwrap();
-                let result = if result.is_nan() {
-                    f64::INFINITY
-                } else {
-                    result
-                };
-                preds.push(result);
-                trues.push(y_row);
-            }
+        population
+            .par_iter_mut()
+            .progress()
+            .for_each(|individual| individual.evaluate(x, y, bounds, keep_errors));
+        population.sort_by(|a, b| a.loss.total_cmp(&b.loss));
 
-            let loss = mse(&preds, &trues) + regularize(&individual.expr, 0.001);
-            individual.loss = loss;
-        });
+        if let Some(fitting) = &params.constant_fitting {
+            if generation % fitting.every.max(1) == 0 {
+                let top_k = fitting.top_k.min(population.len());
+                population[..top_k].par_iter_mut().for_each(|individual| {
+                    let expr = fit_constants(&individual.expr, x, y, fitting.iterations);
+                    let mut fitted = Individual::new(expr);
+                    fitted.evaluate(x, y, bounds, keep_errors);
+                    if fitted.loss < individual.loss {
+                        *individual = fitted;
+                    }
+                });
+                population.sort_by(|a, b| a.loss.total_cmp(&b.loss));
+            }
+        }
+        for individual in &population {
+            if !hall_of_fame.accepts(individual.loss) {
+                break;
+            }
+            hall_of_fame.insert(individual.loss, &individual.expr);
+        }
 
-        population.sort_by(|a, b| a.loss.total_cmp(&b.loss));
         println!(
             "Generation {generation}, best loss: {:0.4}, best expr: {}",
             population[0].loss,
@@ -136,22 +450,385 @@ pub fn genetic_optimizer(
             break;
         }
         let mut new_population = Vec::new();
+        let n_elites = params.elitism.min(params.population_size);
+        new_population.extend_from_slice(&population[..n_elites]);
 
         let mut rng = rand::thread_rng();
+        let selector = Selector::new(&params.selection, &population);
 
-        for _i in 0..params.population_size {
-            let random_individual = population[rng.gen_range(0..n_selected)].clone();
-            let new_individual = random_individual.expr.mutate(params.mutation_rate);
-            let compiled_expr = compile_expr(&new_individual);
-            new_population.push(Individual {
-                expr: new_individual,
-                compiled_expr,
-                loss: f64::INFINITY,
-            });
+        for _i in n_elites..params.population_size {
+            let parent = selector.select(&mut rng);
+            let child = if rng.gen::<f64>() < params.crossover_rate {
+                let donor = selector.select(&mut rng);
+                parent.expr.crossover(&donor.expr)
+            } else {
+                parent.expr.clone()
+            };
+            let mut new_individual = child.mutate(params.mutation_rate);
+            if params.simplify {
+                new_individual = new_individual.simplify();
+            }
+            new_population.push(Individual::new(new_individual));
         }
 
         population = new_population;
     }
 
-    (population[0].loss, population[0].expr.clone())
+    hall_of_fame
+}
+
+// Predictions for every row of `x`, NaNs are replaced
+// with infinity so they can't win any comparison, and
+// so are the predictions of a malformed program.
+fn predict(program: &Program, x: &Vec2d<f64>) -> Vec<f64> {
+    let (rows, _cols) = x.shape();
+    let mut preds = program
+        .evaluate_batch(x)
+        .unwrap_or_else(|_| vec![f64::INFINITY; rows]);
+    for pred in &mut preds {
+        if pred.is_nan() {
+            *pred = f64::INFINITY;
+        }
+    }
+    preds
+}
+
+/// A point on the accuracy vs. complexity trade-off curve.
+#[derive(Debug, Clone)]
+pub struct ParetoPoint {
+    /// Mean squared error on the training data.
+    pub loss: f64,
+    /// As measured by [`complexity`].
+    pub complexity: f64,
+    /// The expression itself.
+    pub expr: Expr,
+}
+
+#[derive(Debug, Clone)]
+struct Solution {
+    expr: Expr,
+    compiled_expr: Program,
+    loss: f64,
+    complexity: f64,
+
+    // Index of the non-dominated front, 0 is the best.
+    rank: usize,
+    crowding: f64,
+}
+
+impl Solution {
+    fn new(expr: Expr) -> Solution {
+        let compiled_expr = compile_expr(&expr).optimize();
+        Solution {
+            expr,
+            compiled_expr,
+            loss: f64::INFINITY,
+            complexity: f64::INFINITY,
+            rank: usize::MAX,
+            crowding: 0.0,
+        }
+    }
+
+    fn dominates(&self, other: &Solution) -> bool {
+        self.loss <= other.loss
+            && self.complexity <= other.complexity
+            && (self.loss < other.loss || self.complexity < other.complexity)
+    }
+
+    // NSGA-II crowded comparison, lower rank
+    // wins and ties go to the less crowded one.
+    fn beats(&self, other: &Solution) -> bool {
+        self.rank < other.rank || (self.rank == other.rank && self.crowding > other.crowding)
+    }
+}
+
+/// Multi-objective optimizer (NSGA-II) that minimizes the MSE and the
+/// complexity of the expressions separately instead of folding them
+/// together with `regularize`. Returns the final non-dominated front
+/// sorted by complexity. `params.selection`, `params.elitism` and
+/// `params.hall_of_fame_size` are not used, the selection is a binary
+/// crowded tournament and the whole front is kept anyway.
+pub fn nsga2_optimizer(
+    iterations: usize,
+    x: &Vec2d<f64>,
+    y: &[f64],
+    params: &GeneticParameters,
+) -> Vec<ParetoPoint> {
+    let (_rows, cols) = x.shape();
+    let mut rng = rand::thread_rng();
+
+    let mut population: Vec<Solution> = (0..params.population_size)
+        .map(|_| {
+            let mut expr = Expr::new(cols);
+            expr.random_tree(10);
+            Solution::new(expr)
+        })
+        .collect();
+    evaluate_solutions(&mut population, x, y);
+    population = environmental_selection(population, params.population_size);
+
+    for generation in 1..=iterations {
+        let mut offspring = Vec::with_capacity(params.population_size);
+
+        for _i in 0..params.population_size {
+            let parent = crowded_tournament(&population, &mut rng);
+            let child = if rng.gen::<f64>() < params.crossover_rate {
+                let donor = crowded_tournament(&population, &mut rng);
+                parent.expr.crossover(&donor.expr)
+            } else {
+                parent.expr.clone()
+            };
+            let mut child = child.mutate(params.mutation_rate);
+            if params.simplify {
+                child = child.simplify();
+            }
+            offspring.push(Solution::new(child));
+        }
+
+        evaluate_solutions(&mut offspring, x, y);
+        population.append(&mut offspring);
+        population = environmental_selection(population, params.population_size);
+
+        let front_size = population.iter().filter(|s| s.rank == 0).count();
+        println!("Generation {generation}, pareto front size: {front_size}");
+    }
+
+    let mut front: Vec<ParetoPoint> = Vec::new();
+    for solution in population {
+        if solution.rank != 0 || !solution.loss.is_finite() {
+            continue;
+        }
+        if front.iter().any(|p| p.expr == solution.expr) {
+            continue;
+        }
+        front.push(ParetoPoint {
+            loss: solution.loss,
+            complexity: solution.complexity,
+            expr: solution.expr,
+        });
+    }
+    front.sort_by(|a, b| a.complexity.total_cmp(&b.complexity));
+
+    front
+}
+
+fn evaluate_solutions(solutions: &mut [Solution], x: &Vec2d<f64>, y: &[f64]) {
+    solutions.par_iter_mut().progress().for_each(|solution| {
+        let preds = predict(&solution.compiled_expr, x);
+        let loss = mse(&preds, y);
+        solution.loss = if loss.is_nan() { f64::INFINITY } else { loss };
+        solution.complexity = complexity(&solution.expr);
+    });
+}
+
+fn crowded_tournament<'a>(population: &'a [Solution], rng: &mut ThreadRng) -> &'a Solution {
+    let a = &population[rng.gen_range(0..population.len())];
+    let b = &population[rng.gen_range(0..population.len())];
+    if b.beats(a) {
+        b
+    } else {
+        a
+    }
+}
+
+// Keeps the best `n` solutions by front and crowding distance.
+fn environmental_selection(mut solutions: Vec<Solution>, n: usize) -> Vec<Solution> {
+    let fronts = non_dominated_sort(&mut solutions);
+
+    let mut slots: Vec<Option<Solution>> = solutions.into_iter().map(Some).collect();
+    let mut selected = Vec::with_capacity(n);
+
+    for front in fronts {
+        if selected.len() >= n {
+            break;
+        }
+
+        let mut members: Vec<Solution> = front.iter().map(|i| slots[*i].take().unwrap()).collect();
+        crowding_distance(&mut members);
+
+        if selected.len() + members.len() > n {
+            members.sort_by(|a, b| b.crowding.total_cmp(&a.crowding));
+            members.truncate(n - selected.len());
+        }
+        selected.append(&mut members);
+    }
+
+    selected
+}
+
+// Sets the rank of every solution and returns the fronts as indecies.
+//
+// With only two objectives a solution sorted by (loss, complexity)
+// can only be dominated by solutions that come before it, and it's
+// enough to compare against the last member of each front.
+fn non_dominated_sort(solutions: &mut [Solution]) -> Vec<Vec<usize>> {
+    let mut order: Vec<usize> = (0..solutions.len()).collect();
+    order.sort_by(|a, b| {
+        let (a, b) = (&solutions[*a], &solutions[*b]);
+        a.loss
+            .total_cmp(&b.loss)
+            .then(a.complexity.total_cmp(&b.complexity))
+    });
+
+    let mut fronts: Vec<Vec<usize>> = Vec::new();
+    for i in order {
+        let rank = fronts
+            .iter()
+            .position(|front| !solutions[*front.last().unwrap()].dominates(&solutions[i]))
+            .unwrap_or(fronts.len());
+
+        if rank == fronts.len() {
+            fronts.push(Vec::new());
+        }
+        fronts[rank].push(i);
+        solutions[i].rank = rank;
+    }
+
+    fronts
+}
+
+fn crowding_distance(front: &mut [Solution]) {
+    let n = front.len();
+    for solution in front.iter_mut() {
+        solution.crowding = 0.0;
+    }
+
+    let objectives: [fn(&Solution) -> f64; 2] = [|s| s.loss, |s| s.complexity];
+    for objective in objectives {
+        front.sort_by(|a, b| objective(a).total_cmp(&objective(b)));
+        front[0].crowding = f64::INFINITY;
+        front[n - 1].crowding = f64::INFINITY;
+
+        let range = objective(&front[n - 1]) - objective(&front[0]);
+        if !(range.is_finite() && range > 0.0) {
+            continue;
+        }
+
+        for i in 1..n - 1 {
+            let distance = (objective(&front[i + 1]) - objective(&front[i - 1])) / range;
+            front[i].crowding += distance;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn individual(loss: f64, errors: Vec<f64>) -> Individual {
+        let mut expr = Expr::new(0);
+        expr.random_tree(2);
+        let compiled_expr = compile_expr(&expr);
+        Individual {
+            expr,
+            compiled_expr,
+            loss,
+            errors,
+        }
+    }
+
+    #[test]
+    fn selection() {
+        let mut rng = rand::thread_rng();
+        let population = vec![
+            individual(0.0, vec![0.0, 0.0]),
+            individual(1.0, vec![0.0, 2.0]),
+            individual(2.0, vec![2.0, 2.0]),
+            individual(f64::INFINITY, vec![f64::INFINITY, f64::INFINITY]),
+        ];
+        let position = |selected: &Individual| {
+            population
+                .iter()
+                .position(|individual| std::ptr::eq(individual, selected))
+                .unwrap()
+        };
+
+        let truncation = Selection::Truncation(0.5);
+        let selector = Selector::new(&truncation, &population);
+        for _ in 0..100 {
+            assert!(position(selector.select(&mut rng)) < 2);
+        }
+
+        // A tournament over the whole population
+        // is very likely to contain the best one.
+        let tournament = Selection::Tournament(100);
+        let selector = Selector::new(&tournament, &population);
+        assert_eq!(position(selector.select(&mut rng)), 0);
+
+        let roulette = Selection::Roulette;
+        let selector = Selector::new(&roulette, &population);
+        for _ in 0..100 {
+            assert_ne!(position(selector.select(&mut rng)), 3);
+        }
+
+        let rank = Selection::Rank;
+        let selector = Selector::new(&rank, &population);
+        let mut counts = [0; 4];
+        for _ in 0..1_000 {
+            counts[position(selector.select(&mut rng))] += 1;
+        }
+        // Weights 4 to 1, so about 400 against 100.
+        assert!(counts[0] > 2 * counts[3], "{counts:?}");
+
+        let lexicase = Selection::Lexicase;
+        let selector = Selector::new(&lexicase, &population);
+        for _ in 0..100 {
+            assert_eq!(position(selector.select(&mut rng)), 0);
+        }
+    }
+
+    #[test]
+    fn hall_of_fame() {
+        let mut hall_of_fame = HallOfFame::new(2);
+        let mut a = Expr::new(1);
+        a.random_tree(3);
+        let mut b = Expr::new(2);
+        b.random_tree(3);
+
+        assert!(hall_of_fame.insert(2.0, &a));
+        assert!(!hall_of_fame.insert(3.0, &a));
+        assert!(hall_of_fame.insert(1.0, &a));
+        assert_eq!(hall_of_fame.entries().len(), 1);
+
+        assert!(!hall_of_fame.insert(f64::NAN, &b));
+        assert!(hall_of_fame.insert(5.0, &b));
+        assert!(!hall_of_fame.accepts(6.0));
+        assert_eq!(hall_of_fame.best().unwrap().0, 1.0);
+    }
+
+    #[test]
+    fn non_dominated_sort() {
+        let mut rng = rand::thread_rng();
+        let mut solutions: Vec<Solution> = (0..200)
+            .map(|_| {
+                let mut expr = Expr::new(0);
+                expr.random_tree(0);
+                let mut solution = Solution::new(expr);
+                solution.loss = rng.gen_range(0..20) as f64;
+                solution.complexity = rng.gen_range(0..20) as f64;
+                solution
+            })
+            .collect();
+
+        let fronts = super::non_dominated_sort(&mut solutions);
+
+        for (rank, front) in fronts.iter().enumerate() {
+            for i in front {
+                assert_eq!(solutions[*i].rank, rank);
+
+                // Nothing in the same or a worse front may dominate
+                // it, and something in the previous front has to.
+                for other in &solutions {
+                    if other.dominates(&solutions[*i]) {
+                        assert!(other.rank < rank);
+                    }
+                }
+                if rank > 0 {
+                    assert!(fronts[rank - 1]
+                        .iter()
+                        .any(|j| solutions[*j].dominates(&solutions[*i])));
+                }
+            }
+        }
+    }
 }
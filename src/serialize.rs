@@ -0,0 +1,388 @@
+use std::{error::Error, fmt};
+
+use crate::{
+    expr::{BinOp, BinaryOp, Expr, IfPositive, Node, UnOp, UnaryOp},
+    vm::VmError,
+};
+
+// Binary formats for saving models, shared by `Expr` and
+// `vm::Program`. Both start with a four byte magic and a little
+// endian `u16` version, followed by the input count and the column
+// names. Integers are little endian `u32`s, and strings and lists
+// are prefixed by their length. Enum variants are written as fixed
+// codes, so reordering them in the source doesn't change the format.
+pub(crate) const VERSION: u16 = 1;
+
+/// A loaded model, along with the inputs it was trained on.
+#[derive(Debug, Clone)]
+pub struct Saved<T> {
+    /// The expression or program.
+    pub model: T,
+    /// Number of inputs the model reads.
+    pub n_inputs: usize,
+    /// Column names of the inputs.
+    pub names: Vec<String>,
+}
+
+/// Why bytes couldn't be loaded.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FormatError {
+    /// Not the magic of the expected format.
+    BadMagic,
+    /// A version this build can't read.
+    UnsupportedVersion(u16),
+    /// The data ends early.
+    Truncated,
+    /// Bytes left after the end of the model.
+    TrailingBytes(usize),
+    /// A column name that isn't valid UTF-8.
+    InvalidUtf8,
+
+    /// Unknown code for an op, node or operator
+    InvalidTag(u8),
+
+    /// A constant, node, variable or jump
+    /// table entry that doesn't exist
+    InvalidIndex(usize),
+
+    /// The jump table doesn't list the labels in order
+    InvalidJumpTable,
+
+    /// The program fails `Program::verify`
+    Program(VmError),
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormatError::BadMagic => write!(f, "not a saved model"),
+            FormatError::UnsupportedVersion(v) => write!(f, "unsupported format version {v}"),
+            FormatError::Truncated => write!(f, "unexpected end of data"),
+            FormatError::TrailingBytes(n) => write!(f, "{n} bytes after the end of the model"),
+            FormatError::InvalidUtf8 => write!(f, "column name is not valid UTF-8"),
+            FormatError::InvalidTag(tag) => write!(f, "invalid tag {tag}"),
+            FormatError::InvalidIndex(i) => write!(f, "index {i} is out of range"),
+            FormatError::InvalidJumpTable => write!(f, "jump table doesn't match the labels"),
+            FormatError::Program(err) => write!(f, "invalid program: {err}"),
+        }
+    }
+}
+
+impl Error for FormatError {}
+
+pub(crate) struct Writer {
+    pub bytes: Vec<u8>,
+}
+
+impl Writer {
+    /// Starts a file with the common header.
+    pub fn new(magic: &[u8; 4], n_inputs: usize, names: &[&str]) -> Writer {
+        let mut writer = Writer {
+            bytes: magic.to_vec(),
+        };
+        writer.bytes.extend_from_slice(&VERSION.to_le_bytes());
+        writer.u32(n_inputs);
+        writer.u32(names.len());
+        for name in names {
+            writer.u32(name.len());
+            writer.bytes.extend_from_slice(name.as_bytes());
+        }
+        writer
+    }
+
+    pub fn u8(&mut self, x: u8) {
+        self.bytes.push(x);
+    }
+
+    pub fn u32(&mut self, x: usize) {
+        let x = u32::try_from(x).expect("too large to save");
+        self.bytes.extend_from_slice(&x.to_le_bytes());
+    }
+
+    pub fn f64(&mut self, x: f64) {
+        self.bytes.extend_from_slice(&x.to_le_bytes());
+    }
+}
+
+pub(crate) struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    /// Checks the common header, returning the
+    /// reader, the input count and the names.
+    pub fn new(
+        bytes: &'a [u8],
+        magic: &[u8; 4],
+    ) -> Result<(Reader<'a>, usize, Vec<String>), FormatError> {
+        let mut reader = Reader { bytes };
+        if reader.take(4).map_err(|_| FormatError::BadMagic)? != magic {
+            return Err(FormatError::BadMagic);
+        }
+
+        let version = u16::from_le_bytes(reader.take(2)?.try_into().unwrap());
+        if version != VERSION {
+            return Err(FormatError::UnsupportedVersion(version));
+        }
+
+        let n_inputs = reader.u32()?;
+        let n_names = reader.u32()?;
+        let mut names = Vec::new();
+        for _ in 0..n_names {
+            let len = reader.u32()?;
+            let name =
+                std::str::from_utf8(reader.take(len)?).map_err(|_| FormatError::InvalidUtf8)?;
+            names.push(name.to_string());
+        }
+
+        Ok((reader, n_inputs, names))
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], FormatError> {
+        if self.bytes.len() < n {
+            return Err(FormatError::Truncated);
+        }
+        let (head, tail) = self.bytes.split_at(n);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, FormatError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u32(&mut self) -> Result<usize, FormatError> {
+        let bytes = self.take(4)?.try_into().unwrap();
+        Ok(u32::from_le_bytes(bytes) as usize)
+    }
+
+    pub fn f64(&mut self) -> Result<f64, FormatError> {
+        Ok(f64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    /// An index below `len`.
+    pub fn index(&mut self, len: usize) -> Result<usize, FormatError> {
+        let i = self.u32()?;
+        if i >= len {
+            return Err(FormatError::InvalidIndex(i));
+        }
+        Ok(i)
+    }
+
+    /// Rejects anything after the model.
+    pub fn finish(self) -> Result<(), FormatError> {
+        match self.bytes.len() {
+            0 => Ok(()),
+            n => Err(FormatError::TrailingBytes(n)),
+        }
+    }
+}
+
+const EXPR_MAGIC: &[u8; 4] = b"SREX";
+
+// Node tags
+const NUMBER: u8 = 0;
+const VARIABLE: u8 = 1;
+const UNARY: u8 = 2;
+const BINARY: u8 = 3;
+const IF_POSITIVE: u8 = 4;
+
+pub(crate) const UNARY_CODES: [(UnaryOp, u8); 8] = [
+    (UnaryOp::Neg, 0),
+    (UnaryOp::Abs, 1),
+    (UnaryOp::Loge, 2),
+    (UnaryOp::Log2, 3),
+    (UnaryOp::Log10, 4),
+    (UnaryOp::Sin, 5),
+    (UnaryOp::Cos, 6),
+    (UnaryOp::Tan, 7),
+];
+
+const BINARY_CODES: [(BinaryOp, u8); 9] = [
+    (BinaryOp::Add, 0),
+    (BinaryOp::Sub, 1),
+    (BinaryOp::Mul, 2),
+    (BinaryOp::Div, 3),
+    (BinaryOp::Pow, 4),
+    (BinaryOp::Lt, 5),
+    (BinaryOp::Gt, 6),
+    (BinaryOp::Min, 7),
+    (BinaryOp::Max, 8),
+];
+
+pub(crate) fn encode<T: PartialEq>(codes: &[(T, u8)], value: &T) -> u8 {
+    codes.iter().find(|(v, _)| v == value).unwrap().1
+}
+
+pub(crate) fn decode<T: Clone>(codes: &[(T, u8)], code: u8) -> Result<T, FormatError> {
+    codes
+        .iter()
+        .find(|(_, c)| *c == code)
+        .map(|(v, _)| v.clone())
+        .ok_or(FormatError::InvalidTag(code))
+}
+
+impl Expr {
+    /// Saves the nodes reachable from the root, children before
+    /// their parents, with the root last. `names` are the columns.
+    pub fn to_bytes(&self, names: &[&str]) -> Vec<u8> {
+        let mut expr = self.clone();
+        expr.compact();
+
+        let mut writer = Writer::new(EXPR_MAGIC, self.n_inputs, names);
+        writer.u32(expr.nodes.len());
+        for node in &expr.nodes {
+            match node {
+                Node::Number(x) => {
+                    writer.u8(NUMBER);
+                    writer.f64(*x);
+                }
+                Node::Variable(ptr) => {
+                    writer.u8(VARIABLE);
+                    writer.u32(*ptr);
+                }
+                Node::UnOp(op) => {
+                    writer.u8(UNARY);
+                    writer.u8(encode(&UNARY_CODES, &op.op));
+                    writer.u32(op.a);
+                }
+                Node::BinOp(op) => {
+                    writer.u8(BINARY);
+                    writer.u8(encode(&BINARY_CODES, &op.op));
+                    writer.u32(op.a);
+                    writer.u32(op.b);
+                }
+                Node::IfPositive(op) => {
+                    writer.u8(IF_POSITIVE);
+                    writer.u32(op.cond);
+                    writer.u32(op.then);
+                    writer.u32(op.otherwise);
+                }
+            }
+        }
+        writer.bytes
+    }
+
+    /// Loads what `to_bytes` saved. Children have to come before
+    /// their parents, so a valid file can't contain a cycle.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Saved<Expr>, FormatError> {
+        let (mut reader, n_inputs, names) = Reader::new(bytes, EXPR_MAGIC)?;
+
+        let n_nodes = reader.u32()?;
+        let mut expr = Expr::new(n_inputs);
+        for i in 0..n_nodes {
+            let node = match reader.u8()? {
+                NUMBER => Node::Number(reader.f64()?),
+                VARIABLE => Node::Variable(reader.index(n_inputs)?),
+                UNARY => {
+                    let op = decode(&UNARY_CODES, reader.u8()?)?;
+                    Node::UnOp(UnOp {
+                        op,
+                        a: reader.index(i)?,
+                    })
+                }
+                BINARY => {
+                    let op = decode(&BINARY_CODES, reader.u8()?)?;
+                    let a = reader.index(i)?;
+                    let b = reader.index(i)?;
+                    Node::BinOp(BinOp { op, a, b })
+                }
+                IF_POSITIVE => Node::IfPositive(IfPositive {
+                    cond: reader.index(i)?,
+                    then: reader.index(i)?,
+                    otherwise: reader.index(i)?,
+                }),
+                tag => return Err(FormatError::InvalidTag(tag)),
+            };
+            expr.nodes.push(node);
+        }
+        reader.finish()?;
+
+        if n_nodes == 0 {
+            return Err(FormatError::InvalidIndex(0));
+        }
+        expr.root = n_nodes - 1;
+
+        Ok(Saved {
+            model: expr,
+            n_inputs,
+            names,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expr_round_trip() {
+        let names = ["a", "b", "c"];
+        for _ in 0..1_000 {
+            let mut expr = Expr::new(3);
+            expr.random_tree(8);
+            let expr = expr.mutate(0.5);
+
+            let bytes = expr.to_bytes(&names);
+            let saved = Expr::from_bytes(&bytes).unwrap();
+            assert_eq!(saved.model.rpn(), expr.rpn());
+            assert_eq!(saved.n_inputs, 3);
+            assert_eq!(saved.names, names);
+        }
+    }
+
+    #[test]
+    fn expr_validation() {
+        let expr = Expr::from_infix("sin(x) + 2", 1, &["x"]).unwrap();
+        let bytes = expr.to_bytes(&["x"]);
+        assert_eq!(&bytes[..4], b"SREX");
+        assert_eq!(bytes.len(), 4 + 2 + 4 + 4 + 5 + 4 + 5 + 6 + 9 + 10);
+
+        let mut bad = bytes.clone();
+        bad[0] = b'X';
+        assert_eq!(Expr::from_bytes(&bad).unwrap_err(), FormatError::BadMagic);
+
+        let mut bad = bytes.clone();
+        bad[4] = 2;
+        assert_eq!(
+            Expr::from_bytes(&bad).unwrap_err(),
+            FormatError::UnsupportedVersion(2)
+        );
+
+        for len in 0..bytes.len() {
+            assert!(Expr::from_bytes(&bytes[..len]).is_err());
+        }
+
+        let mut bad = bytes.clone();
+        bad.push(0);
+        assert_eq!(
+            Expr::from_bytes(&bad).unwrap_err(),
+            FormatError::TrailingBytes(1)
+        );
+
+        // Nodes: x, sin, 2, +. Point `+` at itself.
+        let mut bad = bytes.clone();
+        let add = bytes.len() - 10;
+        assert_eq!(bad[add], BINARY);
+        bad[add + 6] = 3;
+        assert_eq!(
+            Expr::from_bytes(&bad).unwrap_err(),
+            FormatError::InvalidIndex(3)
+        );
+
+        // Make `x` the variable `$1`
+        let mut bad = bytes.clone();
+        bad[24] = 1;
+        assert_eq!(
+            Expr::from_bytes(&bad).unwrap_err(),
+            FormatError::InvalidIndex(1)
+        );
+
+        let mut bad = bytes;
+        bad[add + 1] = 42;
+        assert_eq!(
+            Expr::from_bytes(&bad).unwrap_err(),
+            FormatError::InvalidTag(42)
+        );
+    }
+}
@@ -0,0 +1,530 @@
+use std::cmp::Ordering;
+
+use crate::{
+    dual::Scalar,
+    expr::{BinOp, BinaryOp, Expr, IfPositive, Node, UnOp, UnaryOp},
+    interval::Interval,
+};
+
+impl Expr {
+    /// Algebraic simplification. Folds constants, removes identities
+    /// and double negations, collects like terms and puts the operands
+    /// of commutative operators in a canonical order.
+    ///
+    /// Rules like `x * 0 = 0`, `x - x = 0` and `x / x = 1` only hold
+    /// for finite `x`, nonzero for division, so they are only applied
+    /// where interval arithmetic proves that for any finite inputs. The
+    /// result is NaN where the original is, as long as no intermediate
+    /// value is infinite. Signed zeros are not kept, which can flip the
+    /// sign of a division by zero, and reassociating constants may change
+    /// the last bits of the result or whether it overflows.
+    pub fn simplify(&self) -> Expr {
+        let mut simplifier = Simplifier {
+            out: Expr::new(self.n_inputs),
+            inputs: vec![Interval::new(-f64::MAX, f64::MAX); self.n_inputs],
+        };
+        let root = simplifier.simplify(self, self.root);
+        simplifier.out.root = root;
+        simplifier.out.compact();
+        simplifier.out
+    }
+}
+
+struct Simplifier {
+    out: Expr,
+
+    // Any finite value, for every input.
+    inputs: Vec<Interval>,
+}
+
+impl Simplifier {
+    fn simplify(&mut self, expr: &Expr, node: usize) -> usize {
+        match &expr.nodes[node] {
+            Node::Number(x) => self.number(*x),
+            Node::Variable(ptr) => self.push(Node::Variable(*ptr)),
+            Node::UnOp(op) => {
+                let a = self.simplify(expr, op.a);
+                self.unop(op.op.clone(), a)
+            }
+            Node::BinOp(op) => {
+                let a = self.simplify(expr, op.a);
+                let b = self.simplify(expr, op.b);
+                self.binop(op.op.clone(), a, b)
+            }
+            Node::IfPositive(op) => {
+                let cond = self.simplify(expr, op.cond);
+                let then = self.simplify(expr, op.then);
+                let otherwise = self.simplify(expr, op.otherwise);
+                self.if_positive(cond, then, otherwise)
+            }
+        }
+    }
+
+    fn push(&mut self, node: Node) -> usize {
+        self.out.nodes.push(node);
+        self.out.nodes.len() - 1
+    }
+
+    fn number(&mut self, x: f64) -> usize {
+        self.push(Node::Number(x))
+    }
+
+    fn as_number(&self, node: usize) -> Option<f64> {
+        match self.out.nodes[node] {
+            Node::Number(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    fn is_number(&self, node: usize, x: f64) -> bool {
+        self.as_number(node) == Some(x)
+    }
+
+    fn as_unop(&self, node: usize, op: UnaryOp) -> Option<usize> {
+        match &self.out.nodes[node] {
+            Node::UnOp(unop) if unop.op == op => Some(unop.a),
+            _ => None,
+        }
+    }
+
+    fn as_binop(&self, node: usize, op: BinaryOp) -> Option<(usize, usize)> {
+        match &self.out.nodes[node] {
+            Node::BinOp(binop) if binop.op == op => Some((binop.a, binop.b)),
+            _ => None,
+        }
+    }
+
+    fn same(&self, a: usize, b: usize) -> bool {
+        self.out.subtree_eq(a, &self.out, b)
+    }
+
+    fn bounds(&self, node: usize) -> Interval {
+        self.out
+            .eval_scalar(node, &self.inputs, &|_, c| Interval::constant(c))
+    }
+
+    // Is `node` finite for all finite inputs?
+    fn is_finite(&self, node: usize) -> bool {
+        self.bounds(node).is_valid()
+    }
+
+    // Is `node` finite and nonzero for all finite inputs?
+    fn is_finite_nonzero(&self, node: usize) -> bool {
+        let bounds = self.bounds(node);
+        bounds.is_valid() && !bounds.contains(0.0)
+    }
+
+    fn unop(&mut self, op: UnaryOp, a: usize) -> usize {
+        if let Some(x) = self.as_number(a) {
+            return self.number(op.apply(x));
+        }
+
+        match op {
+            UnaryOp::Neg => {
+                // --x = x
+                if let Some(x) = self.as_unop(a, UnaryOp::Neg) {
+                    return x;
+                }
+                // -(x - y) = y - x
+                if let Some((x, y)) = self.as_binop(a, BinaryOp::Sub) {
+                    return self.binop(BinaryOp::Sub, y, x);
+                }
+                // -(c * x) = -c * x
+                if let Some((c, x)) = self.as_binop(a, BinaryOp::Mul) {
+                    if let Some(c) = self.as_number(c) {
+                        let c = self.number(-c);
+                        return self.binop(BinaryOp::Mul, c, x);
+                    }
+                }
+            }
+            UnaryOp::Abs => {
+                // ||x|| = |x|
+                if self.as_unop(a, UnaryOp::Abs).is_some() {
+                    return a;
+                }
+                // |-x| = |x|
+                if let Some(x) = self.as_unop(a, UnaryOp::Neg) {
+                    return self.unop(UnaryOp::Abs, x);
+                }
+            }
+            _ => (),
+        }
+
+        self.push(Node::UnOp(UnOp { op, a }))
+    }
+
+    fn binop(&mut self, op: BinaryOp, a: usize, b: usize) -> usize {
+        if let (Some(x), Some(y)) = (self.as_number(a), self.as_number(b)) {
+            return self.number(op.apply(x, y));
+        }
+
+        let (a, b) = match op {
+            BinaryOp::Add | BinaryOp::Mul if self.order(a, b) == Ordering::Greater => (b, a),
+            _ => (a, b),
+        };
+
+        // Constants are ordered first, so for
+        // commutative operators only `a` can be one.
+        match op {
+            BinaryOp::Add => {
+                if self.is_number(a, 0.0) {
+                    return b;
+                }
+                if let Some(y) = self.as_unop(b, UnaryOp::Neg) {
+                    return self.binop(BinaryOp::Sub, a, y);
+                }
+                if let Some(y) = self.as_unop(a, UnaryOp::Neg) {
+                    return self.binop(BinaryOp::Sub, b, y);
+                }
+                // c1 + (c2 + x) = (c1 + c2) + x
+                if let Some(c1) = self.as_number(a) {
+                    if let Some((c2, x)) = self.as_binop(b, BinaryOp::Add) {
+                        if let Some(c2) = self.as_number(c2).filter(|c2| (c1 + c2).is_finite()) {
+                            let c = self.number(c1 + c2);
+                            return self.binop(BinaryOp::Add, c, x);
+                        }
+                    }
+                }
+
+                let (ca, ta) = self.term(a);
+                let (cb, tb) = self.term(b);
+                if self.same(ta, tb) && (ca + cb).is_finite() && self.is_finite(ta) {
+                    return self.scale(ca + cb, ta);
+                }
+            }
+            BinaryOp::Sub => {
+                if self.is_number(b, 0.0) {
+                    return a;
+                }
+                if self.is_number(a, 0.0) {
+                    return self.unop(UnaryOp::Neg, b);
+                }
+                if let Some(y) = self.as_unop(b, UnaryOp::Neg) {
+                    return self.binop(BinaryOp::Add, a, y);
+                }
+
+                let (ca, ta) = self.term(a);
+                let (cb, tb) = self.term(b);
+                if self.same(ta, tb) && (ca - cb).is_finite() && self.is_finite(ta) {
+                    return self.scale(ca - cb, ta);
+                }
+            }
+            BinaryOp::Mul => {
+                if self.is_number(a, 0.0) && self.is_finite(b) {
+                    return a;
+                }
+                if self.is_number(a, 1.0) {
+                    return b;
+                }
+                if self.is_number(a, -1.0) {
+                    return self.unop(UnaryOp::Neg, b);
+                }
+                if let Some(c1) = self.as_number(a) {
+                    // c1 * (c2 * x) = (c1 * c2) * x
+                    if let Some((c2, x)) = self.as_binop(b, BinaryOp::Mul) {
+                        let c2 = self.as_number(c2).filter(|c2| {
+                            let c = c1 * c2;
+                            c.is_finite() && c != 0.0
+                        });
+                        if let Some(c2) = c2 {
+                            let c = self.number(c1 * c2);
+                            return self.binop(BinaryOp::Mul, c, x);
+                        }
+                    }
+                    // c * -x = -c * x
+                    if let Some(x) = self.as_unop(b, UnaryOp::Neg) {
+                        let c = self.number(-c1);
+                        return self.binop(BinaryOp::Mul, c, x);
+                    }
+                }
+                // -x * -y = x * y
+                if let (Some(x), Some(y)) =
+                    (self.as_unop(a, UnaryOp::Neg), self.as_unop(b, UnaryOp::Neg))
+                {
+                    return self.binop(BinaryOp::Mul, x, y);
+                }
+                if self.same(a, b) {
+                    let two = self.number(2.0);
+                    return self.binop(BinaryOp::Pow, a, two);
+                }
+            }
+            BinaryOp::Div => {
+                if self.is_number(b, 1.0) {
+                    return a;
+                }
+                if self.is_number(b, -1.0) {
+                    return self.unop(UnaryOp::Neg, a);
+                }
+                if self.is_number(a, 0.0) && self.is_finite_nonzero(b) {
+                    return a;
+                }
+                if self.same(a, b) && self.is_finite_nonzero(a) {
+                    return self.number(1.0);
+                }
+                // -x / -y = x / y
+                if let (Some(x), Some(y)) =
+                    (self.as_unop(a, UnaryOp::Neg), self.as_unop(b, UnaryOp::Neg))
+                {
+                    return self.binop(BinaryOp::Div, x, y);
+                }
+            }
+            BinaryOp::Pow => {
+                if self.is_number(b, 1.0) {
+                    return a;
+                }
+                if self.is_number(b, 0.0) || self.is_number(a, 1.0) {
+                    return self.number(1.0);
+                }
+            }
+            BinaryOp::Lt | BinaryOp::Gt => {
+                if self.same(a, b) {
+                    return self.number(0.0);
+                }
+            }
+            BinaryOp::Min | BinaryOp::Max => {
+                if self.same(a, b) {
+                    return a;
+                }
+            }
+        }
+
+        self.push(Node::BinOp(BinOp { op, a, b }))
+    }
+
+    fn if_positive(&mut self, cond: usize, then: usize, otherwise: usize) -> usize {
+        if let Some(c) = self.as_number(cond) {
+            return if c > 0.0 { then } else { otherwise };
+        }
+        if self.same(then, otherwise) {
+            return then;
+        }
+        self.push(Node::IfPositive(IfPositive {
+            cond,
+            then,
+            otherwise,
+        }))
+    }
+
+    // Splits `node` into a constant coefficient and a term.
+    fn term(&self, node: usize) -> (f64, usize) {
+        if let Some((c, x)) = self.as_binop(node, BinaryOp::Mul) {
+            if let Some(c) = self.as_number(c) {
+                return (c, x);
+            }
+        }
+        if let Some(x) = self.as_unop(node, UnaryOp::Neg) {
+            return (-1.0, x);
+        }
+        (1.0, node)
+    }
+
+    fn scale(&mut self, c: f64, node: usize) -> usize {
+        let c = self.number(c);
+        self.binop(BinaryOp::Mul, c, node)
+    }
+
+    // Canonical order of operands: constants, variables,
+    // unary and then binary operators.
+    fn order(&self, a: usize, b: usize) -> Ordering {
+        fn kind(node: &Node) -> u8 {
+            match node {
+                Node::Number(_) => 0,
+                Node::Variable(_) => 1,
+                Node::UnOp(_) => 2,
+                Node::BinOp(_) => 3,
+                Node::IfPositive(_) => 4,
+            }
+        }
+
+        let (x, y) = (&self.out.nodes[a], &self.out.nodes[b]);
+        match (x, y) {
+            (Node::Number(x), Node::Number(y)) => x.total_cmp(y),
+            (Node::Variable(x), Node::Variable(y)) => x.cmp(y),
+            (Node::UnOp(x), Node::UnOp(y)) => (x.op.clone() as u8)
+                .cmp(&(y.op.clone() as u8))
+                .then_with(|| self.order(x.a, y.a)),
+            (Node::BinOp(x), Node::BinOp(y)) => (x.op.clone() as u8)
+                .cmp(&(y.op.clone() as u8))
+                .then_with(|| self.order(x.a, y.a))
+                .then_with(|| self.order(x.b, y.b)),
+            (Node::IfPositive(x), Node::IfPositive(y)) => self
+                .order(x.cond, y.cond)
+                .then_with(|| self.order(x.then, y.then))
+                .then_with(|| self.order(x.otherwise, y.otherwise)),
+            _ => kind(x).cmp(&kind(y)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn var(expr: &mut Expr, i: usize) -> usize {
+        expr.nodes.push(Node::Variable(i));
+        expr.nodes.len() - 1
+    }
+
+    fn num(expr: &mut Expr, x: f64) -> usize {
+        expr.nodes.push(Node::Number(x));
+        expr.nodes.len() - 1
+    }
+
+    fn bin(expr: &mut Expr, op: BinaryOp, a: usize, b: usize) -> usize {
+        expr.nodes.push(Node::BinOp(BinOp { op, a, b }));
+        expr.nodes.len() - 1
+    }
+
+    fn un(expr: &mut Expr, op: UnaryOp, a: usize) -> usize {
+        expr.nodes.push(Node::UnOp(UnOp { op, a }));
+        expr.nodes.len() - 1
+    }
+
+    #[test]
+    fn rules() {
+        // ((x0 + 0) * 1) - (x0 * 3)
+        let mut expr = Expr::new(2);
+        let x0 = var(&mut expr, 0);
+        let zero = num(&mut expr, 0.0);
+        let one = num(&mut expr, 1.0);
+        let three = num(&mut expr, 3.0);
+        let add = bin(&mut expr, BinaryOp::Add, x0, zero);
+        let mul = bin(&mut expr, BinaryOp::Mul, add, one);
+        let mul3 = bin(&mut expr, BinaryOp::Mul, x0, three);
+        expr.root = bin(&mut expr, BinaryOp::Sub, mul, mul3);
+        assert_eq!(expr.simplify().rpn(), "-2.00 $0 *");
+
+        // --sin(x1) + (2 + 3)
+        let mut expr = Expr::new(2);
+        let x1 = var(&mut expr, 1);
+        let sin = un(&mut expr, UnaryOp::Sin, x1);
+        let neg = un(&mut expr, UnaryOp::Neg, sin);
+        let neg = un(&mut expr, UnaryOp::Neg, neg);
+        let two = num(&mut expr, 2.0);
+        let three = num(&mut expr, 3.0);
+        let five = bin(&mut expr, BinaryOp::Add, two, three);
+        expr.root = bin(&mut expr, BinaryOp::Add, neg, five);
+        assert_eq!(expr.simplify().rpn(), "5.00 $1 sin +");
+
+        // (x1 - x1) * x0 ^ 1
+        let mut expr = Expr::new(2);
+        let x0 = var(&mut expr, 0);
+        let x1 = var(&mut expr, 1);
+        let one = num(&mut expr, 1.0);
+        let sub = bin(&mut expr, BinaryOp::Sub, x1, x1);
+        let pow = bin(&mut expr, BinaryOp::Pow, x0, one);
+        expr.root = bin(&mut expr, BinaryOp::Mul, sub, pow);
+        assert_eq!(expr.simplify().rpn(), "0.00");
+
+        // x1 * x0 and x0 * x1 end up the same
+        let mut a = Expr::new(2);
+        let x0 = var(&mut a, 0);
+        let x1 = var(&mut a, 1);
+        a.root = bin(&mut a, BinaryOp::Mul, x1, x0);
+        let mut b = Expr::new(2);
+        let x0 = var(&mut b, 0);
+        let x1 = var(&mut b, 1);
+        b.root = bin(&mut b, BinaryOp::Mul, x0, x1);
+        assert_eq!(a.simplify(), b.simplify());
+
+        let names = ["x0", "x1"];
+        let simplify = |src| Expr::from_infix(src, 2, &names).unwrap().simplify();
+        assert_eq!(simplify("if_positive(1 - 3, x0, x1 * 1)").rpn(), "$1");
+        assert_eq!(
+            simplify("if_positive(x0, sin(x1), sin(x1))").rpn(),
+            "$1 sin"
+        );
+        assert_eq!(simplify("max(x0, x0) + (x1 < x1)").rpn(), "$0");
+
+        // Cancelling needs a finite operand, and a nonzero one to divide by
+        assert_eq!(simplify("sin(x0) * 0 + (x1 - x1)").rpn(), "0.00");
+        assert_eq!(simplify("(cos(x0) + 2) / (cos(x0) + 2)").rpn(), "1.00");
+        assert_eq!(simplify("0 / (sin(x0) - 2)").rpn(), "0.00");
+        for src in [
+            "(x0 - 1) / (x0 - 1)",
+            "0 / (x0 - 1)",
+            "ln(x0) * 0",
+            "ln(x0) - ln(x0)",
+            "x0 * x1 * 0",
+        ] {
+            assert_ne!(simplify(src).nodes.len(), 1, "{src}");
+        }
+    }
+
+    #[test]
+    fn equivalence() {
+        let n_vars = 3;
+        let close = |a: f64, b: f64| (a - b).abs() <= 1e-6 * a.abs().max(1.0);
+
+        let names = ["x0", "x1", "x2"];
+        for (src, x) in [
+            ("(x0 - 1) / (x0 - 1)", 1.0),
+            ("0 / (x0 - 1)", 1.0),
+            ("ln(x0) * 0", -1.0),
+            ("ln(x0) - ln(x0)", -1.0),
+            ("x0 * x0 * 0", 1e300),
+        ] {
+            let expr = Expr::from_infix(src, n_vars, &names).unwrap();
+            let simplified = expr.simplify();
+            assert!(expr.evaluate(&[x, 0.0, 0.0]).is_nan(), "{src}");
+            assert!(simplified.evaluate(&[x, 0.0, 0.0]).is_nan(), "{src}");
+        }
+
+        for _ in 0..10_000 {
+            let vars: Vec<f64> = (0..n_vars).map(|_| rand::random::<f64>()).collect();
+            let mut expr = Expr::new(n_vars);
+            expr.random_tree(5);
+            let simplified = expr.simplify();
+
+            let a = expr.evaluate(&vars);
+            let b = simplified.evaluate(&vars);
+            let values: Vec<f64> = expr
+                .subtree(expr.root)
+                .into_iter()
+                .map(|i| {
+                    let mut subexpr = expr.clone();
+                    subexpr.root = i;
+                    subexpr.evaluate(&vars)
+                })
+                .collect();
+
+            // Signed zeros are not preserved, which only
+            // matters once something is divided by them.
+            if values.iter().any(|x| x.is_infinite()) {
+                continue;
+            }
+            assert_eq!(
+                a.is_nan(),
+                b.is_nan(),
+                "{} = {a}, {} = {b}",
+                expr.rpn(),
+                simplified.rpn()
+            );
+
+            // Comparisons can hide NaN in any subexpression.
+            if values.iter().any(|x| x.is_nan()) {
+                continue;
+            }
+            assert!(
+                b.is_finite(),
+                "{} = {a}, {} = {b}",
+                expr.rpn(),
+                simplified.rpn()
+            );
+
+            // Reassociating constants changes rounding, which
+            // ill-conditioned expressions blow way out of proportion.
+            let nudged: Vec<f64> = vars.iter().map(|x| x * (1.0 + 1e-12)).collect();
+            if !close(a, expr.evaluate(&nudged)) {
+                continue;
+            }
+
+            assert!(
+                close(a, b),
+                "{} = {a}, {} = {b}",
+                expr.rpn(),
+                simplified.rpn()
+            );
+        }
+    }
+}
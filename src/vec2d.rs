@@ -1,5 +1,6 @@
 use std::collections::HashMap;
 
+/// Iterator over copies of the rows of a [`Vec2d`].
 #[derive(Debug, Clone)]
 pub struct Iter<T> {
     container: Vec2d<T>,
@@ -7,6 +8,7 @@ pub struct Iter<T> {
 }
 
 impl<T> Iter<T> {
+    /// Starts at the first row of `container`.
     pub fn new(container: Vec2d<T>) -> Iter<T> {
         Iter { container, pos: 0 }
     }
@@ -15,12 +17,13 @@ impl<T> Iter<T> {
 impl<T: Copy> Iterator for Iter<T> {
     type Item = Vec<T>;
     fn next(&mut self) -> Option<Self::Item> {
-        let item = Some(Vec::from(self.container.get_row(0)?));
+        let item = Some(Vec::from(self.container.get_row(self.pos)?));
         self.pos += 1;
         item
     }
 }
 
+/// Table stored row by row, with `dim` columns.
 #[derive(Debug, Clone)]
 pub struct Vec2d<T> {
     vec: Vec<T>,
@@ -28,6 +31,7 @@ pub struct Vec2d<T> {
 }
 
 impl<T: Copy> Vec2d<T> {
+    /// An empty table with `dim` columns.
     pub fn new(dim: usize) -> Vec2d<T> {
         Vec2d {
             vec: Vec::new(),
@@ -35,10 +39,12 @@ impl<T: Copy> Vec2d<T> {
         }
     }
 
+    /// The element in `row` and `col`.
     pub fn idx(&self, row: usize, col: usize) -> T {
-        self.vec[col * self.dim + row]
+        self.vec[row * self.dim + col]
     }
 
+    /// Row `idx`, if it's complete.
     pub fn get_row(&self, idx: usize) -> Option<&[T]> {
         let row_start = idx * self.dim;
         let row_end = row_start + self.dim;
@@ -48,6 +54,7 @@ impl<T: Copy> Vec2d<T> {
         Some(&self.vec[row_start..row_end])
     }
 
+    /// A copy of column `idx`.
     pub fn get_col(&self, idx: usize) -> Option<Vec<T>> {
         if idx >= self.dim {
             return None;
@@ -65,39 +72,40 @@ impl<T: Copy> Vec2d<T> {
         Some(res)
     }
 
+    /// Rows and columns.
     pub fn shape(&self) -> (usize, usize) {
         (self.vec.len() / self.dim, self.dim)
     }
 
+    /// Appends one element, rows are filled in order.
     pub fn push(&mut self, item: T) {
         self.vec.push(item);
     }
 
+    /// Removes the last element.
     pub fn pop(&mut self) -> Option<T> {
         self.vec.pop()
     }
 
+    /// Removes the first row, e.g. a CSV header.
     pub fn pop_head(&mut self) -> Vec<T> {
         let (_rows, cols) = self.shape();
-        let mut res = Vec::new();
-        for i in (0..cols).rev() {
-            let e = self.vec.remove(i);
-            res.push(e);
-        }
-
-        res
+        self.vec.drain(..cols).collect()
     }
 
+    /// Appends the elements, rows are filled in order.
     pub fn push_slice(&mut self, items: &[T]) {
         for e in items {
             self.vec.push(*e);
         }
     }
 
+    /// Iterates over copies of the rows.
     pub fn into_iter(&self) -> Iter<T> {
         Iter::new(self.clone())
     }
 
+    /// Splits off the last column, e.g. the target.
     pub fn split_right(&self) -> (Vec2d<T>, Vec<T>) {
         let (rows, cols) = self.shape();
         let mut left_cols = Vec2d::<T>::new(cols - 1);
@@ -115,6 +123,7 @@ impl<T: Copy> Vec2d<T> {
         (left_cols, right_col)
     }
 
+    /// Splits off the first column.
     pub fn split_left(&self) -> (Vec<T>, Vec2d<T>) {
         let (rows, cols) = self.shape();
         let mut right_cols = Vec2d::<T>::new(cols - 1);
@@ -134,16 +143,19 @@ impl<T: Copy> Vec2d<T> {
 }
 
 impl<T: Copy + std::fmt::Debug> Vec2d<T> {
+    /// Prints up to the first 10 rows.
     pub fn print_head(&self) {
         let (rows, _cols) = self.shape();
         for i_row in 0..rows.min(10) {
             let row = self.get_row(i_row).unwrap();
             row.iter().for_each(|e| print!("{e:?}, "));
-            println!("");
+            println!();
         }
     }
 }
 
+/// Converts a table of strings to numbers. Values that don't parse
+/// are categories, numbered per column in order of appearance.
 // This is much easier as a non-generic function
 pub fn categorize_cols(input: Vec2d<&str>) -> Vec2d<f64> {
     let (rows, cols) = input.shape();
@@ -186,3 +198,27 @@ pub fn categorize_cols(input: Vec2d<&str>) -> Vec2d<f64> {
 
     vec2d_f64
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pop_head() {
+        let mut table = Vec2d::new(3);
+        table.push_slice(&["a", "b", "c", "1", "2", "3"]);
+        assert_eq!(table.pop_head(), ["a", "b", "c"]);
+        assert_eq!(table.get_row(0), Some(&["1", "2", "3"][..]));
+        assert_eq!(table.shape(), (1, 3));
+    }
+
+    #[test]
+    fn rows() {
+        let mut table = Vec2d::new(2);
+        table.push_slice(&[1, 2, 3, 4, 5, 6]);
+        assert_eq!(table.idx(2, 0), 5);
+        assert_eq!(table.idx(0, 1), 2);
+        let rows: Vec<_> = table.into_iter().collect();
+        assert_eq!(rows, [[1, 2], [3, 4], [5, 6]]);
+    }
+}
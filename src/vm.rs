@@ -1,12 +1,34 @@
-use crate::expr::{BinaryOp, Expr, Node, UnaryOp};
+use std::{error::Error, fmt};
 
-#[derive(Debug, Clone)]
+use crate::{
+    dual::Scalar,
+    expr::{BinaryOp, Expr, Node, UnaryOp},
+    vec2d::Vec2d,
+};
+
+/// Reading and writing programs as assembly.
+pub mod asm;
+mod binary;
+/// Generating C source for programs.
+pub mod c_source;
+/// Compiling programs to native code.
+pub mod jit;
+mod peephole;
+/// A register machine, which computes shared subexpressions once.
+pub mod register;
+mod wasm;
+
+// Rows evaluated at once by `evaluate_batch`, small enough
+// for the whole stack of a block to stay in the cache.
+const BLOCK_SIZE: usize = 256;
+
+#[derive(Debug, Clone, PartialEq)]
 enum Value {
     Literal(f64),
     Ptr(usize),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 enum BuiltinFunction {
     Abs,
     Loge,
@@ -17,7 +39,7 @@ enum BuiltinFunction {
     Tan,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 enum Op {
     // Push value onto the stack
     Push(Value),
@@ -30,6 +52,14 @@ enum Op {
     Div, // Divide
     Pow, // Power
 
+    // Branch-free comparisons. Like the `Expr` nodes, `Lt` and `Gt`
+    // give 1 or 0, and `Min` and `Max` give the second operand
+    // when the comparison is false, e.g. for NaN.
+    Min, // `a < b ? a : b`
+    Max, // `a > b ? a : b`
+    Lt,  // Less than
+    Gt,  // Greater than
+
     // Unary operators. Operate on top
     // of stack.
     Neg, // Negate
@@ -50,14 +80,81 @@ enum Op {
     Call(BuiltinFunction),
 }
 
+/// Bytecode for a stack machine, compiled from an [`Expr`] by [`compile_expr`].
 #[derive(Debug, Clone)]
 pub struct Program {
     ops: Vec<Op>,
     jump_table: Vec<usize>,
 }
 
+/// What makes a program malformed, with the
+/// index of the offending op where there is one.
+#[derive(Debug, Clone, PartialEq)]
+pub enum VmError {
+    /// An op takes more values than there are on the stack
+    StackUnderflow {
+        /// The op, or the end of the program for an empty stack
+        pc: usize,
+    },
+
+    /// A label missing from the jump table, an entry that isn't a
+    /// `Label`, or a jump backwards, which could loop forever
+    BadJump {
+        /// The jump
+        pc: usize,
+        /// Its label, an index into the jump table
+        label: usize,
+    },
+
+    /// A variable beyond the inputs
+    OutOfBounds {
+        /// The push of the variable
+        pc: usize,
+        /// The index of the variable
+        ptr: usize,
+    },
+
+    /// Paths merging at `pc` with different stack depths
+    StackMismatch {
+        /// Where the paths merge
+        pc: usize,
+    },
+
+    /// Values left on the stack besides the result
+    LeftoverStack(usize),
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmError::StackUnderflow { pc } => write!(f, "stack underflow at op {pc}"),
+            VmError::BadJump { pc, label } => write!(f, "bad jump to label {label} at op {pc}"),
+            VmError::OutOfBounds { pc, ptr } => {
+                write!(f, "variable ${ptr} is out of range at op {pc}")
+            }
+            VmError::StackMismatch { pc } => write!(f, "stack depths differ at op {pc}"),
+            VmError::LeftoverStack(n) => write!(f, "{n} values left on the stack"),
+        }
+    }
+}
+
+impl Error for VmError {}
+
 impl Program {
-    pub fn evaluate(&self, inputs: &[f64]) -> Option<f64> {
+    /// The value for one row of inputs.
+    pub fn evaluate(&self, inputs: &[f64]) -> Result<f64, VmError> {
+        self.evaluate_scalar(inputs, &|_, x| x)
+    }
+
+    /// Runs the program over any `Scalar`, e.g. dual numbers for the
+    /// gradient along with the value. Literals are mapped through
+    /// `literal`, which also gets the index of their `Push` op, so
+    /// they can be seeded as parameters. Jumps branch on the value.
+    pub fn evaluate_scalar<T: Scalar>(
+        &self,
+        inputs: &[T],
+        literal: &impl Fn(usize, f64) -> T,
+    ) -> Result<T, VmError> {
         let mut vm = VM {
             pc: 0,
             stack: Vec::with_capacity(20),
@@ -74,94 +171,120 @@ impl Program {
 
             match op {
                 Op::Push(v) => match v {
-                    Value::Literal(x) => vm.stack.push(*x),
-                    Value::Ptr(x) => vm.stack.push(vm.memory[*x]),
+                    Value::Literal(x) => vm.stack.push(literal(vm.pc, *x)),
+                    Value::Ptr(x) => {
+                        let value = vm
+                            .memory
+                            .get(*x)
+                            .ok_or(VmError::OutOfBounds { pc: vm.pc, ptr: *x })?;
+                        vm.stack.push(value.clone());
+                    }
                 },
                 Op::Add => {
-                    let b = vm.stack.pop()?;
-                    let a = vm.stack.pop()?;
+                    let b = vm.pop()?;
+                    let a = vm.pop()?;
                     vm.stack.push(a + b);
                 }
                 Op::Sub => {
-                    let b = vm.stack.pop()?;
-                    let a = vm.stack.pop()?;
+                    let b = vm.pop()?;
+                    let a = vm.pop()?;
                     vm.stack.push(a - b);
                 }
                 Op::Mul => {
-                    let b = vm.stack.pop()?;
-                    let a = vm.stack.pop()?;
+                    let b = vm.pop()?;
+                    let a = vm.pop()?;
                     vm.stack.push(a * b);
                 }
                 Op::Div => {
-                    let b = vm.stack.pop()?;
-                    let a = vm.stack.pop()?;
+                    let b = vm.pop()?;
+                    let a = vm.pop()?;
                     vm.stack.push(a / b);
                 }
                 Op::Pow => {
-                    let b = vm.stack.pop()?;
-                    let a = vm.stack.pop()?;
+                    let b = vm.pop()?;
+                    let a = vm.pop()?;
                     vm.stack.push(a.powf(b));
                 }
+                Op::Min => {
+                    let b = vm.pop()?;
+                    let a = vm.pop()?;
+                    vm.stack.push(a.select_min(b));
+                }
+                Op::Max => {
+                    let b = vm.pop()?;
+                    let a = vm.pop()?;
+                    vm.stack.push(a.select_max(b));
+                }
+                Op::Lt => {
+                    let b = vm.pop()?;
+                    let a = vm.pop()?;
+                    vm.stack.push(a.less(b));
+                }
+                Op::Gt => {
+                    let b = vm.pop()?;
+                    let a = vm.pop()?;
+                    vm.stack.push(a.greater(b));
+                }
                 Op::Neg => {
-                    let a = vm.stack.pop()?;
+                    let a = vm.pop()?;
                     vm.stack.push(-a);
                 }
                 Op::Dup => {
-                    let a = vm.stack.pop()?;
-                    vm.stack.push(a);
+                    let a = vm.pop()?;
+                    vm.stack.push(a.clone());
                     vm.stack.push(a);
                 }
                 Op::Jmp(label) => {
-                    vm.pc = self.jump_table[*label];
+                    vm.pc = self.jump_target(vm.pc, *label)?;
                 }
                 Op::Je(label) => {
-                    if vm.stack.pop()? == 0.0 {
-                        vm.pc = self.jump_table[*label];
+                    if vm.pop()?.value() == 0.0 {
+                        vm.pc = self.jump_target(vm.pc, *label)?;
                     }
                 }
                 Op::Jne(label) => {
-                    if vm.stack.pop()? != 0.0 {
-                        vm.pc = self.jump_table[*label];
+                    if vm.pop()?.value() != 0.0 {
+                        vm.pc = self.jump_target(vm.pc, *label)?;
                     }
                 }
                 Op::Js(label) => {
-                    if vm.stack.pop()? < 0.0 {
-                        vm.pc = self.jump_table[*label];
+                    if vm.pop()?.value() < 0.0 {
+                        vm.pc = self.jump_target(vm.pc, *label)?;
                     }
                 }
                 Op::Jns(label) => {
-                    if vm.stack.pop()? >= 0.0 {
-                        vm.pc = self.jump_table[*label];
+                    if vm.pop()?.value() >= 0.0 {
+                        vm.pc = self.jump_target(vm.pc, *label)?;
                     }
                 }
                 Op::Label => (),
                 Op::Call(f) => match f {
                     BuiltinFunction::Abs => {
-                        let a = vm.stack.pop()?;
+                        let a = vm.pop()?;
                         vm.stack.push(a.abs());
                     }
                     BuiltinFunction::Loge => {
-                        let a = vm.stack.pop()?;
+                        let a = vm.pop()?;
                         vm.stack.push(a.ln());
                     }
                     BuiltinFunction::Log2 => {
-                        let a = vm.stack.pop()?;
+                        let a = vm.pop()?;
                         vm.stack.push(a.log2());
                     }
                     BuiltinFunction::Log10 => {
-                        let a = vm.stack.pop()?;
+                        let a = vm.pop()?;
                         vm.stack.push(a.log10());
                     }
                     BuiltinFunction::Sin => {
-                        let a = vm.stack.pop()?;
+                        let a = vm.pop()?;
                         vm.stack.push(a.sin());
                     }
                     BuiltinFunction::Cos => {
-                        let a = vm.stack.pop()?;
+                        let a = vm.pop()?;
                         vm.stack.push(a.cos());
                     }
                     BuiltinFunction::Tan => {
-                        let a = vm.stack.pop()?;
+                        let a = vm.pop()?;
                         vm.stack.push(a.tan());
                     }
                 },
@@ -170,132 +293,313 @@ impl Program {
             vm.pc += 1;
         }
 
-        assert_eq!(vm.stack.len(), 1);
-        vm.stack.pop()
+        match vm.stack.len() {
+            0 => Err(VmError::StackUnderflow { pc: self.ops.len() }),
+            1 => Ok(vm.stack.pop().unwrap()),
+            n => Err(VmError::LeftoverStack(n - 1)),
+        }
+    }
+
+    // Position of `label`, for the jump at `pc`.
+    fn jump_target(&self, pc: usize, label: usize) -> Result<usize, VmError> {
+        match self.jump_table.get(label) {
+            Some(&target) if target > pc && self.ops.get(target) == Some(&Op::Label) => Ok(target),
+            _ => Err(VmError::BadJump { pc, label }),
+        }
     }
 
-    pub fn pprint(&self) {
-        let mut label_counter = 0;
-        for op in &self.ops {
-            match op {
-                Op::Push(v) => match v {
-                    Value::Literal(x) => println!("PUSH {x}"),
-                    Value::Ptr(x) => println!("PUSH ${x}"),
-                },
-                Op::Add => println!("    ADD"),
-                Op::Sub => println!("    SUB"),
-                Op::Mul => println!("    MUL"),
-                Op::Div => println!("    DIV"),
-                Op::Pow => println!("    POW"),
-                Op::Neg => println!("    NEG"),
-                Op::Dup => println!("    DUP"),
-                Op::Jmp(l) => println!("    JMP ${l}"),
-                Op::Je(l) => println!("    JE ${l}"),
-                Op::Jne(l) => println!("    JNE ${l}"),
-                Op::Js(l) => println!("    JS ${l}"),
-                Op::Jns(l) => println!("    JNS ${l}"),
-                Op::Label => {
-                    println!("LABEL {label_counter}:");
-                    label_counter += 1;
+    /// Checks the program without running it, for `n_inputs`
+    /// variables: every op has its operands on the stack along
+    /// every path, jumps go forward to labels, and exactly one
+    /// value is left at the end. Returns the deepest the stack gets.
+    pub fn verify(&self, n_inputs: usize) -> Result<usize, VmError> {
+        let depths = self.stack_depths(n_inputs)?;
+        Ok(depths.into_iter().flatten().max().unwrap())
+    }
+
+    // Stack depth on entry to every op, and at the end, for a
+    // program that passes `verify`. Ops that no path reaches
+    // have no depth.
+    pub(crate) fn stack_depths(&self, n_inputs: usize) -> Result<Vec<Option<usize>>, VmError> {
+        // Jumps only go forward, so one pass sees them all
+        let mut depths: Vec<Option<usize>> = vec![None; self.ops.len() + 1];
+        depths[0] = Some(0);
+
+        let merge = |depths: &mut Vec<Option<usize>>, pc: usize, depth: usize| match depths[pc] {
+            Some(other) if other != depth => Err(VmError::StackMismatch { pc }),
+            _ => {
+                depths[pc] = Some(depth);
+                Ok(())
+            }
+        };
+
+        for (pc, op) in self.ops.iter().enumerate() {
+            // Code after an unconditional jump that no label leads to
+            let Some(depth) = depths[pc] else {
+                continue;
+            };
+
+            let (pops, pushes) = match op {
+                Op::Push(Value::Ptr(ptr)) if *ptr >= n_inputs => {
+                    return Err(VmError::OutOfBounds { pc, ptr: *ptr });
+                }
+                Op::Push(_) => (0, 1),
+                Op::Dup => (1, 2),
+                Op::Add | Op::Sub | Op::Mul | Op::Div | Op::Pow => (2, 1),
+                Op::Min | Op::Max | Op::Lt | Op::Gt => (2, 1),
+                Op::Neg | Op::Call(_) => (1, 1),
+                Op::Label | Op::Jmp(_) => (0, 0),
+                Op::Je(_) | Op::Jne(_) | Op::Js(_) | Op::Jns(_) => (1, 0),
+            };
+            let depth = depth
+                .checked_sub(pops)
+                .ok_or(VmError::StackUnderflow { pc })?
+                + pushes;
+
+            if let Op::Jmp(label)
+            | Op::Je(label)
+            | Op::Jne(label)
+            | Op::Js(label)
+            | Op::Jns(label) = op
+            {
+                merge(&mut depths, self.jump_target(pc, *label)?, depth)?;
+            }
+            if !matches!(op, Op::Jmp(_)) {
+                merge(&mut depths, pc + 1, depth)?;
+            }
+        }
+
+        match depths[self.ops.len()] {
+            Some(1) => Ok(depths),
+            Some(0) | None => Err(VmError::StackUnderflow { pc: self.ops.len() }),
+            Some(n) => Err(VmError::LeftoverStack(n - 1)),
+        }
+    }
+
+    /// Evaluates every row of `x`. Each op is interpreted once per
+    /// block of rows over whole column slices, which the compiler can
+    /// vectorize, instead of once per row. Programs with jumps take
+    /// the row-wise path, as their control flow differs between rows.
+    pub fn evaluate_batch(&self, x: &Vec2d<f64>) -> Result<Vec<f64>, VmError> {
+        let (rows, cols) = x.shape();
+        let depth = self.verify(cols)?;
+        if !self.jump_table.is_empty() {
+            return (0..rows)
+                .map(|i_row| self.evaluate(x.get_row(i_row).unwrap()))
+                .collect();
+        }
+
+        let mut stack = vec![0.0; depth * BLOCK_SIZE];
+        let mut columns = vec![0.0; cols * BLOCK_SIZE];
+        let mut results = Vec::with_capacity(rows);
+
+        for start in (0..rows).step_by(BLOCK_SIZE) {
+            let n = BLOCK_SIZE.min(rows - start);
+            for i in 0..n {
+                let row = x.get_row(start + i).unwrap();
+                for (i_col, value) in row.iter().enumerate() {
+                    columns[i_col * BLOCK_SIZE + i] = *value;
                 }
-                Op::Call(f) => match f {
-                    BuiltinFunction::Abs => println!("    CALL $abs"),
-                    BuiltinFunction::Loge => println!("    CALL $loge"),
-                    BuiltinFunction::Log2 => println!("    CALL $log2"),
-                    BuiltinFunction::Log10 => println!("    CALL $log10"),
-                    BuiltinFunction::Sin => println!("    CALL $sin"),
-                    BuiltinFunction::Cos => println!("    CALL $cos"),
-                    BuiltinFunction::Tan => println!("    CALL $tan"),
-                },
             }
+
+            // Stack slot `i` lives at `stack[i * BLOCK_SIZE..][..n]`
+            let mut sp = 0;
+            for op in &self.ops {
+                match op {
+                    Op::Push(v) => {
+                        let top = &mut stack[sp * BLOCK_SIZE..][..n];
+                        match v {
+                            Value::Literal(x) => top.fill(*x),
+                            Value::Ptr(x) => top.copy_from_slice(&columns[x * BLOCK_SIZE..][..n]),
+                        }
+                        sp += 1;
+                    }
+                    Op::Add => sp = binary_slots(&mut stack, sp, n, |a, b| a + b),
+                    Op::Sub => sp = binary_slots(&mut stack, sp, n, |a, b| a - b),
+                    Op::Mul => sp = binary_slots(&mut stack, sp, n, |a, b| a * b),
+                    Op::Div => sp = binary_slots(&mut stack, sp, n, |a, b| a / b),
+                    Op::Pow => sp = binary_slots(&mut stack, sp, n, f64::powf),
+                    Op::Min => sp = binary_slots(&mut stack, sp, n, f64::select_min),
+                    Op::Max => sp = binary_slots(&mut stack, sp, n, f64::select_max),
+                    Op::Lt => sp = binary_slots(&mut stack, sp, n, f64::less),
+                    Op::Gt => sp = binary_slots(&mut stack, sp, n, f64::greater),
+                    Op::Neg => unary_slot(&mut stack, sp, n, |a| -a),
+                    Op::Dup => {
+                        stack.copy_within(
+                            (sp - 1) * BLOCK_SIZE..(sp - 1) * BLOCK_SIZE + n,
+                            sp * BLOCK_SIZE,
+                        );
+                        sp += 1;
+                    }
+                    Op::Jmp(_) | Op::Je(_) | Op::Jne(_) | Op::Js(_) | Op::Jns(_) => {
+                        unreachable!("programs with jumps are evaluated row by row")
+                    }
+                    Op::Label => (),
+                    Op::Call(f) => match f {
+                        BuiltinFunction::Abs => unary_slot(&mut stack, sp, n, f64::abs),
+                        BuiltinFunction::Loge => unary_slot(&mut stack, sp, n, f64::ln),
+                        BuiltinFunction::Log2 => unary_slot(&mut stack, sp, n, f64::log2),
+                        BuiltinFunction::Log10 => unary_slot(&mut stack, sp, n, f64::log10),
+                        BuiltinFunction::Sin => unary_slot(&mut stack, sp, n, f64::sin),
+                        BuiltinFunction::Cos => unary_slot(&mut stack, sp, n, f64::cos),
+                        BuiltinFunction::Tan => unary_slot(&mut stack, sp, n, f64::tan),
+                    },
+                }
+            }
+
+            results.extend_from_slice(&stack[..n]);
         }
+
+        Ok(results)
     }
 }
 
-struct VM {
+struct VM<T> {
     pc: usize,
-    stack: Vec<f64>,
-    memory: Vec<f64>,
+    stack: Vec<T>,
+    memory: Vec<T>,
+}
+
+impl<T> VM<T> {
+    fn pop(&mut self) -> Result<T, VmError> {
+        self.stack
+            .pop()
+            .ok_or(VmError::StackUnderflow { pc: self.pc })
+    }
+}
+
+// Applies `f` to the top two stack slots of a block,
+// leaving the result in the lower one. Returns the new `sp`.
+#[inline(always)]
+fn binary_slots(stack: &mut [f64], sp: usize, n: usize, f: impl Fn(f64, f64) -> f64) -> usize {
+    let (lower, upper) = stack.split_at_mut((sp - 1) * BLOCK_SIZE);
+    let a = &mut lower[(sp - 2) * BLOCK_SIZE..][..n];
+    let b = &upper[..n];
+    for (a, b) in a.iter_mut().zip(b) {
+        *a = f(*a, *b);
+    }
+    sp - 1
+}
+
+#[inline(always)]
+fn unary_slot(stack: &mut [f64], sp: usize, n: usize, f: impl Fn(f64) -> f64) {
+    for a in &mut stack[(sp - 1) * BLOCK_SIZE..][..n] {
+        *a = f(*a);
+    }
 }
 
+/// Compiles the tree to bytecode. Conditionals become forward jumps,
+/// comparisons, `min` and `max` are ops of their own.
 pub fn compile_expr(expr: &Expr) -> Program {
-    let mut ops = Vec::new();
-    flatten_expr(&mut ops, expr, expr.root);
-    let jump_table = Vec::new();
+    let mut compiler = Compiler {
+        ops: Vec::new(),
+        labels: Vec::new(),
+        n_labels: 0,
+    };
+    compiler.flatten_expr(expr, expr.root);
+
+    // Labels are numbered by their order in the program, which
+    // nesting makes differ from the order they were allocated in.
+    let mut number = vec![0; compiler.n_labels];
+    for (i, label) in compiler.labels.iter().enumerate() {
+        number[*label] = i;
+    }
+
+    let mut ops = compiler.ops;
+    for op in &mut ops {
+        if let Op::Jmp(l) | Op::Je(l) | Op::Jne(l) | Op::Js(l) | Op::Jns(l) = op {
+            *l = number[*l];
+        }
+    }
+
+    let jump_table = ops
+        .iter()
+        .enumerate()
+        .filter(|(_, op)| matches!(op, Op::Label))
+        .map(|(i, _)| i)
+        .collect();
     Program { ops, jump_table }
 }
 
-fn flatten_expr(buf: &mut Vec<Op>, expr: &Expr, node: usize) {
-    match &expr.nodes[node] {
-        Node::Number(x) => buf.push(Op::Push(Value::Literal(*x))),
-        Node::Variable(x) => buf.push(Op::Push(Value::Ptr(*x))),
-        Node::BinOp(op) => match op.op {
-            BinaryOp::Add => {
-                flatten_expr(buf, expr, op.a);
-                flatten_expr(buf, expr, op.b);
-                buf.push(Op::Add);
-            }
-            BinaryOp::Sub => {
-                flatten_expr(buf, expr, op.a);
-                flatten_expr(buf, expr, op.b);
-                buf.push(Op::Sub)
-            }
-            BinaryOp::Mul => {
-                flatten_expr(buf, expr, op.a);
-                flatten_expr(buf, expr, op.b);
-                buf.push(Op::Mul)
-            }
-            BinaryOp::Div => {
-                flatten_expr(buf, expr, op.a);
-                flatten_expr(buf, expr, op.b);
-                buf.push(Op::Div)
-            }
-            BinaryOp::Pow => {
-                flatten_expr(buf, expr, op.a);
-                flatten_expr(buf, expr, op.b);
-                buf.push(Op::Pow)
-            }
-        },
-        Node::UnOp(op) => match op.op {
-            UnaryOp::Neg => {
-                flatten_expr(buf, expr, op.a);
-                buf.push(Op::Neg);
-            }
-            UnaryOp::Abs => {
-                flatten_expr(buf, expr, op.a);
-                buf.push(Op::Call(BuiltinFunction::Abs));
-            }
-            UnaryOp::Loge => {
-                flatten_expr(buf, expr, op.a);
-                buf.push(Op::Call(BuiltinFunction::Loge));
-            }
-            UnaryOp::Log2 => {
-                flatten_expr(buf, expr, op.a);
-                buf.push(Op::Call(BuiltinFunction::Log2));
-            }
-            UnaryOp::Log10 => {
-                flatten_expr(buf, expr, op.a);
-                buf.push(Op::Call(BuiltinFunction::Log10));
-            }
-            UnaryOp::Sin => {
-                flatten_expr(buf, expr, op.a);
-                buf.push(Op::Call(BuiltinFunction::Sin));
+struct Compiler {
+    ops: Vec<Op>,
+
+    // Allocated number of every `Label` op, in program order
+    labels: Vec<usize>,
+    n_labels: usize,
+}
+
+impl Compiler {
+    fn new_label(&mut self) -> usize {
+        self.n_labels += 1;
+        self.n_labels - 1
+    }
+
+    fn place_label(&mut self, label: usize) {
+        self.ops.push(Op::Label);
+        self.labels.push(label);
+    }
+
+    // `then` if `jump` on the top of the stack is taken, `otherwise` if not.
+    fn branch(&mut self, expr: &Expr, jump: fn(usize) -> Op, then: usize, otherwise: usize) {
+        let taken = self.new_label();
+        let end = self.new_label();
+
+        self.ops.push(jump(taken));
+        self.flatten_expr(expr, otherwise);
+        self.ops.push(Op::Jmp(end));
+        self.place_label(taken);
+        self.flatten_expr(expr, then);
+        self.place_label(end);
+    }
+
+    fn flatten_expr(&mut self, expr: &Expr, node: usize) {
+        match &expr.nodes[node] {
+            Node::Number(x) => self.ops.push(Op::Push(Value::Literal(*x))),
+            Node::Variable(x) => self.ops.push(Op::Push(Value::Ptr(*x))),
+            Node::BinOp(op) => {
+                self.flatten_expr(expr, op.a);
+                self.flatten_expr(expr, op.b);
+                match op.op {
+                    BinaryOp::Add => self.ops.push(Op::Add),
+                    BinaryOp::Sub => self.ops.push(Op::Sub),
+                    BinaryOp::Mul => self.ops.push(Op::Mul),
+                    BinaryOp::Div => self.ops.push(Op::Div),
+                    BinaryOp::Pow => self.ops.push(Op::Pow),
+                    BinaryOp::Lt => self.ops.push(Op::Lt),
+                    BinaryOp::Gt => self.ops.push(Op::Gt),
+                    BinaryOp::Min => self.ops.push(Op::Min),
+                    BinaryOp::Max => self.ops.push(Op::Max),
+                }
             }
-            UnaryOp::Cos => {
-                flatten_expr(buf, expr, op.a);
-                buf.push(Op::Call(BuiltinFunction::Cos));
+            Node::UnOp(op) => {
+                self.flatten_expr(expr, op.a);
+                self.ops.push(match op.op {
+                    UnaryOp::Neg => Op::Neg,
+                    UnaryOp::Abs => Op::Call(BuiltinFunction::Abs),
+                    UnaryOp::Loge => Op::Call(BuiltinFunction::Loge),
+                    UnaryOp::Log2 => Op::Call(BuiltinFunction::Log2),
+                    UnaryOp::Log10 => Op::Call(BuiltinFunction::Log10),
+                    UnaryOp::Sin => Op::Call(BuiltinFunction::Sin),
+                    UnaryOp::Cos => Op::Call(BuiltinFunction::Cos),
+                    UnaryOp::Tan => Op::Call(BuiltinFunction::Tan),
+                });
             }
-            UnaryOp::Tan => {
-                flatten_expr(buf, expr, op.a);
-                buf.push(Op::Call(BuiltinFunction::Tan));
+
+            // `-cond < 0`, so NaN takes `otherwise`
+            Node::IfPositive(op) => {
+                self.flatten_expr(expr, op.cond);
+                self.ops.push(Op::Neg);
+                self.branch(expr, Op::Js, op.then, op.otherwise);
             }
-        },
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::dual::Dual;
 
     #[test]
     fn compiler() {
@@ -305,6 +609,8 @@ mod tests {
             let mut expr = Expr::new(n_vars);
             expr.random_tree(10);
             let compiled_expr = compile_expr(&expr);
+            assert!(compiled_expr.verify(n_vars).is_ok());
+            assert!(compiled_expr.optimize().verify(n_vars).is_ok());
 
             let expr_eval = expr.evaluate(&vars);
             let vm_eval = compiled_expr
@@ -317,9 +623,199 @@ mod tests {
 
             println!("--------------");
             println!("{}", expr.rpn());
-            compiled_expr.pprint();
+            println!("{compiled_expr}");
 
             assert_eq!(expr_eval, vm_eval);
         }
     }
+
+    #[test]
+    fn jumps() {
+        let src = "if_positive(x, if_positive(x - 1, 1, min(x, 0.5)), x < -1)";
+        let expr = Expr::from_infix(src, 1, &["x"]).unwrap();
+        let program = compile_expr(&expr);
+
+        // Labels are numbered in program order, even though
+        // the outer conditional allocates its labels first.
+        let literal = |c| Op::Push(Value::Literal(c));
+        let x = || Op::Push(Value::Ptr(0));
+        assert_eq!(
+            program.ops,
+            [
+                x(),
+                Op::Neg,
+                Op::Js(0),
+                x(),
+                literal(-1.0),
+                Op::Lt,
+                Op::Jmp(3),
+                Op::Label,
+                x(),
+                literal(1.0),
+                Op::Sub,
+                Op::Neg,
+                Op::Js(1),
+                x(),
+                literal(0.5),
+                Op::Min,
+                Op::Jmp(2),
+                Op::Label,
+                literal(1.0),
+                Op::Label,
+                Op::Label,
+            ]
+        );
+        assert_eq!(program.jump_table, [7, 17, 19, 20]);
+
+        for (x, y) in [
+            (3.0, 1.0),
+            (0.75, 0.5),
+            (0.25, 0.25),
+            (-0.5, 0.0),
+            (-3.0, 1.0),
+            (f64::NAN, 0.0),
+        ] {
+            assert_eq!(program.evaluate(&[x]), Ok(y));
+            assert_eq!(expr.evaluate(&[x]), y);
+        }
+    }
+
+    #[test]
+    fn comparisons() {
+        // Each operand is compiled once, without jumps
+        let expr = Expr::from_infix("min(max(x0, x1), 2) + (x0 < x1) - (x0 > x1)", 2, &[]).unwrap();
+        let program = compile_expr(&expr);
+        assert!(program.jump_table.is_empty());
+        assert_eq!(program.ops.len(), 13);
+
+        let inf = f64::INFINITY;
+        for (x0, x1) in [
+            (1.0, 3.0),
+            (3.0, 1.0),
+            (1.0, 1.0),
+            (0.0, -0.0),
+            (-0.0, 0.0),
+            (inf, inf),
+            (-inf, inf),
+            (f64::NAN, 1.0),
+            (1.0, f64::NAN),
+        ] {
+            let expected = expr.evaluate(&[x0, x1]);
+            let vm_eval = program.evaluate(&[x0, x1]).unwrap();
+            assert!(
+                vm_eval.to_bits() == expected.to_bits() || (vm_eval.is_nan() && expected.is_nan()),
+                "({x0}, {x1}): {vm_eval} != {expected}"
+            );
+        }
+    }
+
+    #[test]
+    fn verifier() {
+        let program = |ops, jump_table| Program { ops, jump_table };
+        let literal = |c| Op::Push(Value::Literal(c));
+        let check = |program: Program, error: VmError| {
+            assert_eq!(program.verify(1), Err(error.clone()));
+            assert_eq!(program.evaluate(&[1.0]), Err(error));
+        };
+
+        check(
+            program(vec![literal(1.0), Op::Add], vec![]),
+            VmError::StackUnderflow { pc: 1 },
+        );
+        check(program(vec![], vec![]), VmError::StackUnderflow { pc: 0 });
+        check(
+            program(vec![Op::Push(Value::Ptr(2))], vec![]),
+            VmError::OutOfBounds { pc: 0, ptr: 2 },
+        );
+        check(
+            program(vec![literal(1.0), literal(2.0)], vec![]),
+            VmError::LeftoverStack(1),
+        );
+        check(
+            program(vec![literal(1.0), Op::Jmp(0)], vec![]),
+            VmError::BadJump { pc: 1, label: 0 },
+        );
+        check(
+            program(vec![Op::Label, literal(1.0), Op::Jmp(0)], vec![0]),
+            VmError::BadJump { pc: 2, label: 0 },
+        );
+        check(
+            program(vec![literal(-1.0), Op::Js(0), literal(2.0)], vec![2]),
+            VmError::BadJump { pc: 1, label: 0 },
+        );
+
+        // Only the path not taken leaves a value on the stack
+        let mismatch = program(
+            vec![literal(1.0), Op::Js(0), literal(2.0), Op::Label],
+            vec![3],
+        );
+        assert_eq!(mismatch.verify(0), Err(VmError::StackMismatch { pc: 3 }));
+        assert_eq!(mismatch.evaluate(&[]), Ok(2.0));
+
+        let expr = Expr::from_infix("x * (x + 1)", 1, &["x"]).unwrap();
+        assert_eq!(compile_expr(&expr).verify(1), Ok(3));
+        assert_eq!(
+            compile_expr(&expr).verify(0),
+            Err(VmError::OutOfBounds { pc: 0, ptr: 0 })
+        );
+    }
+
+    #[test]
+    fn batch() {
+        let n_vars = 4;
+        let mut x = Vec2d::new(n_vars);
+        for _ in 0..(3 * BLOCK_SIZE + 7) * n_vars {
+            x.push(rand::random::<f64>() * 4.0 - 2.0);
+        }
+        let (rows, _cols) = x.shape();
+
+        for _ in 0..1_000 {
+            let mut expr = Expr::new(n_vars);
+            expr.random_tree(10);
+            let compiled_expr = compile_expr(&expr);
+
+            let batch = compiled_expr.evaluate_batch(&x).unwrap();
+            assert_eq!(batch.len(), rows);
+            for (i_row, batch_eval) in batch.into_iter().enumerate() {
+                let vm_eval = compiled_expr.evaluate(x.get_row(i_row).unwrap()).unwrap();
+                assert!(
+                    batch_eval == vm_eval || (batch_eval.is_nan() && vm_eval.is_nan()),
+                    "{}: row {i_row}: {batch_eval} != {vm_eval}",
+                    expr.rpn()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn dual() {
+        let n_vars = 4;
+        for _ in 0..10_000 {
+            let vars: Vec<Dual> = (0..n_vars)
+                .map(|i| Dual::variable(rand::random::<f64>(), i, n_vars + 1))
+                .collect();
+            let mut expr = Expr::new(n_vars);
+            expr.random_tree(10);
+            let compiled_expr = compile_expr(&expr);
+
+            // The last parameter scales all constants at once
+            let constant = |_, x| Dual {
+                value: x,
+                grad: (0..=n_vars)
+                    .map(|i| if i == n_vars { x } else { 0.0 })
+                    .collect(),
+            };
+            let expr_eval = expr.evaluate_scalar(&vars, &constant);
+            let vm_eval = compiled_expr
+                .evaluate_scalar(&vars, &constant)
+                .expect("program should be valid");
+
+            let same = |a: f64, b: f64| a == b || (a.is_nan() && b.is_nan());
+            assert!(same(expr_eval.value, vm_eval.value));
+            assert_eq!(expr_eval.grad.len(), vm_eval.grad.len());
+            for (a, b) in expr_eval.grad.iter().zip(&vm_eval.grad) {
+                assert!(same(*a, *b), "{}: {a} != {b}", expr.rpn());
+            }
+        }
+    }
 }
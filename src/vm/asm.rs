@@ -0,0 +1,330 @@
+use std::{collections::HashMap, error::Error, fmt};
+
+use super::{BuiltinFunction, Op, Program, Value};
+
+// Assembly listing, one op per line, which `Program::from_asm` reads
+// back. Literals print in their shortest exact form, so the round
+// trip is lossless. Labels are numbered by their order like in the
+// jump table.
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut label_counter = 0;
+        for (i, op) in self.ops.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            match op {
+                Op::Push(v) => match v {
+                    Value::Literal(x) => write!(f, "PUSH {x}")?,
+                    Value::Ptr(x) => write!(f, "PUSH ${x}")?,
+                },
+                Op::Label => {
+                    write!(f, "LABEL {label_counter}:")?;
+                    label_counter += 1;
+                }
+                Op::Jmp(l) | Op::Je(l) | Op::Jne(l) | Op::Js(l) | Op::Jns(l) => {
+                    write!(f, "    {} ${l}", mnemonic(op))?
+                }
+                Op::Call(function) => write!(f, "    CALL ${}", function_name(function))?,
+                _ => write!(f, "    {}", mnemonic(op))?,
+            }
+        }
+        Ok(())
+    }
+}
+
+fn mnemonic(op: &Op) -> &'static str {
+    match op {
+        Op::Push(_) => "PUSH",
+        Op::Add => "ADD",
+        Op::Sub => "SUB",
+        Op::Mul => "MUL",
+        Op::Div => "DIV",
+        Op::Pow => "POW",
+        Op::Min => "MIN",
+        Op::Max => "MAX",
+        Op::Lt => "LT",
+        Op::Gt => "GT",
+        Op::Neg => "NEG",
+        Op::Dup => "DUP",
+        Op::Jmp(_) => "JMP",
+        Op::Je(_) => "JE",
+        Op::Jne(_) => "JNE",
+        Op::Js(_) => "JS",
+        Op::Jns(_) => "JNS",
+        Op::Label => "LABEL",
+        Op::Call(_) => "CALL",
+    }
+}
+
+const FUNCTIONS: [(BuiltinFunction, &str); 7] = [
+    (BuiltinFunction::Abs, "abs"),
+    (BuiltinFunction::Loge, "loge"),
+    (BuiltinFunction::Log2, "log2"),
+    (BuiltinFunction::Log10, "log10"),
+    (BuiltinFunction::Sin, "sin"),
+    (BuiltinFunction::Cos, "cos"),
+    (BuiltinFunction::Tan, "tan"),
+];
+
+fn function_name(function: &BuiltinFunction) -> &'static str {
+    FUNCTIONS.iter().find(|(f, _)| f == function).unwrap().1
+}
+
+/// What's wrong with a line of assembly.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AsmErrorKind {
+    /// A mnemonic that isn't an op.
+    UnknownInstruction(String),
+    /// A `CALL` to a function that doesn't exist.
+    UnknownFunction(String),
+    /// An operand that doesn't parse.
+    InvalidOperand(String),
+    /// An op without its operand.
+    MissingOperand,
+    /// An operand to an op that takes none.
+    UnexpectedOperand(String),
+    /// A label defined twice.
+    DuplicateLabel(usize),
+    /// A jump to a label that isn't defined.
+    UndefinedLabel(usize),
+}
+
+/// An error from [`Program::from_asm`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct AsmError {
+    /// What's wrong.
+    pub kind: AsmErrorKind,
+
+    /// Counting from 1
+    pub line: usize,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            AsmErrorKind::UnknownInstruction(s) => write!(f, "unknown instruction '{s}'")?,
+            AsmErrorKind::UnknownFunction(s) => write!(f, "unknown function '{s}'")?,
+            AsmErrorKind::InvalidOperand(s) => write!(f, "invalid operand '{s}'")?,
+            AsmErrorKind::MissingOperand => write!(f, "missing operand")?,
+            AsmErrorKind::UnexpectedOperand(s) => write!(f, "unexpected operand '{s}'")?,
+            AsmErrorKind::DuplicateLabel(l) => write!(f, "label {l} is defined twice")?,
+            AsmErrorKind::UndefinedLabel(l) => write!(f, "label {l} is never defined")?,
+        }
+        write!(f, " on line {}", self.line)
+    }
+}
+
+impl Error for AsmError {}
+
+impl Program {
+    /// Parses the listing written by `Display`. Mnemonics are case
+    /// insensitive, indentation is free and `;` starts a comment.
+    /// Labels can have any number, jumps refer to them by that
+    /// number and they are renumbered by their order. The program
+    /// isn't verified, see `Program::verify`.
+    pub fn from_asm(src: &str) -> Result<Program, AsmError> {
+        // Jumps refer to the labels as written until all are known
+        let mut ops = Vec::new();
+        let mut lines = Vec::new();
+        let mut labels = HashMap::new();
+
+        for (i, line) in src.lines().enumerate() {
+            let line_number = i + 1;
+            let error = |kind| AsmError {
+                kind,
+                line: line_number,
+            };
+
+            let code = line.split(';').next().unwrap();
+            let mut words = code.split_whitespace();
+            let Some(mnemonic) = words.next() else {
+                continue;
+            };
+            let operand = words.next();
+            if let Some(extra) = words.next() {
+                return Err(error(AsmErrorKind::UnexpectedOperand(extra.to_string())));
+            }
+
+            let op = match mnemonic.to_ascii_uppercase().as_str() {
+                "PUSH" => {
+                    let operand = operand.ok_or(error(AsmErrorKind::MissingOperand))?;
+                    let invalid = || error(AsmErrorKind::InvalidOperand(operand.to_string()));
+                    match operand.strip_prefix('$') {
+                        Some(ptr) => Op::Push(Value::Ptr(ptr.parse().map_err(|_| invalid())?)),
+                        None => Op::Push(Value::Literal(operand.parse().map_err(|_| invalid())?)),
+                    }
+                }
+                "LABEL" => {
+                    let operand = operand.ok_or(error(AsmErrorKind::MissingOperand))?;
+                    let label = operand
+                        .strip_suffix(':')
+                        .and_then(|l| l.parse::<usize>().ok())
+                        .ok_or(error(AsmErrorKind::InvalidOperand(operand.to_string())))?;
+                    if labels.insert(label, labels.len()).is_some() {
+                        return Err(error(AsmErrorKind::DuplicateLabel(label)));
+                    }
+                    Op::Label
+                }
+                "CALL" => {
+                    let operand = operand.ok_or(error(AsmErrorKind::MissingOperand))?;
+                    let name = operand.strip_prefix('$').unwrap_or(operand);
+                    let function = FUNCTIONS
+                        .iter()
+                        .find(|(_, n)| *n == name)
+                        .map(|(f, _)| f.clone())
+                        .ok_or(error(AsmErrorKind::UnknownFunction(name.to_string())))?;
+                    Op::Call(function)
+                }
+                jump @ ("JMP" | "JE" | "JNE" | "JS" | "JNS") => {
+                    let operand = operand.ok_or(error(AsmErrorKind::MissingOperand))?;
+                    let label = operand
+                        .strip_prefix('$')
+                        .and_then(|l| l.parse::<usize>().ok())
+                        .ok_or(error(AsmErrorKind::InvalidOperand(operand.to_string())))?;
+                    match jump {
+                        "JMP" => Op::Jmp(label),
+                        "JE" => Op::Je(label),
+                        "JNE" => Op::Jne(label),
+                        "JS" => Op::Js(label),
+                        _ => Op::Jns(label),
+                    }
+                }
+                other => {
+                    let op = match other {
+                        "ADD" => Op::Add,
+                        "SUB" => Op::Sub,
+                        "MUL" => Op::Mul,
+                        "DIV" => Op::Div,
+                        "POW" => Op::Pow,
+                        "MIN" => Op::Min,
+                        "MAX" => Op::Max,
+                        "LT" => Op::Lt,
+                        "GT" => Op::Gt,
+                        "NEG" => Op::Neg,
+                        "DUP" => Op::Dup,
+                        _ => {
+                            return Err(error(AsmErrorKind::UnknownInstruction(
+                                mnemonic.to_string(),
+                            )))
+                        }
+                    };
+                    if let Some(operand) = operand {
+                        return Err(error(AsmErrorKind::UnexpectedOperand(operand.to_string())));
+                    }
+                    op
+                }
+            };
+
+            ops.push(op);
+            lines.push(line_number);
+        }
+
+        // Resolve the label numbers as written to their order
+        for (op, line) in ops.iter_mut().zip(lines) {
+            if let Op::Jmp(l) | Op::Je(l) | Op::Jne(l) | Op::Js(l) | Op::Jns(l) = op {
+                *l = *labels.get(l).ok_or(AsmError {
+                    kind: AsmErrorKind::UndefinedLabel(*l),
+                    line,
+                })?;
+            }
+        }
+
+        let jump_table = ops
+            .iter()
+            .enumerate()
+            .filter(|(_, op)| matches!(op, Op::Label))
+            .map(|(i, _)| i)
+            .collect();
+        Ok(Program { ops, jump_table })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{expr::Expr, vm::compile_expr};
+
+    #[test]
+    fn listing() {
+        let expr = Expr::from_infix("if_positive(x, sin(x), -1.5)", 1, &["x"]).unwrap();
+        let program = compile_expr(&expr);
+        assert_eq!(
+            program.to_string(),
+            "PUSH $0\n    NEG\n    JS $0\nPUSH -1.5\n    JMP $1\nLABEL 0:\nPUSH $0\n    CALL $sin\nLABEL 1:"
+        );
+
+        // Hand-written, with its own label numbers
+        let src = "
+            ; |x - 2|, the long way
+            push $0
+            PUSH 2
+            SUB
+            DUP
+            JNS $7     ; already positive
+            NEG
+            LABEL 7:
+        ";
+        let program = Program::from_asm(src).unwrap();
+        assert_eq!(program.jump_table, [6]);
+        assert_eq!(program.verify(1), Ok(2));
+        assert_eq!(program.evaluate(&[0.5]), Ok(1.5));
+        assert_eq!(program.evaluate(&[3.0]), Ok(1.0));
+        assert_eq!(
+            program.to_string(),
+            "PUSH $0\nPUSH 2\n    SUB\n    DUP\n    JNS $0\n    NEG\nLABEL 0:"
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let error = |src| Program::from_asm(src).unwrap_err();
+
+        assert_eq!(
+            error("PUSH 1\nPUSH 2\nMOV"),
+            AsmError {
+                kind: AsmErrorKind::UnknownInstruction("MOV".to_string()),
+                line: 3
+            }
+        );
+        assert_eq!(error("PUSH").kind, AsmErrorKind::MissingOperand);
+        assert_eq!(
+            error("PUSH $x").kind,
+            AsmErrorKind::InvalidOperand("$x".to_string())
+        );
+        assert_eq!(
+            error("PUSH 1\nADD 2").kind,
+            AsmErrorKind::UnexpectedOperand("2".to_string())
+        );
+        assert_eq!(
+            error("PUSH 1\nCALL $exp").kind,
+            AsmErrorKind::UnknownFunction("exp".to_string())
+        );
+        assert_eq!(
+            error("LABEL 1:\nLABEL 1:").kind,
+            AsmErrorKind::DuplicateLabel(1)
+        );
+        assert_eq!(
+            error("PUSH 1\nJS $3\nLABEL 2:"),
+            AsmError {
+                kind: AsmErrorKind::UndefinedLabel(3),
+                line: 2
+            }
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        for _ in 0..1_000 {
+            let mut expr = Expr::new(3);
+            expr.random_tree(8);
+            for program in [compile_expr(&expr), compile_expr(&expr).optimize()] {
+                // Compared as text, as folding may leave NaN literals
+                let listing = program.to_string();
+                let assembled = Program::from_asm(&listing).unwrap();
+                assert_eq!(assembled.to_string(), listing);
+                assert_eq!(assembled.jump_table, program.jump_table);
+            }
+        }
+    }
+}
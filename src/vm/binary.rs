@@ -0,0 +1,226 @@
+use std::collections::HashMap;
+
+use super::{BuiltinFunction, Op, Program, Value};
+use crate::serialize::{decode, encode, FormatError, Reader, Saved, Writer};
+
+const MAGIC: &[u8; 4] = b"SRVM";
+
+const OPCODES: [(Op, u8); 19] = [
+    (Op::Push(Value::Literal(0.0)), 0),
+    (Op::Push(Value::Ptr(0)), 1),
+    (Op::Add, 2),
+    (Op::Sub, 3),
+    (Op::Mul, 4),
+    (Op::Div, 5),
+    (Op::Pow, 6),
+    (Op::Neg, 7),
+    (Op::Dup, 8),
+    (Op::Jmp(0), 9),
+    (Op::Je(0), 10),
+    (Op::Jne(0), 11),
+    (Op::Js(0), 12),
+    (Op::Jns(0), 13),
+    (Op::Label, 14),
+    (Op::Min, 16),
+    (Op::Max, 17),
+    (Op::Lt, 18),
+    (Op::Gt, 19),
+];
+
+const CALL: u8 = 15;
+
+const FUNCTIONS: [(BuiltinFunction, u8); 7] = [
+    (BuiltinFunction::Abs, 0),
+    (BuiltinFunction::Loge, 1),
+    (BuiltinFunction::Log2, 2),
+    (BuiltinFunction::Log10, 3),
+    (BuiltinFunction::Sin, 4),
+    (BuiltinFunction::Cos, 5),
+    (BuiltinFunction::Tan, 6),
+];
+
+// The op with its operand zeroed, to look up its code.
+fn opcode(op: &Op) -> u8 {
+    let kind = match op {
+        Op::Push(Value::Literal(_)) => Op::Push(Value::Literal(0.0)),
+        Op::Push(Value::Ptr(_)) => Op::Push(Value::Ptr(0)),
+        Op::Jmp(_) => Op::Jmp(0),
+        Op::Je(_) => Op::Je(0),
+        Op::Jne(_) => Op::Jne(0),
+        Op::Js(_) => Op::Js(0),
+        Op::Jns(_) => Op::Jns(0),
+        Op::Call(_) => return CALL,
+        op => op.clone(),
+    };
+    encode(&OPCODES, &kind)
+}
+
+impl Program {
+    /// Saves the program for `n_inputs` columns named `names`.
+    /// Literals go into a table of constants, stored once
+    /// each, and pushes refer to them by index.
+    pub fn to_bytes(&self, n_inputs: usize, names: &[&str]) -> Vec<u8> {
+        let mut constants = Vec::new();
+        let mut indices = HashMap::new();
+        for op in &self.ops {
+            if let Op::Push(Value::Literal(x)) = op {
+                indices.entry(x.to_bits()).or_insert_with(|| {
+                    constants.push(*x);
+                    constants.len() - 1
+                });
+            }
+        }
+
+        let mut writer = Writer::new(MAGIC, n_inputs, names);
+        writer.u32(constants.len());
+        for x in constants {
+            writer.f64(x);
+        }
+        writer.u32(self.jump_table.len());
+        for &pos in &self.jump_table {
+            writer.u32(pos);
+        }
+
+        writer.u32(self.ops.len());
+        for op in &self.ops {
+            writer.u8(opcode(op));
+            match op {
+                Op::Push(Value::Literal(x)) => writer.u32(indices[&x.to_bits()]),
+                Op::Push(Value::Ptr(ptr)) => writer.u32(*ptr),
+                Op::Jmp(l) | Op::Je(l) | Op::Jne(l) | Op::Js(l) | Op::Jns(l) => writer.u32(*l),
+                Op::Call(function) => writer.u8(encode(&FUNCTIONS, function)),
+                _ => (),
+            }
+        }
+        writer.bytes
+    }
+
+    /// Loads what `to_bytes` saved. Only programs that pass
+    /// `verify` for the saved input count are accepted, so
+    /// the result can be evaluated without further checks.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Saved<Program>, FormatError> {
+        let (mut reader, n_inputs, names) = Reader::new(bytes, MAGIC)?;
+
+        let n_constants = reader.u32()?;
+        let mut constants = Vec::new();
+        for _ in 0..n_constants {
+            constants.push(reader.f64()?);
+        }
+        let n_labels = reader.u32()?;
+        let mut jump_table = Vec::new();
+        for _ in 0..n_labels {
+            jump_table.push(reader.u32()?);
+        }
+
+        let n_ops = reader.u32()?;
+        let mut ops = Vec::new();
+        for _ in 0..n_ops {
+            let code = reader.u8()?;
+            if code == CALL {
+                ops.push(Op::Call(decode(&FUNCTIONS, reader.u8()?)?));
+                continue;
+            }
+            let op = match decode(&OPCODES, code)? {
+                Op::Push(Value::Literal(_)) => {
+                    Op::Push(Value::Literal(constants[reader.index(n_constants)?]))
+                }
+                Op::Push(Value::Ptr(_)) => Op::Push(Value::Ptr(reader.u32()?)),
+                Op::Jmp(_) => Op::Jmp(reader.index(n_labels)?),
+                Op::Je(_) => Op::Je(reader.index(n_labels)?),
+                Op::Jne(_) => Op::Jne(reader.index(n_labels)?),
+                Op::Js(_) => Op::Js(reader.index(n_labels)?),
+                Op::Jns(_) => Op::Jns(reader.index(n_labels)?),
+                op => op,
+            };
+            ops.push(op);
+        }
+        reader.finish()?;
+
+        let labels = ops.iter().enumerate().filter(|(_, op)| **op == Op::Label);
+        if !labels.map(|(i, _)| i).eq(jump_table.iter().copied()) {
+            return Err(FormatError::InvalidJumpTable);
+        }
+
+        let program = Program { ops, jump_table };
+        program.verify(n_inputs).map_err(FormatError::Program)?;
+
+        Ok(Saved {
+            model: program,
+            n_inputs,
+            names,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        expr::Expr,
+        vm::{compile_expr, VmError},
+    };
+
+    #[test]
+    fn round_trip() {
+        let names = ["a", "b", "c"];
+        for _ in 0..1_000 {
+            let mut expr = Expr::new(3);
+            expr.random_tree(8);
+            for program in [compile_expr(&expr), compile_expr(&expr).optimize()] {
+                let bytes = program.to_bytes(3, &names);
+                let saved = Program::from_bytes(&bytes).unwrap();
+
+                // Compared as text, as folding may leave NaN literals
+                assert_eq!(saved.model.to_string(), program.to_string());
+                assert_eq!(saved.model.jump_table, program.jump_table);
+                assert_eq!(saved.n_inputs, 3);
+                assert_eq!(saved.names, names);
+            }
+        }
+    }
+
+    #[test]
+    fn validation() {
+        let src = "PUSH $0\nPUSH 2\n    SUB\n    DUP\n    JNS $0\n    NEG\nLABEL 0:";
+        let program = Program::from_asm(src).unwrap();
+        let bytes = program.to_bytes(1, &["x"]);
+        assert_eq!(&bytes[..4], b"SRVM");
+
+        // Header, one constant, one label and seven ops
+        let header = 4 + 2 + 4 + 4 + 5;
+        assert_eq!(bytes.len(), header + 12 + 8 + 4 + 5 * 3 + 4);
+
+        let error = |bytes: &[u8]| Program::from_bytes(bytes).unwrap_err();
+        for len in 0..bytes.len() {
+            error(&bytes[..len]);
+        }
+
+        let mut bad = bytes.clone();
+        bad[5] = 1;
+        assert_eq!(error(&bad), FormatError::UnsupportedVersion(257));
+
+        // The jump table points at `NEG`
+        let mut bad = bytes.clone();
+        bad[header + 16] = 5;
+        assert_eq!(error(&bad), FormatError::InvalidJumpTable);
+
+        // `PUSH $0` becomes `PUSH $1`
+        let ops = header + 24;
+        let mut bad = bytes.clone();
+        bad[ops + 1] = 1;
+        assert_eq!(
+            error(&bad),
+            FormatError::Program(VmError::OutOfBounds { pc: 0, ptr: 1 })
+        );
+
+        // `PUSH 2` uses a constant that doesn't exist
+        let mut bad = bytes.clone();
+        bad[ops + 6] = 1;
+        assert_eq!(error(&bad), FormatError::InvalidIndex(1));
+
+        // `SUB` becomes an unknown op
+        let mut bad = bytes;
+        bad[ops + 10] = 200;
+        assert_eq!(error(&bad), FormatError::InvalidTag(200));
+    }
+}
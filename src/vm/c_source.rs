@@ -0,0 +1,338 @@
+use std::fmt::Write;
+
+use super::{BuiltinFunction, Op, Program, Value, VmError};
+
+/// Standalone C99 source for a program, split into a header
+/// with the prototypes and the definitions, which only need
+/// `math.h`.
+#[derive(Debug, Clone)]
+pub struct CSource {
+    /// The include guard and the prototypes.
+    pub header: String,
+    /// The definitions.
+    pub source: String,
+}
+
+impl Program {
+    /// Emits `double name(const double *x)`, and with `batch` also
+    /// `void name_batch(const double *x, double *y, size_t rows)`
+    /// over rows of `n_inputs` values each. `names` label the
+    /// inputs in comments. `name` is made a valid C identifier and
+    /// prefixed, so `model-1` becomes `symreg_model_1`.
+    ///
+    /// The stack depth is known at every op, so the stack becomes
+    /// one local per slot, which the C compiler keeps in registers,
+    /// and labels become `goto` targets. Compiled with `-fno-builtin`,
+    /// the results match `evaluate` bit for bit, otherwise the compiler
+    /// may fold math calls on constants with its own rounding.
+    pub fn to_c(
+        &self,
+        name: &str,
+        n_inputs: usize,
+        names: &[&str],
+        batch: bool,
+    ) -> Result<CSource, VmError> {
+        let depths = self.stack_depths(n_inputs)?;
+        let max_depth = depths.iter().flatten().max().copied().unwrap();
+        let name = c_identifier(name);
+
+        let mut inputs = String::new();
+        for i in 0..n_inputs {
+            match names.get(i) {
+                Some(col) => writeln!(inputs, " *   x[{i}]: {}", comment_escape(col)).unwrap(),
+                None => writeln!(inputs, " *   x[{i}]").unwrap(),
+            }
+        }
+
+        let guard = format!("{}_H", name.to_ascii_uppercase());
+        let mut header = format!("#ifndef {guard}\n#define {guard}\n\n");
+        if batch {
+            header += "#include <stddef.h>\n\n";
+        }
+        header += &format!("/* Inputs:\n{inputs} */\ndouble {name}(const double *x);\n");
+        if batch {
+            header += &format!(
+                "\n/* Evaluates `rows` rows of `x`, {n_inputs} values each, into `y`. */\n\
+                 void {name}_batch(const double *x, double *y, size_t rows);\n"
+            );
+        }
+        header += &format!("\n#endif /* {guard} */\n");
+
+        let mut source = String::from("#include <math.h>\n");
+        if batch {
+            source += "#include <stddef.h>\n";
+        }
+        source += &format!("\ndouble {name}(const double *x)\n{{\n");
+        let slots: Vec<String> = (0..max_depth).map(|i| format!("s{i}")).collect();
+        source += &format!("    double {};\n\n", slots.join(", "));
+
+        let mut label_counter = 0;
+        for (op, depth) in self.ops.iter().zip(&depths) {
+            if *op == Op::Label {
+                source += &format!("l{label_counter}:\n");
+                label_counter += 1;
+                continue;
+            }
+            // Dead code after an unconditional jump
+            let Some(depth) = *depth else {
+                continue;
+            };
+
+            // The top of the stack, and the value below it
+            let top = depth.wrapping_sub(1);
+            let below = depth.wrapping_sub(2);
+            let line = match op {
+                Op::Push(Value::Literal(x)) => format!("s{depth} = {};", c_literal(*x)),
+                Op::Push(Value::Ptr(ptr)) => format!("s{depth} = x[{ptr}];"),
+                Op::Add => format!("s{below} = s{below} + s{top};"),
+                Op::Sub => format!("s{below} = s{below} - s{top};"),
+                Op::Mul => format!("s{below} = s{below} * s{top};"),
+                Op::Div => format!("s{below} = s{below} / s{top};"),
+                Op::Pow => format!("s{below} = pow(s{below}, s{top});"),
+                Op::Min => format!("s{below} = s{below} < s{top} ? s{below} : s{top};"),
+                Op::Max => format!("s{below} = s{below} > s{top} ? s{below} : s{top};"),
+                Op::Lt => format!("s{below} = s{below} < s{top} ? 1.0 : 0.0;"),
+                Op::Gt => format!("s{below} = s{below} > s{top} ? 1.0 : 0.0;"),
+                Op::Neg => format!("s{top} = -s{top};"),
+                Op::Dup => format!("s{depth} = s{top};"),
+                Op::Jmp(l) => format!("goto l{l};"),
+                Op::Je(l) => format!("if (s{top} == 0.0) goto l{l};"),
+                Op::Jne(l) => format!("if (s{top} != 0.0) goto l{l};"),
+                Op::Js(l) => format!("if (s{top} < 0.0) goto l{l};"),
+                Op::Jns(l) => format!("if (s{top} >= 0.0) goto l{l};"),
+                Op::Call(function) => {
+                    format!("s{top} = {}(s{top});", c_function(function))
+                }
+                Op::Label => unreachable!(),
+            };
+            source += &format!("    {line}\n");
+        }
+        source += "    return s0;\n}\n";
+
+        if batch {
+            source += &format!(
+                "\nvoid {name}_batch(const double *x, double *y, size_t rows)\n{{\n    \
+                 for (size_t i = 0; i < rows; i++) {{\n        \
+                 y[i] = {name}(x + i * {n_inputs});\n    \
+                 }}\n}}\n"
+            );
+        }
+
+        Ok(CSource { header, source })
+    }
+}
+
+fn c_function(function: &BuiltinFunction) -> &'static str {
+    match function {
+        BuiltinFunction::Abs => "fabs",
+        BuiltinFunction::Loge => "log",
+        BuiltinFunction::Log2 => "log2",
+        BuiltinFunction::Log10 => "log10",
+        BuiltinFunction::Sin => "sin",
+        BuiltinFunction::Cos => "cos",
+        BuiltinFunction::Tan => "tan",
+    }
+}
+
+// Debug formatting is the shortest form that reads back
+// exactly, and always has a `.` or an exponent.
+fn c_literal(x: f64) -> String {
+    if x.is_nan() {
+        "NAN".to_string()
+    } else if x.is_infinite() {
+        if x > 0.0 { "INFINITY" } else { "-INFINITY" }.to_string()
+    } else {
+        format!("{x:?}")
+    }
+}
+
+// Every symbol gets a prefix, which keeps it clear of the keywords,
+// whatever `math.h` declares, like `NAN` or `powf`, and the names of
+// the parameters. Identifiers starting with an underscore or a digit
+// never come out of it.
+fn c_identifier(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    format!("symreg_{name}")
+}
+
+fn comment_escape(s: &str) -> String {
+    s.replace("*/", "* /").replace(char::is_control, " ")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fmt::Write, fs, process::Command};
+
+    use super::*;
+    use crate::{expr::Expr, vm::compile_expr};
+
+    #[test]
+    fn listing() {
+        let src = "PUSH $0\nPUSH 2\n    SUB\n    DUP\n    JNS $0\n    NEG\nLABEL 0:";
+        let program = Program::from_asm(src).unwrap();
+        let c = program.to_c("dist", 1, &["petal_width"], true).unwrap();
+        assert_eq!(
+            c.header,
+            "#ifndef SYMREG_DIST_H\n\
+             #define SYMREG_DIST_H\n\
+             \n\
+             #include <stddef.h>\n\
+             \n\
+             /* Inputs:\n \
+             *   x[0]: petal_width\n \
+             */\n\
+             double symreg_dist(const double *x);\n\
+             \n\
+             /* Evaluates `rows` rows of `x`, 1 values each, into `y`. */\n\
+             void symreg_dist_batch(const double *x, double *y, size_t rows);\n\
+             \n\
+             #endif /* SYMREG_DIST_H */\n"
+        );
+        assert_eq!(
+            c.source,
+            "#include <math.h>\n\
+             #include <stddef.h>\n\
+             \n\
+             double symreg_dist(const double *x)\n\
+             {\n    \
+             double s0, s1;\n\
+             \n    \
+             s0 = x[0];\n    \
+             s1 = 2.0;\n    \
+             s0 = s0 - s1;\n    \
+             s1 = s0;\n    \
+             if (s1 >= 0.0) goto l0;\n    \
+             s0 = -s0;\n\
+             l0:\n    \
+             return s0;\n\
+             }\n\
+             \n\
+             void symreg_dist_batch(const double *x, double *y, size_t rows)\n\
+             {\n    \
+             for (size_t i = 0; i < rows; i++) {\n        \
+             y[i] = symreg_dist(x + i * 1);\n    \
+             }\n\
+             }\n"
+        );
+
+        assert_eq!(
+            program.to_c("dist", 0, &[], false).unwrap_err(),
+            VmError::OutOfBounds { pc: 0, ptr: 0 }
+        );
+
+        // Names that aren't C identifiers, or clash with one
+        for (name, identifier) in [
+            ("model-1", "symreg_model_1"),
+            ("2nd", "symreg_2nd"),
+            ("", "symreg_"),
+            ("-x", "symreg__x"),
+            ("sin", "symreg_sin"),
+            ("NAN", "symreg_NAN"),
+            ("rows", "symreg_rows"),
+        ] {
+            let c = program.to_c(name, 1, &[], true).unwrap();
+            let guard = identifier.to_ascii_uppercase();
+            assert!(c.header.starts_with(&format!("#ifndef {guard}_H\n")));
+            assert!(c
+                .source
+                .contains(&format!("\ndouble {identifier}(const double *x)\n")));
+            assert!(c.source.contains(&format!(" {identifier}_batch(")));
+        }
+    }
+
+    #[test]
+    fn matches_interpreter() {
+        // Needs a C compiler
+        if Command::new("cc").arg("--version").output().is_err() {
+            eprintln!("skipping matches_interpreter, there is no `cc`");
+            return;
+        }
+
+        let n_vars = 3;
+        let n_rows = 20;
+        let inputs: Vec<f64> = (0..n_vars * n_rows)
+            .map(|_| rand::random::<f64>() * 4.0 - 2.0)
+            .collect();
+
+        // All models go into one file, along with a driver
+        // printing their results for every row.
+        let mut programs = Vec::new();
+        let mut source = String::new();
+        for i in 0..100 {
+            let mut expr = Expr::new(n_vars);
+            expr.random_tree(8);
+            for program in [compile_expr(&expr), compile_expr(&expr).optimize()] {
+                let c = program
+                    .to_c(&format!("model{}", programs.len()), n_vars, &[], i == 0)
+                    .unwrap();
+                source += &c.header;
+                source += &c.source;
+                programs.push(program);
+            }
+        }
+
+        let values: Vec<String> = inputs.iter().map(|x| format!("{x:?}")).collect();
+        writeln!(
+            source,
+            "#include <stdio.h>\n\
+             static const double inputs[] = {{{}}};\n\
+             static void print(double y)\n{{\n    \
+             if (isnan(y)) puts(\"nan\"); else printf(\"%.17g\\n\", y);\n}}\n\
+             int main(void)\n{{\n    \
+             double batch[{n_rows}];\n    \
+             symreg_model0_batch(inputs, batch, {n_rows});\n    \
+             for (int i = 0; i < {n_rows}; i++) print(batch[i]);",
+            values.join(", ")
+        )
+        .unwrap();
+        for i in 0..programs.len() {
+            writeln!(
+                source,
+                "    for (int i = 0; i < {n_rows}; i++) print(symreg_model{i}(inputs + i * {n_vars}));"
+            )
+            .unwrap();
+        }
+        source += "    return 0;\n}\n";
+
+        let dir = std::env::temp_dir().join(format!("symreg-c-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let c_path = dir.join("models.c");
+        let exe_path = dir.join("models");
+        fs::write(&c_path, &source).unwrap();
+        // Without builtins, as the compiler would fold calls on
+        // constants with its own math, which may round differently
+        let status = Command::new("cc")
+            .args(["-std=c99", "-O2", "-fno-builtin", "-o"])
+            .arg(&exe_path)
+            .arg(&c_path)
+            .arg("-lm")
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let output = Command::new(&exe_path).output().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        let results: Vec<f64> = String::from_utf8(output.stdout)
+            .unwrap()
+            .lines()
+            .map(|line| line.parse().unwrap())
+            .collect();
+
+        let runs = [&programs[0]].into_iter().chain(&programs);
+        assert_eq!(results.len(), (programs.len() + 1) * n_rows);
+        for (program, results) in runs.zip(results.chunks(n_rows)) {
+            for (row, result) in inputs.chunks(n_vars).zip(results) {
+                let expected = program.evaluate(row).unwrap();
+                // The same operations in the same order,
+                // with the same libm, so the same bits
+                assert!(
+                    *result == expected || (result.is_nan() && expected.is_nan()),
+                    "{result} != {expected} at {row:?} for\n{program}"
+                );
+            }
+        }
+    }
+}
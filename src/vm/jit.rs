@@ -0,0 +1,394 @@
+use super::{Program, VmError};
+use crate::vec2d::Vec2d;
+
+/// A `Program` compiled to native code, when built with the `jit`
+/// feature on x86-64 Linux. Falls back to the interpreter otherwise,
+/// and for programs the code generator can't handle, i.e. jumps
+/// or stacks deeper than 500 slots.
+pub struct JitProgram {
+    program: Program,
+    native: Option<native::Function>,
+}
+
+impl JitProgram {
+    /// Compiles `program` to native code, when possible.
+    pub fn new(program: Program) -> JitProgram {
+        let native = native::Function::compile(&program);
+        JitProgram { program, native }
+    }
+
+    /// Did the program compile to native code?
+    pub fn is_native(&self) -> bool {
+        self.native.is_some()
+    }
+
+    /// The value for one row of inputs. Too few inputs
+    /// are left to the interpreter to report.
+    pub fn evaluate(&self, inputs: &[f64]) -> Result<f64, VmError> {
+        match &self.native {
+            Some(function) if inputs.len() >= function.n_inputs() => Ok(function.call(inputs)),
+            _ => self.program.evaluate(inputs),
+        }
+    }
+
+    /// The values for every row of `x`.
+    pub fn evaluate_batch(&self, x: &Vec2d<f64>) -> Result<Vec<f64>, VmError> {
+        let (rows, cols) = x.shape();
+        match &self.native {
+            Some(function) if cols >= function.n_inputs() => Ok((0..rows)
+                .map(|i_row| function.call(x.get_row(i_row).unwrap()))
+                .collect()),
+            _ => self.program.evaluate_batch(x),
+        }
+    }
+}
+
+#[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+mod native {
+    use std::{mem, ptr};
+
+    use super::super::{BuiltinFunction, Op, Program, Value};
+
+    // System V calling convention: `inputs` comes in rdi and the result
+    // goes out in xmm0. The stack slots live in the machine stack frame,
+    // addressed from rbp, and inputs are addressed from rbx. Both are
+    // callee saved, so they survive the calls into the math functions.
+    type Entry = extern "C" fn(*const f64) -> f64;
+
+    pub struct Function {
+        code: *mut libc::c_void,
+        len: usize,
+        n_inputs: usize,
+    }
+
+    // The code is never written to after compilation.
+    unsafe impl Send for Function {}
+    unsafe impl Sync for Function {}
+
+    impl Function {
+        pub fn compile(program: &Program) -> Option<Function> {
+            let n_inputs = program
+                .ops
+                .iter()
+                .filter_map(|op| match op {
+                    Op::Push(Value::Ptr(x)) => Some(x + 1),
+                    _ => None,
+                })
+                .max()
+                .unwrap_or(0);
+            let code = generate(program, n_inputs)?;
+
+            unsafe {
+                let len = code.len();
+                let memory = libc::mmap(
+                    ptr::null_mut(),
+                    len,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                    -1,
+                    0,
+                );
+                if memory == libc::MAP_FAILED {
+                    return None;
+                }
+
+                ptr::copy_nonoverlapping(code.as_ptr(), memory as *mut u8, len);
+                if libc::mprotect(memory, len, libc::PROT_READ | libc::PROT_EXEC) != 0 {
+                    libc::munmap(memory, len);
+                    return None;
+                }
+
+                Some(Function {
+                    code: memory,
+                    len,
+                    n_inputs,
+                })
+            }
+        }
+
+        pub fn n_inputs(&self) -> usize {
+            self.n_inputs
+        }
+
+        pub fn call(&self, inputs: &[f64]) -> f64 {
+            assert!(inputs.len() >= self.n_inputs, "not enough inputs");
+            let entry: Entry = unsafe { mem::transmute(self.code) };
+            entry(inputs.as_ptr())
+        }
+    }
+
+    impl Drop for Function {
+        fn drop(&mut self) {
+            unsafe {
+                libc::munmap(self.code, self.len);
+            }
+        }
+    }
+
+    extern "C" fn pow(a: f64, b: f64) -> f64 {
+        a.powf(b)
+    }
+
+    extern "C" fn ln(x: f64) -> f64 {
+        x.ln()
+    }
+
+    extern "C" fn log2(x: f64) -> f64 {
+        x.log2()
+    }
+
+    extern "C" fn log10(x: f64) -> f64 {
+        x.log10()
+    }
+
+    extern "C" fn sin(x: f64) -> f64 {
+        x.sin()
+    }
+
+    extern "C" fn cos(x: f64) -> f64 {
+        x.cos()
+    }
+
+    extern "C" fn tan(x: f64) -> f64 {
+        x.tan()
+    }
+
+    // Base registers in the ModRM rm field.
+    const RBX: u8 = 3;
+    const RBP: u8 = 5;
+
+    // SSE2 opcodes after the F2 0F prefix.
+    const MOVSD_LOAD: u8 = 0x10;
+    const MOVSD_STORE: u8 = 0x11;
+    const ADDSD: u8 = 0x58;
+    const MULSD: u8 = 0x59;
+    const SUBSD: u8 = 0x5c;
+    const MINSD: u8 = 0x5d;
+    const DIVSD: u8 = 0x5e;
+    const MAXSD: u8 = 0x5f;
+    const CMPSD: u8 = 0xc2;
+
+    // `cmpsd` predicate, all ones when less than and zeros
+    // otherwise, NaN included.
+    const LT: u8 = 1;
+
+    struct Assembler {
+        code: Vec<u8>,
+    }
+
+    impl Assembler {
+        // `op xmm, [base + disp32]` and the store variant.
+        fn sse(&mut self, opcode: u8, xmm: u8, base: u8, disp: i32) {
+            self.code
+                .extend_from_slice(&[0xf2, 0x0f, opcode, 0x80 | xmm << 3 | base]);
+            self.code.extend_from_slice(&disp.to_le_bytes());
+        }
+
+        // `op [rbp + disp32], rax` for an ALU opcode.
+        fn alu_slot_rax(&mut self, opcode: u8, disp: i32) {
+            self.code.extend_from_slice(&[0x48, opcode, 0x80 | RBP]);
+            self.code.extend_from_slice(&disp.to_le_bytes());
+        }
+
+        fn mov_rax(&mut self, imm: u64) {
+            self.code.extend_from_slice(&[0x48, 0xb8]);
+            self.code.extend_from_slice(&imm.to_le_bytes());
+        }
+
+        fn call(&mut self, function: usize) {
+            self.mov_rax(function as u64);
+            self.code.extend_from_slice(&[0xff, 0xd0]); // call rax
+        }
+
+        // Calls `function` with the slot as the argument and result.
+        fn call_unary(&mut self, function: extern "C" fn(f64) -> f64, disp: i32) {
+            self.sse(MOVSD_LOAD, 0, RBP, disp);
+            self.call(function as usize);
+            self.sse(MOVSD_STORE, 0, RBP, disp);
+        }
+    }
+
+    fn slot(i: usize) -> i32 {
+        (i * 8) as i32
+    }
+
+    // Keeps the frame within a page. Nothing probes the stack, so a
+    // larger frame could skip past the guard page below it.
+    const MAX_DEPTH: usize = 500;
+
+    fn generate(program: &Program, n_inputs: usize) -> Option<Vec<u8>> {
+        let depth = program.verify(n_inputs).ok()?;
+        if depth > MAX_DEPTH {
+            return None;
+        }
+
+        // Keeps rsp 16 byte aligned at the calls, with the
+        // return address and the two pushes already on it.
+        let frame = (depth * 8).div_ceil(16) * 16 + 8;
+        let frame = (frame as u32).to_le_bytes();
+
+        let mut asm = Assembler { code: Vec::new() };
+        asm.code.extend_from_slice(&[0x53, 0x55]); // push rbx; push rbp
+        asm.code.extend_from_slice(&[0x48, 0x81, 0xec]); // sub rsp, frame
+        asm.code.extend_from_slice(&frame);
+        asm.code.extend_from_slice(&[0x48, 0x89, 0xe5]); // mov rbp, rsp
+        asm.code.extend_from_slice(&[0x48, 0x89, 0xfb]); // mov rbx, rdi
+
+        let mut sp = 0;
+        for op in &program.ops {
+            match op {
+                Op::Push(Value::Literal(x)) => {
+                    asm.mov_rax(x.to_bits());
+                    asm.alu_slot_rax(0x89, slot(sp)); // mov
+                    sp += 1;
+                }
+                Op::Push(Value::Ptr(x)) => {
+                    asm.sse(MOVSD_LOAD, 0, RBX, slot(*x));
+                    asm.sse(MOVSD_STORE, 0, RBP, slot(sp));
+                    sp += 1;
+                }
+                Op::Add | Op::Sub | Op::Mul | Op::Div => {
+                    let opcode = match op {
+                        Op::Add => ADDSD,
+                        Op::Sub => SUBSD,
+                        Op::Mul => MULSD,
+                        _ => DIVSD,
+                    };
+                    asm.sse(MOVSD_LOAD, 0, RBP, slot(sp - 2));
+                    asm.sse(opcode, 0, RBP, slot(sp - 1));
+                    asm.sse(MOVSD_STORE, 0, RBP, slot(sp - 2));
+                    sp -= 1;
+                }
+                // `minsd` and `maxsd` return the second operand unless
+                // the comparison holds, just like `Min` and `Max`.
+                Op::Min | Op::Max => {
+                    let opcode = if *op == Op::Min { MINSD } else { MAXSD };
+                    asm.sse(MOVSD_LOAD, 0, RBP, slot(sp - 2));
+                    asm.sse(opcode, 0, RBP, slot(sp - 1));
+                    asm.sse(MOVSD_STORE, 0, RBP, slot(sp - 2));
+                    sp -= 1;
+                }
+                // The mask of `a < b`, or of `b < a` for `Gt`, anded with 1.0
+                Op::Lt | Op::Gt => {
+                    let (a, b) = if *op == Op::Lt { (2, 1) } else { (1, 2) };
+                    asm.sse(MOVSD_LOAD, 0, RBP, slot(sp - a));
+                    asm.sse(CMPSD, 0, RBP, slot(sp - b));
+                    asm.code.push(LT);
+                    asm.sse(MOVSD_STORE, 0, RBP, slot(sp - 2));
+                    asm.mov_rax(1.0f64.to_bits());
+                    asm.alu_slot_rax(0x21, slot(sp - 2)); // and
+                    sp -= 1;
+                }
+                Op::Pow => {
+                    asm.sse(MOVSD_LOAD, 0, RBP, slot(sp - 2));
+                    asm.sse(MOVSD_LOAD, 1, RBP, slot(sp - 1));
+                    let pow: extern "C" fn(f64, f64) -> f64 = pow;
+                    asm.call(pow as usize);
+                    asm.sse(MOVSD_STORE, 0, RBP, slot(sp - 2));
+                    sp -= 1;
+                }
+                Op::Neg => {
+                    asm.mov_rax(1 << 63);
+                    asm.alu_slot_rax(0x31, slot(sp - 1)); // xor
+                }
+                Op::Dup => {
+                    asm.sse(MOVSD_LOAD, 0, RBP, slot(sp - 1));
+                    asm.sse(MOVSD_STORE, 0, RBP, slot(sp));
+                    sp += 1;
+                }
+                Op::Label => (),
+                Op::Jmp(_) | Op::Je(_) | Op::Jne(_) | Op::Js(_) | Op::Jns(_) => return None,
+                Op::Call(f) => match f {
+                    BuiltinFunction::Abs => {
+                        asm.mov_rax(!(1 << 63));
+                        asm.alu_slot_rax(0x21, slot(sp - 1)); // and
+                    }
+                    BuiltinFunction::Loge => asm.call_unary(ln, slot(sp - 1)),
+                    BuiltinFunction::Log2 => asm.call_unary(log2, slot(sp - 1)),
+                    BuiltinFunction::Log10 => asm.call_unary(log10, slot(sp - 1)),
+                    BuiltinFunction::Sin => asm.call_unary(sin, slot(sp - 1)),
+                    BuiltinFunction::Cos => asm.call_unary(cos, slot(sp - 1)),
+                    BuiltinFunction::Tan => asm.call_unary(tan, slot(sp - 1)),
+                },
+            }
+        }
+
+        asm.sse(MOVSD_LOAD, 0, RBP, slot(0));
+        asm.code.extend_from_slice(&[0x48, 0x81, 0xc4]); // add rsp, frame
+        asm.code.extend_from_slice(&frame);
+        asm.code.extend_from_slice(&[0x5d, 0x5b, 0xc3]); // pop rbp; pop rbx; ret
+        Some(asm.code)
+    }
+}
+
+#[cfg(not(all(feature = "jit", target_arch = "x86_64", target_os = "linux")))]
+mod native {
+    use super::super::Program;
+
+    pub enum Function {}
+
+    impl Function {
+        pub fn compile(_program: &Program) -> Option<Function> {
+            None
+        }
+
+        pub fn n_inputs(&self) -> usize {
+            match *self {}
+        }
+
+        pub fn call(&self, _inputs: &[f64]) -> f64 {
+            match *self {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{expr::Expr, vm::compile_expr};
+
+    #[test]
+    fn deep_stack() {
+        for depth in [500, 501, 100_000] {
+            let mut src = "PUSH 1\n".repeat(depth);
+            src += &"ADD\n".repeat(depth - 1);
+            let jit = JitProgram::new(Program::from_asm(&src).unwrap());
+            let supported = cfg!(all(
+                feature = "jit",
+                target_arch = "x86_64",
+                target_os = "linux"
+            ));
+            assert_eq!(jit.is_native(), supported && depth <= 500);
+            assert_eq!(jit.evaluate(&[]).unwrap(), depth as f64);
+        }
+    }
+
+    #[test]
+    fn matches_interpreter() {
+        let n_vars = 4;
+        for _ in 0..10_000 {
+            let vars: Vec<f64> = (0..n_vars)
+                .map(|_| rand::random::<f64>() * 4.0 - 2.0)
+                .collect();
+            let mut expr = Expr::new(n_vars);
+            expr.random_tree(10);
+            let program = compile_expr(&expr);
+            let vm_eval = program.evaluate(&vars).unwrap();
+
+            let jit = JitProgram::new(program);
+            let supported = cfg!(all(
+                feature = "jit",
+                target_arch = "x86_64",
+                target_os = "linux"
+            ));
+            assert_eq!(jit.is_native(), supported && !expr.has_conditional());
+            let jit_eval = jit.evaluate(&vars).unwrap();
+
+            assert!(
+                jit_eval.to_bits() == vm_eval.to_bits() || (jit_eval.is_nan() && vm_eval.is_nan()),
+                "{}: {jit_eval} != {vm_eval}",
+                expr.rpn()
+            );
+        }
+    }
+}
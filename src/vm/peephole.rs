@@ -0,0 +1,258 @@
+use super::{Op, Program, Value};
+
+impl Program {
+    /// Peephole optimized copy of the program. Folds constants, drops
+    /// operations that don't change the value, turns squaring into
+    /// `Dup; Mul`, removes unreachable code and reuses repeated pushes
+    /// through `Dup`. The result is the same for every input, except
+    /// `x^2` and `x * x` may round differently in the last place.
+    pub fn optimize(&self) -> Program {
+        let mut ops = self.ops.clone();
+        loop {
+            let len = ops.len();
+            ops = eliminate_dead_code(fold(ops));
+            if ops.len() == len {
+                break;
+            }
+        }
+
+        let ops = use_dup(ops);
+        let jump_table = ops
+            .iter()
+            .enumerate()
+            .filter(|(_, op)| matches!(op, Op::Label))
+            .map(|(i, _)| i)
+            .collect();
+        Program { ops, jump_table }
+    }
+}
+
+fn is_binary(op: &Op) -> bool {
+    matches!(
+        op,
+        Op::Add | Op::Sub | Op::Mul | Op::Div | Op::Pow | Op::Min | Op::Max | Op::Lt | Op::Gt
+    )
+}
+
+// Runs constant ops through the interpreter,
+// so folding can't change the semantics.
+fn run(ops: Vec<Op>) -> Option<f64> {
+    let program = Program {
+        ops,
+        jump_table: Vec::new(),
+    };
+    program.evaluate(&[]).ok()
+}
+
+fn fold(ops: Vec<Op>) -> Vec<Op> {
+    let mut out: Vec<Op> = Vec::with_capacity(ops.len());
+
+    for op in ops {
+        out.push(op);
+
+        // Keep reducing the tail until nothing matches
+        loop {
+            let n = out.len();
+            let tail = |i: usize| n.checked_sub(i).map(|i| &out[i]);
+
+            match (tail(3), tail(2), tail(1)) {
+                (
+                    Some(Op::Push(Value::Literal(a))),
+                    Some(Op::Push(Value::Literal(b))),
+                    Some(op),
+                ) if is_binary(op) => {
+                    let ops = vec![
+                        Op::Push(Value::Literal(*a)),
+                        Op::Push(Value::Literal(*b)),
+                        op.clone(),
+                    ];
+                    let x = run(ops).unwrap();
+                    out.truncate(n - 3);
+                    out.push(Op::Push(Value::Literal(x)));
+                }
+                (_, Some(Op::Push(Value::Literal(a))), Some(op @ (Op::Neg | Op::Call(_)))) => {
+                    let x = run(vec![Op::Push(Value::Literal(*a)), op.clone()]).unwrap();
+                    out.truncate(n - 2);
+                    out.push(Op::Push(Value::Literal(x)));
+                }
+                (_, Some(Op::Neg), Some(Op::Neg)) => out.truncate(n - 2),
+
+                // x * 1, x / 1, x^1 and x - 0
+                (_, Some(Op::Push(Value::Literal(c))), Some(Op::Mul | Op::Div | Op::Pow))
+                    if *c == 1.0 =>
+                {
+                    out.truncate(n - 2)
+                }
+                (_, Some(Op::Push(Value::Literal(c))), Some(Op::Sub)) if *c == 0.0 => {
+                    out.truncate(n - 2)
+                }
+
+                (_, Some(Op::Push(Value::Literal(c))), Some(Op::Pow)) if *c == 2.0 => {
+                    out.truncate(n - 2);
+                    out.extend([Op::Dup, Op::Mul]);
+                }
+
+                // Jumps on a constant condition
+                (_, Some(Op::Push(Value::Literal(c))), Some(jump)) => {
+                    let taken = match jump {
+                        Op::Je(_) => *c == 0.0,
+                        Op::Jne(_) => *c != 0.0,
+                        Op::Js(_) => *c < 0.0,
+                        Op::Jns(_) => *c >= 0.0,
+                        _ => break,
+                    };
+                    let label = match jump {
+                        Op::Je(l) | Op::Jne(l) | Op::Js(l) | Op::Jns(l) => *l,
+                        _ => unreachable!(),
+                    };
+                    out.truncate(n - 2);
+                    if taken {
+                        out.push(Op::Jmp(label));
+                    }
+                }
+                _ => break,
+            }
+        }
+    }
+
+    out
+}
+
+// Drops code after an unconditional jump up to the next label,
+// and jumps to the label right after them. Labels are kept, as
+// they are numbered by their order.
+fn eliminate_dead_code(ops: Vec<Op>) -> Vec<Op> {
+    let mut out = Vec::with_capacity(ops.len());
+    let mut reachable = true;
+    let mut label = 0;
+
+    for op in ops {
+        if let Op::Label = op {
+            if let Some(Op::Jmp(l)) = out.last() {
+                if *l == label {
+                    out.pop();
+                }
+            }
+            label += 1;
+            reachable = true;
+        } else if !reachable {
+            continue;
+        }
+
+        if let Op::Jmp(_) = op {
+            reachable = false;
+        }
+        out.push(op);
+    }
+
+    out
+}
+
+// `Push v; Push v` to `Push v; Dup`.
+fn use_dup(ops: Vec<Op>) -> Vec<Op> {
+    let mut out: Vec<Op> = Vec::with_capacity(ops.len());
+
+    for op in ops {
+        let repeated = match (out.last(), &op) {
+            (Some(Op::Push(Value::Literal(a))), Op::Push(Value::Literal(b))) => {
+                a.to_bits() == b.to_bits()
+            }
+            (Some(Op::Push(Value::Ptr(a))), Op::Push(Value::Ptr(b))) => a == b,
+            _ => false,
+        };
+        out.push(if repeated { Op::Dup } else { op });
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        expr::Expr,
+        vm::{compile_expr, BuiltinFunction},
+    };
+
+    fn optimize(src: &str) -> Vec<Op> {
+        let expr = Expr::from_infix(src, 1, &["x"]).unwrap();
+        compile_expr(&expr).optimize().ops
+    }
+
+    #[test]
+    fn rules() {
+        let x = || Op::Push(Value::Ptr(0));
+        let literal = |c| Op::Push(Value::Literal(c));
+
+        assert_eq!(optimize("x + (2 + 3)"), [x(), literal(5.0), Op::Add]);
+        assert_eq!(optimize("x * sin(0)"), [x(), literal(0.0), Op::Mul]);
+        assert_eq!(optimize("x * max(2, 3 < 4)"), [x(), literal(2.0), Op::Mul]);
+        assert_eq!(optimize("-(-(x))"), [x()]);
+        assert_eq!(optimize("(x - 0) / 1 * 1"), [x()]);
+        assert_eq!(optimize("x^2"), [x(), Op::Dup, Op::Mul]);
+        assert_eq!(optimize("x * x"), [x(), Op::Dup, Op::Mul]);
+        assert_eq!(
+            optimize("ln(x)^(1 + 1)"),
+            [x(), Op::Call(BuiltinFunction::Loge), Op::Dup, Op::Mul]
+        );
+    }
+
+    #[test]
+    fn jumps() {
+        // 1 if 2 < 0, else $0 + 1
+        let program = Program {
+            ops: vec![
+                Op::Push(Value::Literal(2.0)),
+                Op::Jns(0),
+                Op::Push(Value::Literal(1.0)),
+                Op::Jmp(1),
+                Op::Label,
+                Op::Push(Value::Ptr(0)),
+                Op::Push(Value::Literal(1.0)),
+                Op::Add,
+                Op::Label,
+            ],
+            jump_table: vec![4, 8],
+        };
+
+        let optimized = program.optimize();
+        assert_eq!(
+            optimized.ops,
+            [
+                Op::Label,
+                Op::Push(Value::Ptr(0)),
+                Op::Push(Value::Literal(1.0)),
+                Op::Add,
+                Op::Label,
+            ]
+        );
+        assert_eq!(optimized.jump_table, [0, 4]);
+        assert_eq!(optimized.evaluate(&[3.0]), Ok(4.0));
+    }
+
+    #[test]
+    fn equivalence() {
+        let n_vars = 10;
+        for _ in 0..10_000 {
+            let vars: Vec<f64> = (0..n_vars).map(|_| rand::random::<f64>()).collect();
+            let mut expr = Expr::new(n_vars);
+            expr.random_tree(10);
+            let compiled_expr = compile_expr(&expr);
+            let optimized = compiled_expr.optimize();
+            assert!(optimized.ops.len() <= compiled_expr.ops.len());
+
+            let expr_eval = expr.evaluate(&vars);
+            let vm_eval = optimized.evaluate(&vars).expect("program should be valid");
+
+            if expr_eval.is_nan() && vm_eval.is_nan() {
+                continue;
+            }
+
+            // Squaring by `Mul` instead of `powf` can differ in the
+            // last place, which the rest of the expression amplifies.
+            let close = expr_eval == vm_eval
+                || (expr_eval - vm_eval).abs() <= 1e-9 * expr_eval.abs().max(1.0);
+            assert!(close, "{}: {expr_eval} != {vm_eval}", expr.rpn());
+        }
+    }
+}
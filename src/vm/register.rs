@@ -0,0 +1,220 @@
+use std::{collections::HashMap, fmt};
+
+use crate::{
+    expr::{BinaryOp, Expr, Node, UnaryOp},
+    format::function_name,
+};
+
+// Instruction `i` writes register `i`, so every register is
+// assigned exactly once and operands always refer backwards.
+#[derive(Debug, Clone)]
+enum Instruction {
+    Literal(f64),
+    Input(usize),
+    Unary(UnaryOp, usize),
+    Binary(BinaryOp, usize, usize),
+
+    // Condition, then and otherwise. Both branches are computed,
+    // as they may be shared with the rest of the program.
+    IfPositive(usize, usize, usize),
+}
+
+/// Register based alternative to the stack `Program`, compiled from
+/// the DAG of an expression. Identical subexpressions are hash-consed
+/// into the same register, so each of them is computed only once.
+#[derive(Debug, Clone)]
+pub struct RegisterProgram {
+    instructions: Vec<Instruction>,
+    result: usize,
+}
+
+impl RegisterProgram {
+    /// The value for one row of inputs.
+    pub fn evaluate(&self, inputs: &[f64]) -> f64 {
+        let mut registers = Vec::with_capacity(self.instructions.len());
+
+        for instruction in &self.instructions {
+            let value = match instruction {
+                Instruction::Literal(x) => *x,
+                Instruction::Input(ptr) => inputs[*ptr],
+                Instruction::Unary(op, a) => op.apply(registers[*a]),
+                Instruction::Binary(op, a, b) => op.apply(registers[*a], registers[*b]),
+                Instruction::IfPositive(cond, then, otherwise) => {
+                    if registers[*cond] > 0.0 {
+                        registers[*then]
+                    } else {
+                        registers[*otherwise]
+                    }
+                }
+            };
+            registers.push(value);
+        }
+
+        registers[self.result]
+    }
+
+    /// Number of instructions.
+    pub fn len(&self) -> usize {
+        self.instructions.len()
+    }
+
+    /// Has no instructions?
+    pub fn is_empty(&self) -> bool {
+        self.instructions.is_empty()
+    }
+}
+
+impl fmt::Display for RegisterProgram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, instruction) in self.instructions.iter().enumerate() {
+            write!(f, "r{i} = ")?;
+            match instruction {
+                Instruction::Literal(x) => writeln!(f, "{x}")?,
+                Instruction::Input(ptr) => writeln!(f, "${ptr}")?,
+                Instruction::Unary(UnaryOp::Neg, a) => writeln!(f, "neg r{a}")?,
+                Instruction::Unary(op, a) => writeln!(f, "{} r{a}", function_name(op))?,
+                Instruction::Binary(op, a, b) => {
+                    let name = match op {
+                        BinaryOp::Add => "add",
+                        BinaryOp::Sub => "sub",
+                        BinaryOp::Mul => "mul",
+                        BinaryOp::Div => "div",
+                        BinaryOp::Pow => "pow",
+                        BinaryOp::Lt => "lt",
+                        BinaryOp::Gt => "gt",
+                        BinaryOp::Min => "min",
+                        BinaryOp::Max => "max",
+                    };
+                    writeln!(f, "{name} r{a}, r{b}")?
+                }
+                Instruction::IfPositive(cond, then, otherwise) => {
+                    writeln!(f, "if_positive r{cond}, r{then}, r{otherwise}")?
+                }
+            }
+        }
+        write!(f, "return r{}", self.result)
+    }
+}
+
+// Structural identity of an instruction, with
+// literals compared by their bit pattern.
+#[derive(PartialEq, Eq, Hash)]
+enum Key {
+    Literal(u64),
+    Input(usize),
+    Unary(u8, usize),
+    Binary(u8, usize, usize),
+    IfPositive(usize, usize, usize),
+}
+
+/// Compiles the tree, giving each distinct subexpression a register.
+pub fn compile_registers(expr: &Expr) -> RegisterProgram {
+    let mut compiler = Compiler {
+        expr,
+        instructions: Vec::new(),
+        registers: HashMap::new(),
+        compiled: vec![None; expr.nodes.len()],
+    };
+    let result = compiler.compile(expr.root);
+
+    RegisterProgram {
+        instructions: compiler.instructions,
+        result,
+    }
+}
+
+struct Compiler<'a> {
+    expr: &'a Expr,
+    instructions: Vec<Instruction>,
+
+    // Hash-consing table from structure to register
+    registers: HashMap<Key, usize>,
+
+    // Register of every node already compiled
+    compiled: Vec<Option<usize>>,
+}
+
+impl Compiler<'_> {
+    fn compile(&mut self, node: usize) -> usize {
+        if let Some(register) = self.compiled[node] {
+            return register;
+        }
+
+        let (key, instruction) = match &self.expr.nodes[node] {
+            Node::Number(x) => (Key::Literal(x.to_bits()), Instruction::Literal(*x)),
+            Node::Variable(ptr) => (Key::Input(*ptr), Instruction::Input(*ptr)),
+            Node::UnOp(op) => {
+                let a = self.compile(op.a);
+                (
+                    Key::Unary(op.op.clone() as u8, a),
+                    Instruction::Unary(op.op.clone(), a),
+                )
+            }
+            Node::BinOp(op) => {
+                let mut a = self.compile(op.a);
+                let mut b = self.compile(op.b);
+
+                // `a + b` and `b + a` are the same bits. Not so for
+                // `min` and `max`, which return the second on NaN.
+                if matches!(op.op, BinaryOp::Add | BinaryOp::Mul) && b < a {
+                    (a, b) = (b, a);
+                }
+                (
+                    Key::Binary(op.op.clone() as u8, a, b),
+                    Instruction::Binary(op.op.clone(), a, b),
+                )
+            }
+            Node::IfPositive(op) => {
+                let cond = self.compile(op.cond);
+                let then = self.compile(op.then);
+                let otherwise = self.compile(op.otherwise);
+                (
+                    Key::IfPositive(cond, then, otherwise),
+                    Instruction::IfPositive(cond, then, otherwise),
+                )
+            }
+        };
+
+        let register = *self.registers.entry(key).or_insert_with(|| {
+            self.instructions.push(instruction);
+            self.instructions.len() - 1
+        });
+        self.compiled[node] = Some(register);
+        register
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sharing() {
+        let expr = Expr::from_infix("(x + 1) * (1 + x) - sin(x + 1)", 1, &["x"]).unwrap();
+        let program = compile_registers(&expr);
+        assert_eq!(program.len(), 6);
+        assert_eq!(
+            program.to_string(),
+            "r0 = $0\nr1 = 1\nr2 = add r0, r1\nr3 = mul r2, r2\nr4 = sin r2\nr5 = sub r3, r4\nreturn r5"
+        );
+        assert_eq!(program.evaluate(&[2.0]), expr.evaluate(&[2.0]));
+    }
+
+    #[test]
+    fn compiler() {
+        let n_vars = 10;
+        for _ in 0..10_000 {
+            let vars: Vec<f64> = (0..n_vars).map(|_| rand::random::<f64>()).collect();
+            let mut expr = Expr::new(n_vars);
+            expr.random_tree(10);
+            let program = compile_registers(&expr);
+
+            let expr_eval = expr.evaluate(&vars);
+            let register_eval = program.evaluate(&vars);
+            if expr_eval.is_nan() && register_eval.is_nan() {
+                continue;
+            }
+            assert_eq!(expr_eval, register_eval, "{}\n{program}", expr.rpn());
+        }
+    }
+}
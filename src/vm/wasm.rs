@@ -0,0 +1,462 @@
+use super::{BuiltinFunction, Op, Program, Value, VmError};
+
+// Functions the module imports from the host, under `math`. They
+// are named like their counterparts in JavaScript's `Math`, so
+// a browser can instantiate the module with `{ math: Math }`.
+// `abs` is an instruction of its own.
+const IMPORTS: [&str; 7] = ["pow", "log", "log2", "log10", "sin", "cos", "tan"];
+
+fn import_name(op: &Op) -> Option<&'static str> {
+    match op {
+        Op::Pow => Some("pow"),
+        Op::Call(function) => match function {
+            BuiltinFunction::Abs => None,
+            BuiltinFunction::Loge => Some("log"),
+            BuiltinFunction::Log2 => Some("log2"),
+            BuiltinFunction::Log10 => Some("log10"),
+            BuiltinFunction::Sin => Some("sin"),
+            BuiltinFunction::Cos => Some("cos"),
+            BuiltinFunction::Tan => Some("tan"),
+        },
+        _ => None,
+    }
+}
+
+// Types, by their index in the type section
+const PREDICT_TYPE: u32 = 0;
+const UNARY_TYPE: u32 = 1;
+const BINARY_TYPE: u32 = 2;
+
+const F64: u8 = 0x7c;
+const EMPTY_BLOCK: u8 = 0x40;
+const FUNC: u8 = 0x00;
+
+// Instructions
+const BLOCK: u8 = 0x02;
+const END: u8 = 0x0b;
+const BR: u8 = 0x0c;
+const BR_IF: u8 = 0x0d;
+const CALL: u8 = 0x10;
+const SELECT: u8 = 0x1b;
+const LOCAL_GET: u8 = 0x20;
+const LOCAL_SET: u8 = 0x21;
+const F64_CONST: u8 = 0x44;
+const F64_EQ: u8 = 0x61;
+const F64_NE: u8 = 0x62;
+const F64_LT: u8 = 0x63;
+const F64_GT: u8 = 0x64;
+const F64_GE: u8 = 0x66;
+const I32_AND: u8 = 0x71;
+const I32_OR: u8 = 0x72;
+const F64_ABS: u8 = 0x99;
+const F64_NEG: u8 = 0x9a;
+const F64_ADD: u8 = 0xa0;
+const F64_SUB: u8 = 0xa1;
+const F64_MUL: u8 = 0xa2;
+const F64_DIV: u8 = 0xa3;
+const F64_CONVERT_I32_U: u8 = 0xb8;
+
+// Unsigned LEB128, which the format uses for all integers.
+fn write_u32(out: &mut Vec<u8>, mut x: u32) {
+    loop {
+        let byte = (x & 0x7f) as u8;
+        x >>= 7;
+        if x == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn write_name(out: &mut Vec<u8>, name: &str) {
+    write_u32(out, name.len() as u32);
+    out.extend_from_slice(name.as_bytes());
+}
+
+fn write_f64(out: &mut Vec<u8>, x: f64) {
+    out.push(F64_CONST);
+    out.extend_from_slice(&x.to_le_bytes());
+}
+
+fn write_section(out: &mut Vec<u8>, id: u8, content: &[u8]) {
+    out.push(id);
+    write_u32(out, content.len() as u32);
+    out.extend_from_slice(content);
+}
+
+fn write_func_type(out: &mut Vec<u8>, n_params: usize) {
+    out.push(0x60);
+    write_u32(out, n_params as u32);
+    out.extend(std::iter::repeat_n(F64, n_params));
+    write_u32(out, 1);
+    out.push(F64);
+}
+
+impl Program {
+    /// A WebAssembly module exporting `predict`, which takes the
+    /// `n_inputs` inputs as `f64` parameters and returns the result.
+    ///
+    /// The functions are imported from the host as `math`, e.g.
+    /// JavaScript's `Math`. `Math.pow` differs from `powf` only for
+    /// `1^NaN` and `(±1)^±inf`, which the module handles itself.
+    ///
+    /// Like the C source, the stack lives in one local per slot.
+    /// Jumps only go forward, so every label closes a block opened
+    /// at the start of the function, the first label innermost, and
+    /// a jump breaks out to the end of the block of its label.
+    pub fn to_wasm(&self, n_inputs: usize) -> Result<Vec<u8>, VmError> {
+        let depths = self.stack_depths(n_inputs)?;
+        let max_depth = depths.iter().flatten().max().copied().unwrap();
+
+        let imports: Vec<&str> = IMPORTS
+            .into_iter()
+            .filter(|name| self.ops.iter().any(|op| import_name(op) == Some(*name)))
+            .collect();
+        let function_index = |op: &Op| {
+            let name = import_name(op).unwrap();
+            imports.iter().position(|n| *n == name).unwrap() as u32
+        };
+
+        let mut types = Vec::new();
+        write_u32(&mut types, 3);
+        write_func_type(&mut types, n_inputs);
+        write_func_type(&mut types, 1);
+        write_func_type(&mut types, 2);
+
+        let mut import_section = Vec::new();
+        write_u32(&mut import_section, imports.len() as u32);
+        for name in &imports {
+            write_name(&mut import_section, "math");
+            write_name(&mut import_section, name);
+            import_section.push(FUNC);
+            let ty = if *name == "pow" {
+                BINARY_TYPE
+            } else {
+                UNARY_TYPE
+            };
+            write_u32(&mut import_section, ty);
+        }
+
+        let mut functions = Vec::new();
+        write_u32(&mut functions, 1);
+        write_u32(&mut functions, PREDICT_TYPE);
+
+        // The function comes after the imports
+        let mut exports = Vec::new();
+        write_u32(&mut exports, 1);
+        write_name(&mut exports, "predict");
+        exports.push(FUNC);
+        write_u32(&mut exports, imports.len() as u32);
+
+        // Parameters come first among the locals
+        let slot = |i: usize| (n_inputs + i) as u32;
+
+        let mut body = Vec::new();
+        write_u32(&mut body, 1);
+        write_u32(&mut body, max_depth as u32);
+        body.push(F64);
+        for _ in &self.jump_table {
+            body.push(BLOCK);
+            body.push(EMPTY_BLOCK);
+        }
+
+        let mut label_counter = 0;
+        for (op, depth) in self.ops.iter().zip(&depths) {
+            if *op == Op::Label {
+                body.push(END);
+                label_counter += 1;
+                continue;
+            }
+            // Dead code after an unconditional jump
+            let Some(depth) = *depth else {
+                continue;
+            };
+
+            // The top of the stack, and the value below it
+            let top = depth.wrapping_sub(1);
+            let below = depth.wrapping_sub(2);
+            match op {
+                Op::Push(Value::Literal(x)) => {
+                    write_f64(&mut body, *x);
+                    body.push(LOCAL_SET);
+                    write_u32(&mut body, slot(depth));
+                }
+                Op::Push(Value::Ptr(ptr)) => {
+                    body.push(LOCAL_GET);
+                    write_u32(&mut body, *ptr as u32);
+                    body.push(LOCAL_SET);
+                    write_u32(&mut body, slot(depth));
+                }
+                Op::Add | Op::Sub | Op::Mul | Op::Div => {
+                    body.push(LOCAL_GET);
+                    write_u32(&mut body, slot(below));
+                    body.push(LOCAL_GET);
+                    write_u32(&mut body, slot(top));
+                    body.push(match op {
+                        Op::Add => F64_ADD,
+                        Op::Sub => F64_SUB,
+                        Op::Mul => F64_MUL,
+                        _ => F64_DIV,
+                    });
+                    body.push(LOCAL_SET);
+                    write_u32(&mut body, slot(below));
+                }
+                // `Math.pow` gives NaN for `1^NaN` and `(±1)^±inf`, where
+                // `powf` gives 1, so those select 1 instead of the call:
+                // `|a| == 1 && (a == 1 || |b| == inf) ? 1 : pow(a, b)`
+                Op::Pow => {
+                    write_f64(&mut body, 1.0);
+                    body.push(LOCAL_GET);
+                    write_u32(&mut body, slot(below));
+                    body.push(LOCAL_GET);
+                    write_u32(&mut body, slot(top));
+                    body.push(CALL);
+                    write_u32(&mut body, function_index(op));
+
+                    body.push(LOCAL_GET);
+                    write_u32(&mut body, slot(below));
+                    body.push(F64_ABS);
+                    write_f64(&mut body, 1.0);
+                    body.push(F64_EQ);
+                    body.push(LOCAL_GET);
+                    write_u32(&mut body, slot(below));
+                    write_f64(&mut body, 1.0);
+                    body.push(F64_EQ);
+                    body.push(LOCAL_GET);
+                    write_u32(&mut body, slot(top));
+                    body.push(F64_ABS);
+                    write_f64(&mut body, f64::INFINITY);
+                    body.extend([F64_EQ, I32_OR, I32_AND, SELECT]);
+
+                    body.push(LOCAL_SET);
+                    write_u32(&mut body, slot(below));
+                }
+                // `f64.min` and `f64.max` differ on NaN and signed
+                // zeros, so `Min` and `Max` select on the comparison.
+                Op::Min | Op::Max | Op::Lt | Op::Gt => {
+                    if matches!(op, Op::Min | Op::Max) {
+                        body.push(LOCAL_GET);
+                        write_u32(&mut body, slot(below));
+                        body.push(LOCAL_GET);
+                        write_u32(&mut body, slot(top));
+                    }
+                    body.push(LOCAL_GET);
+                    write_u32(&mut body, slot(below));
+                    body.push(LOCAL_GET);
+                    write_u32(&mut body, slot(top));
+                    match op {
+                        Op::Min => body.extend([F64_LT, SELECT]),
+                        Op::Max => body.extend([F64_GT, SELECT]),
+                        Op::Lt => body.extend([F64_LT, F64_CONVERT_I32_U]),
+                        _ => body.extend([F64_GT, F64_CONVERT_I32_U]),
+                    }
+                    body.push(LOCAL_SET);
+                    write_u32(&mut body, slot(below));
+                }
+                Op::Neg | Op::Call(_) => {
+                    body.push(LOCAL_GET);
+                    write_u32(&mut body, slot(top));
+                    match op {
+                        Op::Neg => body.push(F64_NEG),
+                        Op::Call(BuiltinFunction::Abs) => body.push(F64_ABS),
+                        _ => {
+                            body.push(CALL);
+                            write_u32(&mut body, function_index(op));
+                        }
+                    }
+                    body.push(LOCAL_SET);
+                    write_u32(&mut body, slot(top));
+                }
+                Op::Dup => {
+                    body.push(LOCAL_GET);
+                    write_u32(&mut body, slot(top));
+                    body.push(LOCAL_SET);
+                    write_u32(&mut body, slot(depth));
+                }
+                Op::Jmp(label) => {
+                    body.push(BR);
+                    write_u32(&mut body, (label - label_counter) as u32);
+                }
+                Op::Je(label) | Op::Jne(label) | Op::Js(label) | Op::Jns(label) => {
+                    body.push(LOCAL_GET);
+                    write_u32(&mut body, slot(top));
+                    write_f64(&mut body, 0.0);
+                    body.push(match op {
+                        Op::Je(_) => F64_EQ,
+                        Op::Jne(_) => F64_NE,
+                        Op::Js(_) => F64_LT,
+                        _ => F64_GE,
+                    });
+                    body.push(BR_IF);
+                    write_u32(&mut body, (label - label_counter) as u32);
+                }
+                Op::Label => unreachable!(),
+            }
+        }
+        body.push(LOCAL_GET);
+        write_u32(&mut body, slot(0));
+        body.push(END);
+
+        let mut code = Vec::new();
+        write_u32(&mut code, 1);
+        write_u32(&mut code, body.len() as u32);
+        code.extend_from_slice(&body);
+
+        let mut module = b"\0asm".to_vec();
+        module.extend_from_slice(&1u32.to_le_bytes());
+        write_section(&mut module, 1, &types);
+        write_section(&mut module, 2, &import_section);
+        write_section(&mut module, 3, &functions);
+        write_section(&mut module, 7, &exports);
+        write_section(&mut module, 10, &code);
+        Ok(module)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, process::Command};
+
+    use wasmparser::{Parser, Payload, TypeRef};
+
+    use super::*;
+    use crate::{expr::Expr, vm::compile_expr};
+
+    #[test]
+    fn leb128() {
+        for (x, bytes) in [
+            (0, vec![0x00]),
+            (127, vec![0x7f]),
+            (128, vec![0x80, 0x01]),
+            (624485, vec![0xe5, 0x8e, 0x26]),
+        ] {
+            let mut out = Vec::new();
+            write_u32(&mut out, x);
+            assert_eq!(out, bytes);
+        }
+    }
+
+    #[test]
+    fn validates() {
+        for _ in 0..1_000 {
+            let mut expr = Expr::new(3);
+            expr.random_tree(8);
+            for program in [compile_expr(&expr), compile_expr(&expr).optimize()] {
+                let module = program.to_wasm(3).unwrap();
+                if let Err(err) = wasmparser::validate(&module) {
+                    panic!("{err}\n{program}");
+                }
+            }
+        }
+
+        // Only the functions used are imported
+        let expr = Expr::from_infix("max(abs(x), y^2) / sin(x)", 2, &["x", "y"]).unwrap();
+        let module = compile_expr(&expr).to_wasm(2).unwrap();
+        let mut imports = Vec::new();
+        let mut exports = Vec::new();
+        for payload in Parser::new(0).parse_all(&module) {
+            match payload.unwrap() {
+                Payload::ImportSection(reader) => {
+                    for import in reader.into_imports() {
+                        let import = import.unwrap();
+                        assert_eq!(import.module, "math");
+                        assert!(matches!(import.ty, TypeRef::Func(_)));
+                        imports.push(import.name.to_string());
+                    }
+                }
+                Payload::ExportSection(reader) => {
+                    for export in reader {
+                        let export = export.unwrap();
+                        exports.push((export.name.to_string(), export.index));
+                    }
+                }
+                _ => (),
+            }
+        }
+        assert_eq!(imports, ["pow", "sin"]);
+        assert_eq!(exports, [("predict".to_string(), 2)]);
+
+        assert_eq!(
+            compile_expr(&expr).to_wasm(1).unwrap_err(),
+            VmError::OutOfBounds { pc: 2, ptr: 1 }
+        );
+    }
+
+    #[test]
+    fn matches_interpreter() {
+        // Runs the modules in Node.js, when it's installed
+        if Command::new("node").arg("--version").output().is_err() {
+            return;
+        }
+
+        let exprs = [
+            "-x^2 / abs(y - 1.5) + ln(z) * log2(z) - log10(sin(x)) * cos(y)^-tan(-0.5 * z)",
+            "if_positive(x - 1, min(x, y), 2) * -(x < y) + max(z, y) * (z > 0.5)",
+            // `1^NaN`, `(-1)^±inf` and `1^±inf`, which `Math.pow` gets wrong
+            "1^(y / 0 - y / 0) + (-1)^(y / 0) + (x / x)^(z / 0) + x^y",
+        ];
+        let n_rows = 50;
+        let inputs: Vec<f64> = (0..3 * n_rows)
+            .map(|_| rand::random::<f64>() * 4.0 - 2.0)
+            .collect();
+
+        let dir = std::env::temp_dir().join(format!("symreg-wasm-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut programs = Vec::new();
+        let mut paths = Vec::new();
+        for (i, src) in exprs.iter().enumerate() {
+            let expr = Expr::from_infix(src, 3, &["x", "y", "z"]).unwrap();
+            for program in [compile_expr(&expr), compile_expr(&expr).optimize()] {
+                let path = dir.join(format!("model{i}_{}.wasm", programs.len()));
+                fs::write(&path, program.to_wasm(3).unwrap()).unwrap();
+                paths.push(format!("{:?}", path.to_str().unwrap()));
+                programs.push(program);
+            }
+        }
+
+        let values: Vec<String> = inputs.iter().map(|x| format!("{x:?}")).collect();
+        let script = format!(
+            "const fs = require('fs');\n\
+             const inputs = [{}];\n\
+             for (const path of [{}]) {{\n    \
+             const module = new WebAssembly.Module(fs.readFileSync(path));\n    \
+             const {{ predict }} = new WebAssembly.Instance(module, {{ math: Math }}).exports;\n    \
+             for (let i = 0; i < inputs.length; i += 3) {{\n        \
+             console.log(String(predict(inputs[i], inputs[i + 1], inputs[i + 2])));\n    \
+             }}\n\
+             }}\n",
+            values.join(", "),
+            paths.join(", ")
+        );
+        let output = Command::new("node")
+            .arg("-e")
+            .arg(&script)
+            .output()
+            .unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let mut lines = stdout.lines();
+        for program in &programs {
+            for row in inputs.chunks(3) {
+                let result: f64 = lines.next().unwrap().parse().unwrap();
+                let expected = program.evaluate(row).unwrap();
+                if expected.is_nan() {
+                    assert!(result.is_nan());
+                } else {
+                    // JavaScript has its own implementation of the
+                    // math functions, which may differ in the last bit
+                    let tolerance = 1e-9 * expected.abs().max(1.0);
+                    assert!(result == expected || (result - expected).abs() < tolerance);
+                }
+            }
+        }
+        assert_eq!(lines.next(), None);
+    }
+}
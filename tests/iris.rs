@@ -0,0 +1,58 @@
+use symreg_rs::{
+    categorize_cols, genetic_optimizer, nsga2_optimizer,
+    optimizer::{ConstantFitting, Selection},
+    DataLoader, GeneticParameters, Vec2d,
+};
+
+fn iris() -> (Vec2d<f64>, Vec<f64>) {
+    let data_loader = DataLoader::new("data/IRIS.csv").unwrap();
+    let mut data = data_loader.vec2d();
+    let _headers = data.pop_head();
+    categorize_cols(data).split_right()
+}
+
+#[test]
+fn genetic() {
+    let (x, y) = iris();
+    let params = GeneticParameters {
+        population_size: 1000,
+        selection: Selection::Truncation(0.1),
+        mutation_rate: 0.1,
+        crossover_rate: 0.5,
+        elitism: 1,
+        hall_of_fame_size: 10,
+        simplify: true,
+        constant_fitting: Some(ConstantFitting {
+            every: 5,
+            top_k: 10,
+            iterations: 10,
+        }),
+        interval_pruning: true,
+    };
+
+    let hall_of_fame = genetic_optimizer(20, &x, &y, &params);
+    let entries = hall_of_fame.entries();
+    assert!(!entries.is_empty());
+    for (i, (loss, expr)) in entries.iter().enumerate() {
+        for (other_loss, other) in &entries[i + 1..] {
+            assert!(loss <= other_loss);
+            assert_ne!(expr, other);
+        }
+    }
+}
+
+#[test]
+fn pareto() {
+    let (x, y) = iris();
+    let params = GeneticParameters {
+        population_size: 500,
+        ..GeneticParameters::default()
+    };
+
+    let front = nsga2_optimizer(10, &x, &y, &params);
+    assert!(!front.is_empty());
+    for pair in front.windows(2) {
+        assert!(pair[0].complexity <= pair[1].complexity);
+        assert!(pair[0].loss >= pair[1].loss);
+    }
+}